@@ -0,0 +1,67 @@
+use base64::engine::general_purpose::STANDARD as base64;
+use base64::Engine;
+
+/// How keys and values are written on the command line and in outputs.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    #[value(alias = "utf8")]
+    String,
+    Base64,
+    Hex,
+}
+
+impl Encoding {
+    pub fn encode(&self, data: &[u8]) -> String {
+        match self {
+            Encoding::String => String::from_utf8_lossy(data).to_string(),
+            Encoding::Base64 => base64.encode(data),
+            Encoding::Hex => hex_encode(data),
+        }
+    }
+
+    pub fn decode(&self, s: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::String => Ok(s.as_bytes().to_vec()),
+            Encoding::Base64 => base64.decode(s).map_err(|e| e.to_string()),
+            Encoding::Hex => hex_decode(s),
+        }
+    }
+}
+
+/// Encode `data` as text when it is valid UTF-8, otherwise in base64, along
+/// with the name of the encoding used.
+pub fn encode_readable(data: &[u8]) -> (String, &'static str) {
+    match std::str::from_utf8(data) {
+        Ok(text) => (text.to_string(), "utf8"),
+        Err(_) => (base64.encode(data), "base64"),
+    }
+}
+
+/// The JSON value held by `data`, when it is an object, an array, a number,
+/// a boolean or null. Strings are left out, they would read as encoded
+/// values.
+pub fn json_value(data: &[u8]) -> Option<json::JsonValue> {
+    let value = json::parse(std::str::from_utf8(data).ok()?).ok()?;
+    if value.is_string() {
+        return None;
+    }
+    Some(value)
+}
+
+pub fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hex_decode(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("odd number of hex digits: {}", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("invalid hex digits at {}: {}", i, s))
+        })
+        .collect()
+}
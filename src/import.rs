@@ -0,0 +1,268 @@
+use std::io::BufRead;
+
+use clap::ValueEnum;
+use json::JsonValue;
+
+use lmdb_tool::lmdb::model::Element;
+
+use crate::encoding::Encoding;
+
+/// How the records of `import` are decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Encoding of the keys of records without a `key_encoding` field
+    pub key_encoding: Encoding,
+    /// Encoding of the values of records without a `value_encoding` field
+    pub value_encoding: Encoding,
+    /// Arrays of values are duplicates of a key rather than JSON values
+    pub dupsort: bool,
+}
+
+/// Read a JSON document: an array of records, or an object of values by key
+/// as written by `dump --json`.
+pub fn read_json(text: &str, options: Options) -> Result<Vec<Element>, String> {
+    let document = json::parse(text).map_err(|e| e.to_string())?;
+    match &document {
+        JsonValue::Array(records) => records
+            .iter()
+            .enumerate()
+            .map(|(i, record)| {
+                read_record(record, options).map_err(|e| format!("record {}: {}", i, e))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|records| records.into_iter().flatten().collect()),
+        JsonValue::Object(object) => {
+            let mut elements = Vec::new();
+            for (key, value) in object.iter() {
+                let key = options
+                    .key_encoding
+                    .decode(key)
+                    .map_err(|e| format!("key {:?}: {}", key, e))?;
+                for value in values(value, options.value_encoding, options.dupsort)
+                    .map_err(|e| format!("value of {:?}: {}", String::from_utf8_lossy(&key), e))?
+                {
+                    elements.push(Element {
+                        key: key.clone(),
+                        value,
+                    });
+                }
+            }
+            Ok(elements)
+        }
+        _ => Err("expected an array of records or an object".to_string()),
+    }
+}
+
+/// Read JSON Lines: a record per line, blank lines ignored.
+pub fn read_jsonl<R: BufRead>(input: R, options: Options) -> Result<Vec<Element>, String> {
+    let mut elements = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = json::parse(&line)
+            .map_err(|e| e.to_string())
+            .and_then(|record| read_record(&record, options))
+            .map_err(|e| format!("line {}: {}", number + 1, e))?;
+        elements.extend(record);
+    }
+    Ok(elements)
+}
+
+/// A `{"key": ..., "value": ...}` record, with optional `key_encoding` and
+/// `value_encoding` fields (`utf8`, `base64`, `hex`). A `json` value
+/// encoding stores the value serialized, or its `value_text` field when
+/// given, as written by `dump --value-format json`.
+fn read_record(record: &JsonValue, options: Options) -> Result<Vec<Element>, String> {
+    if !record.is_object() {
+        return Err("expected an object".to_string());
+    }
+    let key_encoding = encoding(&record["key_encoding"], options.key_encoding)?;
+    let nested = record["value_encoding"] == "json";
+    let value_encoding = match nested {
+        true => options.value_encoding,
+        false => encoding(&record["value_encoding"], options.value_encoding)?,
+    };
+    let key = match record["key"].as_str() {
+        Some(key) => key_encoding
+            .decode(key)
+            .map_err(|e| format!("key: {}", e))?,
+        None => return Err("expected a string key".to_string()),
+    };
+    if !record.has_key("value") {
+        return Err("missing value".to_string());
+    }
+    let values = match (nested, record["value_text"].as_str()) {
+        (true, Some(text)) => vec![text.as_bytes().to_vec()],
+        (true, None) => vec![record["value"].dump().into_bytes()],
+        (false, _) => values(&record["value"], value_encoding, options.dupsort)
+            .map_err(|e| format!("value: {}", e))?,
+    };
+    Ok(values
+        .into_iter()
+        .map(|value| Element {
+            key: key.clone(),
+            value,
+        })
+        .collect())
+}
+
+fn encoding(name: &JsonValue, default: Encoding) -> Result<Encoding, String> {
+    match name {
+        JsonValue::Null => Ok(default),
+        _ => name
+            .as_str()
+            .and_then(|name| Encoding::from_str(name, true).ok())
+            .ok_or_else(|| format!("unknown encoding {}", name.dump())),
+    }
+}
+
+/// Strings are decoded, other JSON values are stored serialized. With
+/// `dupsort`, each item of an array is a value.
+fn values(value: &JsonValue, encoding: Encoding, dupsort: bool) -> Result<Vec<Vec<u8>>, String> {
+    match value {
+        JsonValue::Array(items) if dupsort => items
+            .iter()
+            .map(|item| self::value(item, encoding))
+            .collect(),
+        _ => Ok(vec![self::value(value, encoding)?]),
+    }
+}
+
+fn value(value: &JsonValue, encoding: Encoding) -> Result<Vec<u8>, String> {
+    match value.as_str() {
+        Some(text) => encoding.decode(text),
+        None => Ok(value.dump().into_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: Options = Options {
+        key_encoding: Encoding::String,
+        value_encoding: Encoding::String,
+        dupsort: false,
+    };
+
+    fn pairs(elements: Vec<Element>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        elements
+            .into_iter()
+            .map(|element| (element.key, element.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_read_json_records() {
+        let text = r#"[
+            {"key": "plain", "value": "text"},
+            {"key": "AAE=", "key_encoding": "base64", "value": "0aff", "value_encoding": "hex"},
+            {"key": "state", "value": {"Name": "idle", "Data": [1, 2]}},
+            {"key": "count", "value": 3}
+        ]"#;
+        assert_eq!(
+            pairs(read_json(text, OPTIONS).unwrap()),
+            vec![
+                (b"plain".to_vec(), b"text".to_vec()),
+                (vec![0, 1], vec![0x0a, 0xff]),
+                (
+                    b"state".to_vec(),
+                    br#"{"Name":"idle","Data":[1,2]}"#.to_vec()
+                ),
+                (b"count".to_vec(), b"3".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_json_object() {
+        let text = r#"{"a": "1", "b": ["2", "3"]}"#;
+        assert_eq!(
+            pairs(read_json(text, OPTIONS).unwrap()),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), br#"["2","3"]"#.to_vec()),
+            ]
+        );
+        let options = Options {
+            dupsort: true,
+            ..OPTIONS
+        };
+        assert_eq!(
+            pairs(read_json(text, options).unwrap()),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"b".to_vec(), b"3".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_jsonl_nested() {
+        let text = concat!(
+            "{\"key\": \"a\", \"value\": \"[1]\", \"value_encoding\": \"utf8\"}\n",
+            "{\"key\": \"b\", \"value\": [1, {\"c\": null}], \"value_encoding\": \"json\"}\n",
+        );
+        let options = Options {
+            dupsort: true,
+            ..OPTIONS
+        };
+        assert_eq!(
+            pairs(read_jsonl(text.as_bytes(), options).unwrap()),
+            vec![
+                (b"a".to_vec(), b"[1]".to_vec()),
+                (b"b".to_vec(), br#"[1,{"c":null}]"#.to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import_key_size() {
+        use lmdb_tool::lmdb::{Endianness, Factory, WordSize};
+
+        for (size, page_size) in [(5000, 4096), (500, 512)] {
+            let text = format!("{{\"key\": \"{}\", \"value\": \"1\"}}", "k".repeat(size));
+            let section = lmdb_tool::lmdb::dump::Section {
+                header: Default::default(),
+                elements: read_jsonl(text.as_bytes(), OPTIONS).unwrap(),
+            };
+            assert!(section.check_sizes(8, page_size).is_err(), "{}", size);
+
+            let file = tempfile::NamedTempFile::new().unwrap();
+            let mut db = Factory::create_with(
+                file.path().into(),
+                WordSize::Word64,
+                Endianness::Little,
+                page_size,
+            )
+            .unwrap();
+            assert!(db.load(vec![section]).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn test_read_jsonl() {
+        let text = "{\"key\": \"a\", \"value\": \"1\"}\n\n{\"key\": \"b\", \"value\": null}\n";
+        assert_eq!(
+            pairs(read_jsonl(text.as_bytes(), OPTIONS).unwrap()),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"null".to_vec()),
+            ]
+        );
+
+        for text in [
+            "{\"key\": \"a\"}",
+            "{\"key\": 1, \"value\": \"1\"}",
+            "{\"key\": \"a\", \"value\": \"1\", \"value_encoding\": \"rot13\"}",
+            "{\"key\": \"zz\", \"key_encoding\": \"hex\", \"value\": \"1\"}",
+            "[\"a\", \"1\"]",
+        ] {
+            let error = read_jsonl(text.as_bytes(), OPTIONS).unwrap_err();
+            assert!(error.starts_with("line 1: "), "{}", error);
+        }
+    }
+}
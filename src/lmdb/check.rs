@@ -0,0 +1,631 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use error_stack::AttachmentKind;
+use error_stack::FrameKind;
+use error_stack::Report;
+use error_stack::Result;
+
+use super::compare;
+use super::database::Database;
+use super::error::Error;
+use super::model;
+use super::model::lowlevel;
+use super::Endianness;
+
+/// An inconsistency found by `Database::check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// Page the problem was found on, if any
+    pub page: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.page {
+            Some(page) => write!(f, "page {}: {}", page, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Outcome of `Database::check`.
+#[derive(Debug, Clone, Default)]
+pub struct Check {
+    /// Pages of the file up to `last_pgno`, meta pages included
+    pub pages: u64,
+    /// Pages referenced by a tree
+    pub used: u64,
+    /// Pages recorded in the free list
+    pub free: u64,
+    /// Pages neither referenced nor free. LMDB never reuses them, but they
+    /// do not make the database unreadable.
+    pub leaked: Vec<u64>,
+    pub problems: Vec<Problem>,
+}
+
+impl Check {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Pages seen so far, with the tree referencing them.
+struct Walk {
+    check: Check,
+    owners: HashMap<u64, String>,
+    endianness: Endianness,
+    word_size: usize,
+}
+
+impl Walk {
+    fn problem(&mut self, page: Option<u64>, message: String) {
+        tracing::debug!("Problem on page {:?}: {}", page, message);
+        self.check.problems.push(Problem { page, message });
+    }
+
+    /// Record that `tree` references `pageno`, returns false when it is out
+    /// of bounds or already referenced.
+    fn claim(&mut self, pageno: u64, last_pgno: u64, tree: &str) -> bool {
+        if pageno < 2 || pageno > last_pgno {
+            self.problem(
+                Some(pageno),
+                format!("referenced by {} beyond the last page {}", tree, last_pgno),
+            );
+            return false;
+        }
+        if let Some(owner) = self.owners.get(&pageno) {
+            let message = format!("referenced by {} and {}", owner, tree);
+            self.problem(Some(pageno), message);
+            return false;
+        }
+        self.owners.insert(pageno, tree.to_string());
+        true
+    }
+}
+
+/// Page counts of a tree, to compare with its `MDB_db` record.
+#[derive(Default)]
+struct Counts {
+    branch_pages: u64,
+    leaf_pages: u64,
+    overflow_pages: u64,
+    entries: u64,
+}
+
+fn show(key: &[u8]) -> String {
+    format!("{:?}", String::from_utf8_lossy(key))
+}
+
+/// Context and attachments of an error, on a single line.
+fn describe(report: &Report<Error>) -> String {
+    let mut parts = vec![report.current_context().to_string()];
+    parts.extend(report.frames().filter_map(|frame| match frame.kind() {
+        FrameKind::Attachment(AttachmentKind::Printable(printable)) => Some(printable.to_string()),
+        _ => None,
+    }));
+    parts.join(": ")
+}
+
+impl<'a> Database<'a> {
+    /// Check the structure of the database: both meta pages, every page of
+    /// every tree (page numbers, bounds, key order, separators, overflow
+    /// runs), the counts recorded for each tree, and the free list.
+    /// Inconsistencies are reported in the returned `Check`; errors are only
+    /// returned when the file cannot be read at all.
+    pub fn check(&mut self) -> Result<Check, Error> {
+        let mut walk = Walk {
+            check: Check {
+                pages: self.meta.last_pgno + 1,
+                ..Default::default()
+            },
+            owners: HashMap::new(),
+            endianness: self.endianness(),
+            word_size: self.word_size(),
+        };
+
+        self.check_metas(&mut walk)?;
+
+        let free = self.meta.free.clone();
+        let mut misplaced = self.check_tree(&mut walk, "the free list", &free);
+        let main = self.meta.main.clone();
+        let named = self.check_tree(&mut walk, "the main database", &main);
+        for (name, tree) in named {
+            let label = format!("database {}", show(&name));
+            misplaced.extend(self.check_tree(&mut walk, &label, &tree));
+        }
+        // Only the main database records named databases
+        for (name, _) in misplaced {
+            let message = format!(
+                "database {} recorded outside of the main database",
+                show(&name)
+            );
+            walk.problem(None, message);
+        }
+        walk.check.used = walk.owners.len() as u64;
+
+        self.check_freelist(&mut walk);
+
+        Ok(walk.check)
+    }
+
+    fn check_metas(&mut self, walk: &mut Walk) -> Result<(), Error> {
+        let reader = self.reader.as_mut().ok_or(Error::NoReader)?;
+        let reader = reader.get_mut().unwrap();
+        let file_size = reader.seek(std::io::SeekFrom::End(0))? as u64;
+
+        let mut metas = Vec::new();
+        for id in 0..2 {
+            Self::seek_page_unsafe(reader.as_mut(), id, self.page_size)?;
+            match Self::read_meta_unsafe(reader.as_mut()) {
+                Ok(meta) => metas.push(meta),
+                Err(e) => walk.problem(Some(id as u64), describe(&e)),
+            }
+        }
+        if metas.len() == 2 && metas[0].txnid == metas[1].txnid && metas[0].txnid > 0 {
+            walk.problem(
+                None,
+                format!("both meta pages have transaction {}", metas[0].txnid),
+            );
+        }
+
+        for meta in metas {
+            if meta.free.pad as usize != self.page_size {
+                walk.problem(
+                    None,
+                    format!(
+                        "meta page of transaction {} has {}B pages, {}B expected",
+                        meta.txnid, meta.free.pad, self.page_size
+                    ),
+                );
+            }
+            for (label, tree) in [("free list", &meta.free), ("main database", &meta.main)] {
+                if tree
+                    .root
+                    .is_some_and(|root| root < 2 || root > meta.last_pgno)
+                {
+                    walk.problem(
+                        None,
+                        format!(
+                            "meta page of transaction {} has the {} rooted at page {}, last page is {}",
+                            meta.txnid,
+                            label,
+                            tree.root.unwrap(),
+                            meta.last_pgno
+                        ),
+                    );
+                }
+            }
+        }
+
+        let size = (self.meta.last_pgno + 1) * self.page_size as u64;
+        if file_size < size {
+            walk.problem(
+                None,
+                format!(
+                    "file holds {} bytes, {} needed up to page {}",
+                    file_size, size, self.meta.last_pgno
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    /// Check a tree against its record, and return the named databases
+    /// found in it.
+    fn check_tree(
+        &mut self,
+        walk: &mut Walk,
+        label: &str,
+        tree: &model::Database,
+    ) -> Vec<(Vec<u8>, model::Database)> {
+        let mut counts = Counts::default();
+        let mut named = Vec::new();
+        if let Some(root) = tree.root {
+            self.check_page(
+                walk,
+                label,
+                tree,
+                root,
+                1,
+                (None, None),
+                &mut counts,
+                &mut named,
+            );
+        } else if tree.depth != 0 {
+            walk.problem(
+                None,
+                format!("{} is empty with depth {}", label, tree.depth),
+            );
+        }
+
+        for (field, recorded, actual) in [
+            ("branch pages", tree.branch_pages, counts.branch_pages),
+            ("leaf pages", tree.leaf_pages, counts.leaf_pages),
+            ("overflow pages", tree.overflow_pages, counts.overflow_pages),
+            ("entries", tree.entries, counts.entries),
+        ] {
+            if recorded != actual {
+                walk.problem(
+                    None,
+                    format!("{} records {} {}, {} found", label, recorded, field, actual),
+                );
+            }
+        }
+        named
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn check_page(
+        &mut self,
+        walk: &mut Walk,
+        label: &str,
+        tree: &model::Database,
+        pageno: u64,
+        level: u16,
+        bounds: (Option<&[u8]>, Option<&[u8]>),
+        counts: &mut Counts,
+        named: &mut Vec<(Vec<u8>, model::Database)>,
+    ) {
+        if !walk.claim(pageno, self.meta.last_pgno, label) {
+            return;
+        }
+
+        let header = match self.read_header(pageno) {
+            Ok(header) => header,
+            Err(e) => return walk.problem(Some(pageno), describe(&e)),
+        };
+        if header.pageno != pageno {
+            walk.problem(
+                Some(pageno),
+                format!("header has page number {}", header.pageno),
+            );
+        }
+        let lower = header.free_lower as usize;
+        let upper = header.free_upper as usize;
+        if lower < lowlevel::page_header_size(walk.word_size)
+            || lower > upper
+            || upper > self.page_size
+        {
+            return walk.problem(
+                Some(pageno),
+                format!("free space bounds {}..{} out of the page", lower, upper),
+            );
+        }
+
+        let page = match self.read_page(pageno as usize) {
+            Ok(page) => page,
+            Err(e) => return walk.problem(Some(pageno), describe(&e)),
+        };
+        let (lo, hi) = bounds;
+        match page {
+            model::Page::Branch(branch) => {
+                counts.branch_pages += 1;
+                if level >= tree.depth {
+                    walk.problem(
+                        Some(pageno),
+                        format!("branch page at level {} of {} levels", level, tree.depth),
+                    );
+                }
+                if branch.nodes.is_empty() {
+                    return walk.problem(Some(pageno), "empty branch page".to_string());
+                }
+                let keys = branch
+                    .nodes
+                    .iter()
+                    .skip(1)
+                    .map(|node| node.key.as_slice())
+                    .collect::<Vec<_>>();
+                self.check_keys(walk, tree.flags, pageno, &keys, bounds, "separator");
+                for (i, node) in branch.nodes.iter().enumerate() {
+                    let child_lo = if i == 0 {
+                        lo
+                    } else {
+                        Some(node.key.as_slice())
+                    };
+                    let child_hi = match branch.nodes.get(i + 1) {
+                        Some(next) => Some(next.key.as_slice()),
+                        None => hi,
+                    };
+                    self.check_page(
+                        walk,
+                        label,
+                        tree,
+                        node.pageno,
+                        level + 1,
+                        (child_lo, child_hi),
+                        counts,
+                        named,
+                    );
+                }
+            }
+            model::Page::Leaf(leaf) => {
+                counts.leaf_pages += 1;
+                if level != tree.depth {
+                    walk.problem(
+                        Some(pageno),
+                        format!("leaf page at level {} of {} levels", level, tree.depth),
+                    );
+                }
+                let keys = leaf
+                    .nodes
+                    .iter()
+                    .map(|node| node.key.as_slice())
+                    .collect::<Vec<_>>();
+                self.check_keys(walk, tree.flags, pageno, &keys, bounds, "key");
+                for node in leaf.nodes {
+                    counts.entries += node.entries();
+                    self.check_node(walk, label, tree, pageno, node, counts, named);
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn check_node(
+        &mut self,
+        walk: &mut Walk,
+        label: &str,
+        tree: &model::Database,
+        pageno: u64,
+        node: model::Node,
+        counts: &mut Counts,
+        named: &mut Vec<(Vec<u8>, model::Database)>,
+    ) {
+        match node.data {
+            model::NodeData::Overflow(overflow, size) => {
+                let pages = lowlevel::overflow_pages(walk.word_size, self.page_size, size) as u64;
+                counts.overflow_pages += pages;
+                for page in overflow..overflow + pages {
+                    if !walk.claim(page, self.meta.last_pgno, label) {
+                        return;
+                    }
+                }
+                match self.read_header(overflow) {
+                    Ok(header) if header.pageno != overflow => walk.problem(
+                        Some(overflow),
+                        format!("header has page number {}", header.pageno),
+                    ),
+                    Ok(_) => (),
+                    Err(e) => return walk.problem(Some(overflow), describe(&e)),
+                }
+                if let Err(e) = self.read_overflow(overflow as usize, size) {
+                    walk.problem(Some(overflow), describe(&e));
+                }
+            }
+            model::NodeData::SubData(sub) if node.flags.contains(model::NodeFlags::DUPDATA) => {
+                let label = format!(
+                    "the duplicates of {:?} in {}",
+                    String::from_utf8_lossy(&node.key),
+                    label
+                );
+                let mut sub = sub;
+                sub.flags = tree.flags.dup_flags();
+                self.check_tree(walk, &label, &sub);
+            }
+            model::NodeData::SubData(sub) => named.push((node.key, sub)),
+            model::NodeData::SubPage(leaf) => {
+                let keys = leaf
+                    .nodes
+                    .iter()
+                    .map(|node| node.key.as_slice())
+                    .collect::<Vec<_>>();
+                let flags = tree.flags.dup_flags();
+                self.check_keys(walk, flags, pageno, &keys, (None, None), "duplicate");
+            }
+            model::NodeData::Data(_) => (),
+        }
+    }
+
+    /// Keys must be in strictly ascending order, within the bounds set by
+    /// the separators of the parent pages.
+    fn check_keys(
+        &self,
+        walk: &mut Walk,
+        flags: model::metadata::Flags,
+        pageno: u64,
+        keys: &[&[u8]],
+        bounds: (Option<&[u8]>, Option<&[u8]>),
+        what: &str,
+    ) {
+        let endianness = walk.endianness;
+        let cmp = |a: &[u8], b: &[u8]| compare::compare(flags, endianness, a, b);
+        for pair in keys.windows(2) {
+            if cmp(pair[0], pair[1]).is_ge() {
+                walk.problem(
+                    Some(pageno),
+                    format!("{} {} not before {}", what, show(pair[0]), show(pair[1])),
+                );
+            }
+        }
+        let (lo, hi) = bounds;
+        if let (Some(lo), Some(first)) = (lo, keys.first()) {
+            if cmp(first, lo).is_lt() {
+                walk.problem(
+                    Some(pageno),
+                    format!("{} {} before the separator {}", what, show(first), show(lo)),
+                );
+            }
+        }
+        if let (Some(hi), Some(last)) = (hi, keys.last()) {
+            if cmp(last, hi).is_ge() {
+                walk.problem(
+                    Some(pageno),
+                    format!(
+                        "{} {} not before the separator {}",
+                        what,
+                        show(last),
+                        show(hi)
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Free pages must be within the file, listed once, and not referenced.
+    fn check_freelist(&mut self, walk: &mut Walk) {
+        let records = match self.freelist() {
+            Ok(records) => records,
+            Err(e) => return walk.problem(None, describe(&e)),
+        };
+        let mut free = HashMap::new();
+        for (txnid, pages) in records {
+            for page in pages {
+                if page < 2 || page > self.meta.last_pgno {
+                    walk.problem(
+                        Some(page),
+                        format!(
+                            "freed by transaction {} beyond the last page {}",
+                            txnid, self.meta.last_pgno
+                        ),
+                    );
+                } else if let Some(owner) = walk.owners.get(&page) {
+                    let message = format!("freed by transaction {} but used by {}", txnid, owner);
+                    walk.problem(Some(page), message);
+                } else if let Some(other) = free.insert(page, txnid) {
+                    walk.problem(
+                        Some(page),
+                        format!("freed by transactions {} and {}", other, txnid),
+                    );
+                }
+            }
+        }
+        walk.check.free = free.len() as u64;
+        walk.check.leaked = (2..self.meta.last_pgno + 1)
+            .filter(|page| !walk.owners.contains_key(page) && !free.contains_key(page))
+            .collect();
+    }
+
+    fn read_header(&mut self, pageno: u64) -> Result<model::Header, Error> {
+        let reader = self.reader.as_mut().ok_or(Error::NoReader)?;
+        let reader = reader.get_mut().unwrap();
+        Self::seek_page_unsafe(reader.as_mut(), pageno as usize, self.page_size)?;
+        Self::read_page_header_unsafe(reader.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, Write};
+
+    use crate::lmdb::Factory;
+    use crate::lmdb::WordSize;
+
+    use super::*;
+
+    macro_rules! test_case {
+        ($fname:expr) => {
+            std::path::PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/", $fname))
+        };
+    }
+
+    pub fn init_tracing() -> tracing::subscriber::DefaultGuard {
+        let subscriber = tracing_subscriber::fmt::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_line_number(true)
+            .with_file(true)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .finish();
+        tracing::subscriber::set_default(subscriber)
+    }
+
+    #[test]
+    fn test_check_resources() {
+        let _guard = init_tracing();
+        for path in [
+            test_case!("btree.64bits"),
+            test_case!("dupsort.64bits"),
+            test_case!("named.64bits"),
+            test_case!("overflow.64bits"),
+            test_case!("mender-store.32bits"),
+            test_case!("mender-store.32bits.2"),
+            test_case!("mender-store.64bits"),
+        ] {
+            let name = path.display().to_string();
+            let mut db = Factory::open(path).unwrap();
+            let check = db.check().unwrap();
+            assert!(check.is_ok(), "{}: {:?}", name, check.problems);
+            assert!(check.leaked.is_empty(), "{}: {:?}", name, check.leaked);
+            assert_eq!(check.used + check.free + 2, check.pages, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_check_unordered_64() {
+        let _guard = init_tracing();
+
+        // Written by an early version of this tool, which did not sort keys
+        let mut db = Factory::open(test_case!("mender-store.64bits.3")).unwrap();
+        let check = db.check().unwrap();
+        assert!(!check.is_ok());
+        assert!(check
+            .problems
+            .iter()
+            .all(|problem| problem.page == Some(2) && problem.message.contains("not before")));
+    }
+
+    #[test]
+    fn test_check_counts_64() {
+        let _guard = init_tracing();
+        let mut db = Factory::open(test_case!("mender-store.64bits.json-error")).unwrap();
+        let check = db.check().unwrap();
+        assert_eq!(check.problems.len(), 1);
+        assert!(check.problems[0].message.contains("overflow pages"));
+        assert_eq!(check.leaked.len(), 1);
+    }
+
+    #[test]
+    fn test_check_page_number_64() {
+        let _guard = init_tracing();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&std::fs::read(test_case!("btree.64bits")).unwrap())
+            .unwrap();
+        let mut db = Factory::open(file.path().into()).unwrap();
+        let root = db.meta.main.root.unwrap();
+        // A broken root would make the meta page unusable
+        let page = match db.read_page(root as usize).unwrap() {
+            model::Page::Branch(branch) => branch.nodes[0].pageno,
+            model::Page::Leaf(_) => panic!("single page tree"),
+        };
+
+        file.seek(std::io::SeekFrom::Start(page * 4096)).unwrap();
+        file.write_all(&1234u64.to_le_bytes()).unwrap();
+        file.flush().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        let check = db.check().unwrap();
+        assert_eq!(
+            check.problems,
+            vec![Problem {
+                page: Some(page),
+                message: "header has page number 1234".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_check_updated_32() {
+        let _guard = init_tracing();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word32).unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        for i in 0..300 {
+            let key = format!("key-{:04}", i).into_bytes();
+            cur.push(key, vec![b'a'; 50 + i % 3000]).unwrap();
+        }
+        cur.commit().unwrap();
+
+        let mut db = Factory::open_rw(file.path().into()).unwrap();
+        for i in (0..300).step_by(7) {
+            let key = format!("key-{:04}", i).into_bytes();
+            db.put(&key, &vec![b'b'; 50 + i % 3000]).unwrap();
+        }
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        let check = db.check().unwrap();
+        assert!(check.is_ok(), "{:?}", check.problems);
+        assert!(check.leaked.is_empty());
+        assert!(check.free > 0);
+    }
+}
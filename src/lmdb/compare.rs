@@ -0,0 +1,71 @@
+use std::cmp::Ordering;
+
+use super::model::metadata::Flags;
+use super::Endianness;
+
+/// Order two keys of a tree the way LMDB's default comparators do: byte by
+/// byte (`mdb_cmp_memn`), from the last byte with `REVERSEKEY`
+/// (`mdb_cmp_memnr`), or as native unsigned integers with `INTEGERKEY`.
+pub fn compare(flags: Flags, endianness: Endianness, a: &[u8], b: &[u8]) -> Ordering {
+    if flags.contains(Flags::INTEGERKEY) && a.len() == b.len() {
+        match endianness {
+            Endianness::Big => a.cmp(b),
+            Endianness::Little => a.iter().rev().cmp(b.iter().rev()),
+        }
+    } else if flags.contains(Flags::REVERSEKEY) {
+        a.iter().rev().cmp(b.iter().rev())
+    } else {
+        a.cmp(b)
+    }
+}
+
+/// Order two values of the same key in a `DUPSORT` tree.
+pub fn compare_dups(flags: Flags, endianness: Endianness, a: &[u8], b: &[u8]) -> Ordering {
+    compare(flags.dup_flags(), endianness, a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let little = Endianness::Little;
+        assert_eq!(compare(Flags::empty(), little, b"ab", b"b"), Ordering::Less);
+        assert_eq!(
+            compare(Flags::empty(), little, b"ab", b"a"),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare(Flags::REVERSEKEY, little, b"ba", b"ab"),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare(
+                Flags::INTEGERKEY,
+                little,
+                &256u32.to_le_bytes(),
+                &2u32.to_le_bytes()
+            ),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare(
+                Flags::INTEGERKEY,
+                Endianness::Big,
+                &256u32.to_be_bytes(),
+                &2u32.to_be_bytes()
+            ),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_dups(
+                Flags::DUPSORT | Flags::INTEGERDUP,
+                little,
+                &1u64.to_le_bytes(),
+                &256u64.to_le_bytes()
+            ),
+            Ordering::Less
+        );
+    }
+}
@@ -1,128 +1,866 @@
+use super::compare;
 use super::database::Database;
 use super::error::Error;
 use super::model;
+use super::model::lowlevel;
 use super::model::Element;
-use super::model::Node;
 
+use std::borrow::BorrowMut;
+use std::collections::VecDeque;
+use std::ops::Bound;
+
+use error_stack::Report;
 use error_stack::Result;
 
-pub struct ReadCursor<'a, 'b> {
-    pub db: &'b mut Database<'a>,
+/// Maximum depth of a B-tree, same bound as LMDB's `CURSOR_STACK`.
+pub(crate) const CURSOR_STACK: usize = 32;
+
+/// Position within one B-tree: the branches walked down from the root, the
+/// current leaf and the gap before `node_idx` in that leaf. Moving forward
+/// returns the node after the gap, moving backward the node before it.
+#[derive(Debug, Clone)]
+pub struct TreeCursor {
+    pub tree: model::Database,
+    pub stack: Vec<(model::Branch, usize)>,
     pub page: Option<model::Leaf>,
     pub node_idx: usize,
 }
 
-impl<'a, 'b> ReadCursor<'a, 'b> {
-    pub fn init(db: &'b mut Database<'a>) -> Result<Self, Error> {
-        let mut cur = ReadCursor {
-            db,
+impl TreeCursor {
+    /// Position the cursor before the first node of `tree`.
+    pub fn init(db: &mut Database, tree: model::Database) -> Result<Self, Error> {
+        let mut cur = TreeCursor {
+            tree,
+            stack: Vec::new(),
             page: None,
             node_idx: 0,
         };
-        cur.next_page()?;
+        cur.first(db)?;
         Ok(cur)
     }
 
-    pub fn next_page(&mut self) -> Result<(), Error> {
-        let root = self.db.meta.main.root.unwrap_or(2 as u64) as usize;
-        let leaf_pages = self.db.meta.main.leaf_pages as usize;
-        let max = std::cmp::min(self.db.meta.last_pgno as usize + 1, root + leaf_pages);
-        let idx = match &self.page {
-            Some(page) => page.pageno + 1,
-            None => root as usize,
+    /// Walk the nodes of a single leaf, such as a sub-page of duplicates.
+    pub fn from_leaf(leaf: model::Leaf) -> Self {
+        let tree = model::Database {
+            pad: 0,
+            flags: model::metadata::Flags::empty(),
+            depth: 1,
+            branch_pages: 0,
+            leaf_pages: 1,
+            overflow_pages: 0,
+            entries: leaf.nodes.len() as u64,
+            root: None,
         };
+        TreeCursor {
+            tree,
+            stack: Vec::new(),
+            page: Some(leaf),
+            node_idx: 0,
+        }
+    }
+
+    /// Files written by earlier versions of this tool hold several leaves
+    /// laid out after a leaf root, without any branch page above them.
+    fn is_contiguous(&self) -> bool {
+        self.tree.branch_pages == 0 && self.tree.leaf_pages > 1
+    }
+
+    /// Move before the first node.
+    pub fn first(&mut self, db: &mut Database) -> Result<(), Error> {
+        if let Some(root) = self.tree.root {
+            self.stack.clear();
+            self.descend(db, root as usize, false)?;
+        }
+        self.node_idx = 0;
+        Ok(())
+    }
+
+    /// Move after the last node.
+    pub fn last(&mut self, db: &mut Database) -> Result<(), Error> {
+        if let Some(root) = self.tree.root {
+            self.stack.clear();
+            if self.is_contiguous() {
+                let last = std::cmp::min(
+                    db.meta.last_pgno,
+                    root + self.tree.leaf_pages.saturating_sub(1),
+                );
+                self.page = Some(db.read(last as usize)?);
+            } else {
+                self.descend(db, root as usize, true)?;
+            }
+        }
+        self.node_idx = self.page.as_ref().map_or(0, |page| page.nodes.len());
+        Ok(())
+    }
+
+    /// Move before the first node whose key is not below `key`
+    /// (`MDB_SET_RANGE`).
+    pub fn seek(&mut self, db: &mut Database, key: &[u8]) -> Result<(), Error> {
+        let flags = self.tree.flags;
+        let endianness = db.endianness();
+        let cmp = |a: &[u8], b: &[u8]| compare::compare(flags, endianness, a, b);
+
+        self.first(db)?;
+        if self.is_contiguous() {
+            while self.page.as_ref().is_some_and(|page| {
+                page.nodes
+                    .last()
+                    .is_none_or(|node| cmp(&node.key, key).is_lt())
+            }) && self.next_page(db)?
+            {}
+        } else if let Some(root) = self.tree.root {
+            self.stack.clear();
+            let mut pageno = root as usize;
+            while let model::Page::Branch(branch) = db.read_page(pageno)? {
+                if self.stack.len() >= CURSOR_STACK {
+                    return Err(Report::new(Error::InvalidFileFormat)
+                        .attach_printable(format!("B-tree deeper than {}", CURSOR_STACK)));
+                }
+                // Last child whose separator is not above the key, the first
+                // separator being implicit
+                let idx = branch
+                    .nodes
+                    .iter()
+                    .skip(1)
+                    .take_while(|node| cmp(&node.key, key).is_le())
+                    .count();
+                pageno = match branch.nodes.get(idx) {
+                    Some(node) => node.pageno as usize,
+                    None => {
+                        return Err(Report::new(Error::InvalidFileFormat)
+                            .attach_printable(format!("empty branch page {}", pageno)))
+                    }
+                };
+                self.stack.push((branch, idx));
+            }
+            self.page = Some(db.read(pageno)?);
+        }
+        self.node_idx = self.page.as_ref().map_or(0, |page| {
+            page.nodes
+                .partition_point(|node| cmp(&node.key, key).is_lt())
+        });
+        Ok(())
+    }
+
+    /// Walk down from `pageno` until a leaf is reached, following the
+    /// leftmost (or rightmost) children and remembering the branches on the
+    /// way.
+    fn descend(&mut self, db: &mut Database, pageno: usize, rightmost: bool) -> Result<(), Error> {
+        let mut pageno = pageno;
+        loop {
+            match db.read_page(pageno)? {
+                model::Page::Branch(branch) => {
+                    if self.stack.len() >= CURSOR_STACK {
+                        return Err(Report::new(Error::InvalidFileFormat)
+                            .attach_printable(format!("B-tree deeper than {}", CURSOR_STACK)));
+                    }
+                    if branch.nodes.is_empty() {
+                        return Err(Report::new(Error::InvalidFileFormat)
+                            .attach_printable(format!("empty branch page {}", pageno)));
+                    }
+                    let idx = if rightmost { branch.nodes.len() - 1 } else { 0 };
+                    pageno = branch.nodes[idx].pageno as usize;
+                    self.stack.push((branch, idx));
+                }
+                model::Page::Leaf(leaf) => {
+                    self.page = Some(leaf);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Move to the start of the next leaf. The position is kept when there
+    /// is none.
+    fn next_page(&mut self, db: &mut Database) -> Result<bool, Error> {
         tracing::debug!(
-            "next_page {}: last_pgno:{}, root:{} + leaf_pages:{}",
-            idx,
-            self.db.meta.last_pgno,
-            root,
-            leaf_pages
+            "next_page: depth:{}, root:{:?}",
+            self.stack.len(),
+            self.tree.root
         );
 
-        self.page = if idx < max {
+        if self.is_contiguous() {
+            let root = self.tree.root.unwrap_or(2);
+            let max = std::cmp::min(db.meta.last_pgno + 1, root + self.tree.leaf_pages);
+            let pageno = match &self.page {
+                Some(page) => page.pageno as u64 + 1,
+                None => max,
+            };
+            if pageno >= max {
+                return Ok(false);
+            }
+            self.page = Some(db.read(pageno as usize)?);
             self.node_idx = 0;
-            Some(self.db.read(idx)?)
+            return Ok(true);
+        }
+
+        let mut stack = self.stack.clone();
+        while let Some((branch, idx)) = stack.pop() {
+            if idx + 1 < branch.nodes.len() {
+                let child = branch.nodes[idx + 1].pageno as usize;
+                stack.push((branch, idx + 1));
+                self.stack = stack;
+                self.descend(db, child, false)?;
+                self.node_idx = 0;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Move to the end of the previous leaf. The position is kept when there
+    /// is none.
+    fn prev_page(&mut self, db: &mut Database) -> Result<bool, Error> {
+        if self.is_contiguous() {
+            let root = self.tree.root.unwrap_or(2);
+            let pageno = match &self.page {
+                Some(page) if page.pageno as u64 > root => page.pageno - 1,
+                _ => return Ok(false),
+            };
+            let page = db.read(pageno)?;
+            self.node_idx = page.nodes.len();
+            self.page = Some(page);
+            return Ok(true);
+        }
+
+        let mut stack = self.stack.clone();
+        while let Some((branch, idx)) = stack.pop() {
+            if idx > 0 {
+                let child = branch.nodes[idx - 1].pageno as usize;
+                stack.push((branch, idx - 1));
+                self.stack = stack;
+                self.descend(db, child, true)?;
+                self.node_idx = self.page.as_ref().map_or(0, |page| page.nodes.len());
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Return the node after the gap and move past it.
+    pub fn next_node(&mut self, db: &mut Database) -> Result<Option<model::Node>, Error> {
+        loop {
+            if let Some(page) = &self.page {
+                if self.node_idx < page.nodes.len() {
+                    self.node_idx += 1;
+                    return Ok(Some(page.nodes[self.node_idx - 1].clone()));
+                }
+            }
+            if !self.next_page(db)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Return the node before the gap and move back over it.
+    pub fn prev_node(&mut self, db: &mut Database) -> Result<Option<model::Node>, Error> {
+        loop {
+            if let Some(page) = &self.page {
+                if self.node_idx > 0 {
+                    self.node_idx -= 1;
+                    return Ok(Some(page.nodes[self.node_idx].clone()));
+                }
+            }
+            if !self.prev_page(db)? {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Backward,
+}
+
+/// Iterates the records of one tree in key order, yielding one element per
+/// duplicate in `DUPSORT` trees. Nodes of the main tree holding named
+/// databases are skipped, see `Database::databases`.
+///
+/// As with LMDB cursors, `seek_first`, `seek_last` and `seek` return the
+/// element the cursor lands on, and `move_next` and `prev` the element after
+/// or before the current one; an unpositioned cursor starts from the first
+/// (or last) element. Elements outside of the bounds set by `set_range` or
+/// `set_prefix` are never returned.
+///
+/// The cursor is an `Iterator` calling `move_next`. It either borrows the
+/// database (`Database::read_cursor`) or owns it (`OwnedReadCursor`).
+pub struct ReadCursor<D> {
+    pub db: D,
+    pub cursor: TreeCursor,
+    pub dups: Option<(Vec<u8>, TreeCursor)>,
+    pub start: Bound<Vec<u8>>,
+    pub end: Bound<Vec<u8>>,
+    pub prefix: Option<Vec<u8>>,
+    direction: Option<Direction>,
+}
+
+/// A `ReadCursor` owning its database, e.g. to return it from a function.
+pub type OwnedReadCursor<'a> = ReadCursor<Database<'a>>;
+
+impl<'a, D: BorrowMut<Database<'a>>> ReadCursor<D> {
+    pub fn init(db: D) -> Result<Self, Error> {
+        let tree = db.borrow().meta.main.clone();
+        Self::init_with(db, tree)
+    }
+
+    /// Start iterating the given tree, e.g. a named database.
+    pub fn init_with(mut db: D, tree: model::Database) -> Result<Self, Error> {
+        let cursor = TreeCursor::init(db.borrow_mut(), tree)?;
+        Ok(ReadCursor {
+            db,
+            cursor,
+            dups: None,
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+            prefix: None,
+            direction: None,
+        })
+    }
+
+    /// Only return the keys within the given bounds.
+    pub fn set_range(&mut self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) {
+        self.start = start;
+        self.end = end;
+        self.direction = None;
+    }
+
+    /// Only return the keys starting with `prefix`. The scan stops at the
+    /// first key past the prefix, which assumes keys sorted byte by byte.
+    pub fn set_prefix(&mut self, prefix: Vec<u8>) {
+        if let Bound::Unbounded = self.start {
+            self.start = Bound::Included(prefix.clone());
+        }
+        self.prefix = Some(prefix);
+        self.direction = None;
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+        compare::compare(self.cursor.tree.flags, self.db.borrow().endianness(), a, b)
+    }
+
+    fn before_start(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => self.compare(key, start).is_lt(),
+            Bound::Excluded(start) => self.compare(key, start).is_le(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn after_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => self.compare(key, end).is_gt(),
+            Bound::Excluded(end) => self.compare(key, end).is_ge(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn below_prefix(&self, key: &[u8]) -> bool {
+        self.prefix
+            .as_ref()
+            .is_some_and(|prefix| self.compare(key, prefix).is_lt())
+    }
+
+    fn in_bounds(&self, key: &[u8]) -> bool {
+        !self.before_start(key)
+            && !self.after_end(key)
+            && self
+                .prefix
+                .as_ref()
+                .is_none_or(|prefix| key.starts_with(prefix))
+    }
+
+    /// Return the next node of the tree as stored, including the nodes of
+    /// named databases. Bounds and duplicates are not handled.
+    pub fn next_node(&mut self) -> Result<Option<model::Node>, Error> {
+        self.cursor.next_node(self.db.borrow_mut())
+    }
+
+    /// Turn a node into the element it holds, entering its duplicates.
+    /// Returns none for named databases.
+    fn enter(&mut self, node: model::Node, forward: bool) -> Result<Option<Element>, Error> {
+        let mut dups = match node.data {
+            model::NodeData::Data(data) => {
+                return Ok(Some(Element {
+                    key: node.key,
+                    value: data,
+                }))
+            }
+            model::NodeData::Overflow(overflow, size) => {
+                let value = self
+                    .db
+                    .borrow_mut()
+                    .read_overflow(overflow as usize, size)?;
+                return Ok(Some(Element {
+                    key: node.key,
+                    value,
+                }));
+            }
+            model::NodeData::SubPage(leaf) => TreeCursor::from_leaf(leaf),
+            model::NodeData::SubData(tree) => {
+                if !node.flags.contains(model::NodeFlags::DUPDATA) {
+                    return Ok(None);
+                }
+                TreeCursor::init(self.db.borrow_mut(), tree)?
+            }
+        };
+        let dup = if forward {
+            dups.next_node(self.db.borrow_mut())?
         } else {
-            None
+            dups.last(self.db.borrow_mut())?;
+            dups.prev_node(self.db.borrow_mut())?
         };
-        Ok(())
+        let key = node.key;
+        let element = dup.map(|dup| Element {
+            key: key.clone(),
+            value: dup.key,
+        });
+        self.dups = Some((key, dups));
+        Ok(element)
     }
 
-    pub fn next(&mut self) -> Result<Option<Element>, Error> {
-        let element = match &self.page {
-            Some(page) => {
-                let node = &page.nodes[self.node_idx];
-                match node.data {
-                    model::NodeData::Data(ref data) => Some(Element {
-                        key: node.key.clone(),
-                        value: data.clone(),
-                    }),
-                    model::NodeData::Overflow(overflow, size) => {
-                        let value = self.db.read_overflow(overflow as usize, size)?;
-                        Some(Element {
-                            key: node.key.clone(),
-                            value,
-                        })
+    /// Move forward over one element. While in duplicates, the tree cursor
+    /// stays after the node holding them.
+    fn step_next(&mut self) -> Result<Option<Element>, Error> {
+        loop {
+            if let Some((key, dups)) = &mut self.dups {
+                match dups.next_node(self.db.borrow_mut())? {
+                    Some(dup) => {
+                        return Ok(Some(Element {
+                            key: key.clone(),
+                            value: dup.key,
+                        }))
                     }
+                    None => self.dups = None,
                 }
             }
-            None => None,
-        };
+            let node = match self.cursor.next_node(self.db.borrow_mut())? {
+                Some(node) => node,
+                None => return Ok(None),
+            };
+            if let Some(element) = self.enter(node, true)? {
+                return Ok(Some(element));
+            }
+        }
+    }
+
+    /// Move backward over one element.
+    fn step_prev(&mut self) -> Result<Option<Element>, Error> {
+        loop {
+            if let Some((key, dups)) = &mut self.dups {
+                match dups.prev_node(self.db.borrow_mut())? {
+                    Some(dup) => {
+                        return Ok(Some(Element {
+                            key: key.clone(),
+                            value: dup.key,
+                        }))
+                    }
+                    None => {
+                        // Step back over the node holding the duplicates
+                        self.dups = None;
+                        self.cursor.prev_node(self.db.borrow_mut())?;
+                    }
+                }
+            }
+            let node = match self.cursor.prev_node(self.db.borrow_mut())? {
+                Some(node) => node,
+                None => return Ok(None),
+            };
+            if node.flags.contains(model::NodeFlags::DUPDATA) {
+                self.cursor.next_node(self.db.borrow_mut())?;
+            }
+            if let Some(element) = self.enter(node, false)? {
+                return Ok(Some(element));
+            }
+        }
+    }
 
-        // Try to move next
-        if let Some(page) = &self.page {
-            self.node_idx += 1;
-            if page.nodes.len() == self.node_idx {
-                self.next_page()?
+    /// Return the next element within bounds. When past the bounds, the
+    /// cursor stays on the last element returned.
+    fn advance(&mut self) -> Result<Option<Element>, Error> {
+        while let Some(element) = self.step_next()? {
+            if self.before_start(&element.key) || self.below_prefix(&element.key) {
+                continue;
+            }
+            if !self.in_bounds(&element.key) {
+                self.step_prev()?;
+                return Ok(None);
             }
+            self.direction = Some(Direction::Forward);
+            return Ok(Some(element));
         }
+        Ok(None)
+    }
 
-        Ok(element)
+    /// Return the previous element within bounds.
+    fn retreat(&mut self) -> Result<Option<Element>, Error> {
+        while let Some(element) = self.step_prev()? {
+            if self.before_start(&element.key) {
+                self.step_next()?;
+                return Ok(None);
+            }
+            if !self.in_bounds(&element.key) {
+                continue;
+            }
+            self.direction = Some(Direction::Backward);
+            return Ok(Some(element));
+        }
+        Ok(None)
+    }
+
+    /// Move to the first element within bounds.
+    pub fn seek_first(&mut self) -> Result<Option<Element>, Error> {
+        self.dups = None;
+        self.direction = None;
+        match self.start.clone() {
+            Bound::Included(start) | Bound::Excluded(start) => {
+                self.cursor.seek(self.db.borrow_mut(), &start)?
+            }
+            Bound::Unbounded => self.cursor.first(self.db.borrow_mut())?,
+        }
+        self.advance()
+    }
+
+    /// Move to the last element within bounds.
+    pub fn seek_last(&mut self) -> Result<Option<Element>, Error> {
+        self.dups = None;
+        self.direction = None;
+        match self.end.clone() {
+            Bound::Included(end) | Bound::Excluded(end) => {
+                // Move after the last key not above the bound
+                self.cursor.seek(self.db.borrow_mut(), &end)?;
+                while let Some(node) = self.cursor.next_node(self.db.borrow_mut())? {
+                    if self.after_end(&node.key) {
+                        self.cursor.prev_node(self.db.borrow_mut())?;
+                        break;
+                    }
+                }
+            }
+            Bound::Unbounded => self.cursor.last(self.db.borrow_mut())?,
+        }
+        self.retreat()
+    }
+
+    /// Move to the first element within bounds whose key is not below `key`
+    /// (`MDB_SET_RANGE`).
+    pub fn seek(&mut self, key: &[u8]) -> Result<Option<Element>, Error> {
+        if self.before_start(key) {
+            return self.seek_first();
+        }
+        self.dups = None;
+        self.direction = None;
+        self.cursor.seek(self.db.borrow_mut(), key)?;
+        self.advance()
+    }
+
+    /// Move to the next element, see `Iterator::next`.
+    pub fn move_next(&mut self) -> Result<Option<Element>, Error> {
+        match self.direction {
+            None => return self.seek_first(),
+            Some(Direction::Backward) => {
+                // Step over the current element
+                self.step_next()?;
+            }
+            Some(Direction::Forward) => (),
+        }
+        self.advance()
+    }
+
+    pub fn prev(&mut self) -> Result<Option<Element>, Error> {
+        match self.direction {
+            None => return self.seek_last(),
+            Some(Direction::Forward) => {
+                // Step over the current element
+                self.step_prev()?;
+            }
+            Some(Direction::Backward) => (),
+        }
+        self.retreat()
+    }
+}
+
+impl<'a, D: BorrowMut<Database<'a>>> Iterator for ReadCursor<D> {
+    type Item = Result<Element, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.move_next().transpose()
     }
 }
 
 pub struct WriteCursor<'a, 'b> {
     pub db: &'b mut Database<'a>,
+    pub name: Option<Vec<u8>>,
+    pub tree: model::Database,
     pub page: model::Leaf,
+    pub children: Vec<model::BranchNode>,
+    pub last_key: Option<Vec<u8>>,
+    pub databases: VecDeque<(Vec<u8>, model::Database)>,
+    pub dup_key: Option<Vec<u8>>,
+    pub dups: Vec<Vec<u8>>,
 }
 
 impl<'a, 'b> WriteCursor<'a, 'b> {
+    /// Start bulk-loading a new main tree. Elements must be pushed in
+    /// ascending key order; pages are allocated after `last_pgno`.
+    /// Named databases committed beforehand are merged into the main tree.
+    /// The main tree is only bulk-loaded once, in a database just created:
+    /// committed records are updated with `Database::put`.
     pub fn init(db: &'b mut Database<'a>) -> Result<Self, Error> {
-        let last = db.meta.last_pgno as usize;
-        let page = match db.reader {
-            Some(ref reader) => {
-                let mut reader = reader.lock().unwrap();
-                Database::seek_page_unsafe(reader.as_mut(), last)?;
-                Database::read_leaf_unsafe(reader.as_mut())?
-            }
-            None => model::Leaf {
-                pageno: if last > 1 { last + 1 } else { 2 },
-                flags: model::header::Flags::LEAF,
-                nodes: Vec::<model::Node>::new(),
-            },
+        if db.meta.txnid > 0 {
+            return Err(Report::new(Error::WriteError)
+                .attach_printable("the main database is already committed"));
+        }
+        let flags = db.meta.main.flags;
+        let mut cur = Self::init_tree(db, None, flags)?;
+        let mut databases = std::mem::take(&mut cur.db.named);
+        databases.sort_by(|a, b| a.0.cmp(&b.0));
+        cur.databases = databases.into();
+        Ok(cur)
+    }
+
+    /// Start bulk-loading a named database. It is recorded in the main tree
+    /// by the next commit of a main cursor (or by `Database::close`).
+    pub fn init_named(
+        db: &'b mut Database<'a>,
+        name: &[u8],
+        flags: model::metadata::Flags,
+    ) -> Result<Self, Error> {
+        Self::init_tree(db, Some(name.to_vec()), flags)
+    }
+
+    pub(crate) fn init_tree(
+        db: &'b mut Database<'a>,
+        name: Option<Vec<u8>>,
+        flags: model::metadata::Flags,
+    ) -> Result<Self, Error> {
+        if db.writer.is_none() {
+            return Err(Report::new(Error::NoWriter));
+        }
+        let tree = model::Database {
+            pad: 0,
+            flags,
+            depth: 0,
+            branch_pages: 0,
+            leaf_pages: 0,
+            overflow_pages: 0,
+            entries: 0,
+            root: None,
+        };
+        let cur = WriteCursor {
+            db,
+            name,
+            page: Self::empty_leaf(&tree),
+            tree,
+            children: Vec::new(),
+            last_key: None,
+            databases: VecDeque::new(),
+            dup_key: None,
+            dups: Vec::new(),
         };
-        let cur = WriteCursor { db, page };
         Ok(cur)
     }
 
+    /// Trees of fixed-size duplicates are made of `LEAF2` pages.
+    fn empty_leaf(tree: &model::Database) -> model::Leaf {
+        let flags = if tree.flags.contains(model::metadata::Flags::DUPFIXED)
+            && !tree.flags.contains(model::metadata::Flags::DUPSORT)
+        {
+            model::header::Flags::LEAF | model::header::Flags::LEAF2
+        } else {
+            model::header::Flags::LEAF
+        };
+        model::Leaf {
+            pageno: 0,
+            flags,
+            nodes: Vec::<model::Node>::new(),
+        }
+    }
+
+    fn allocate(&mut self, count: u64) -> u64 {
+        let pageno = self.db.meta.last_pgno + 1;
+        self.db.meta.last_pgno += count;
+        pageno
+    }
+
     pub fn push(&mut self, key: Vec<u8>, data: Vec<u8>) -> Result<(), Error> {
         self.push_element(Element { key, value: data })
     }
 
+    fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+        let endianness = self
+            .db
+            .writer
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .endianness();
+        compare::compare(self.tree.flags, endianness, a, b)
+    }
+
+    fn check_key_order(&self, key: &[u8]) -> Result<(), Error> {
+        match &self.last_key {
+            Some(last_key) if self.compare(key, last_key).is_le() => {
+                Err(Report::new(Error::InvalidKeyOrder)
+                    .attach_printable(format!("{:?} pushed after {:?}", key, last_key)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// In `DUPSORT` trees, the values of a key are pushed as elements with
+    /// the same key, in ascending order.
     pub fn push_element(&mut self, element: Element) -> Result<(), Error> {
-        if element.value.len() > 2048 {
-            // Store in overflow page
-            let mut writer = self.db.writer.as_ref().unwrap().lock().unwrap();
+        self.check_key_size("keys", &element.key)?;
+        if self.tree.flags.contains(model::metadata::Flags::DUPSORT) {
+            // Duplicates are the keys of their sub-page or sub-tree
+            self.check_key_size("values of DUPSORT databases", &element.value)?;
+            return self.push_duplicate(element);
+        }
+        self.push_value(element)
+    }
+
+    /// Same bound as `mdb_put`, which keeps keys within a node.
+    fn check_key_size(&self, what: &str, key: &[u8]) -> Result<(), Error> {
+        let word_size = self.db.writer.as_ref().unwrap().lock().unwrap().word_size();
+        let key_max = lowlevel::key_max(word_size, self.db.page_size);
+        if key.is_empty() || key.len() > key_max {
+            return Err(Report::new(Error::WriteError).attach_printable(format!(
+                "{} must hold 1 to {} bytes, got {}",
+                what,
+                key_max,
+                key.len()
+            )));
+        }
+        Ok(())
+    }
 
-            let pageno = self.page.pageno as u64 + 1;
-            self.db.meta.main.overflow_pages += 1;
-            self.db.meta.last_pgno = pageno;
+    fn push_duplicate(&mut self, element: Element) -> Result<(), Error> {
+        if let Some(dup_key) = &self.dup_key {
+            match self.compare(&element.key, dup_key) {
+                std::cmp::Ordering::Less => {
+                    return Err(Report::new(Error::InvalidKeyOrder)
+                        .attach_printable(format!("{:?} pushed after {:?}", element.key, dup_key)))
+                }
+                std::cmp::Ordering::Equal => {
+                    let endianness = self
+                        .db
+                        .writer
+                        .as_ref()
+                        .unwrap()
+                        .lock()
+                        .unwrap()
+                        .endianness();
+                    let last = self.dups.last().unwrap();
+                    if compare::compare_dups(self.tree.flags, endianness, &element.value, last)
+                        .is_le()
+                    {
+                        return Err(
+                            Report::new(Error::InvalidKeyOrder).attach_printable(format!(
+                                "{:?} pushed after {:?}",
+                                element.value, last
+                            )),
+                        );
+                    }
+                    self.dups.push(element.value);
+                    return Ok(());
+                }
+                std::cmp::Ordering::Greater => self.flush_duplicates()?,
+            }
+        }
+        self.check_key_order(&element.key)?;
+        self.dup_key = Some(element.key);
+        self.dups = vec![element.value];
+        Ok(())
+    }
+
+    /// Write the values pending for the last key: a plain node for a single
+    /// value, else a sub-page, or a sub-tree when the sub-page would not fit
+    /// in a node.
+    fn flush_duplicates(&mut self) -> Result<(), Error> {
+        let key = match self.dup_key.take() {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let mut dups = std::mem::take(&mut self.dups);
+        if dups.len() == 1 {
+            return self.push_value(Element {
+                key,
+                value: dups.pop().unwrap(),
+            });
+        }
+
+        let flags = self.tree.flags.dup_flags();
+        if flags.contains(model::metadata::Flags::DUPFIXED)
+            && dups.iter().any(|dup| dup.len() != dups[0].len())
+        {
+            return Err(Report::new(Error::WriteError)
+                .attach_printable(format!("values of {:?} differ in size", key)));
+        }
+
+        let word_size = self.db.writer.as_ref().unwrap().lock().unwrap().word_size();
+        let mut sub = model::Leaf {
+            pageno: 0,
+            flags: model::header::Flags::LEAF
+                | model::header::Flags::DIRTY
+                | model::header::Flags::SUB,
+            nodes: dups
+                .into_iter()
+                .map(|dup| model::Node {
+                    flags: model::NodeFlags::empty(),
+                    key: dup,
+                    data: model::NodeData::Data(vec![]),
+                })
+                .collect(),
+        };
+        if flags.contains(model::metadata::Flags::DUPFIXED) {
+            sub.flags |= model::header::Flags::LEAF2;
+        }
+        let size = lowlevel::NODE_SIZE + key.len() + sub.sub_page_size(word_size);
+        if size <= lowlevel::node_max(word_size, self.db.page_size) {
+            return self.push_node(model::Node {
+                flags: model::NodeFlags::DUPDATA,
+                key,
+                data: model::NodeData::SubPage(sub),
+            });
+        }
+
+        // Bulk-load the duplicates in their own tree
+        let mut cur = WriteCursor::init_tree(self.db, None, flags)?;
+        if flags.contains(model::metadata::Flags::DUPFIXED) {
+            cur.tree.pad = sub.nodes[0].key.len() as u32;
+        }
+        for node in sub.nodes {
+            cur.append_node(node)?;
+        }
+        let tree = cur.finish()?;
+        self.push_node(model::Node {
+            flags: model::NodeFlags::SUBDATA | model::NodeFlags::DUPDATA,
+            key,
+            data: model::NodeData::SubData(tree),
+        })
+    }
+
+    fn push_value(&mut self, element: Element) -> Result<(), Error> {
+        self.check_key_order(&element.key)?;
+        let word_size = self.db.writer.as_ref().unwrap().lock().unwrap().word_size();
+        let size = lowlevel::NODE_SIZE + element.key.len() + element.value.len();
+        if size > lowlevel::node_max(word_size, self.db.page_size) {
+            // Store in a run of overflow pages
+            let pages =
+                lowlevel::overflow_pages(word_size, self.db.page_size, element.value.len()) as u64;
+            let pageno = self.allocate(pages);
+            self.tree.overflow_pages += pages;
+
+            let mut writer = self.db.writer.as_ref().unwrap().lock().unwrap();
             Database::write_overflow_unsafe(
                 writer.as_mut(),
                 model::Overflow {
                     pageno,
                     data: element.value.clone(),
                 },
+                self.db.page_size,
             )?;
             drop(writer);
 
@@ -143,55 +881,177 @@ impl<'a, 'b> WriteCursor<'a, 'b> {
     }
 
     pub fn push_node(&mut self, node: model::Node) -> Result<(), Error> {
-        let size = self
-            .page
-            .nodes
-            .iter()
-            .map(|node| node.size())
-            .reduce(|a, b| a + b)
-            .unwrap_or(0);
-        if size + node.size() >= 4096 - 6 * (self.page.nodes.len() + 1) {
-            let mut writer = self.db.writer.as_ref().unwrap().lock().unwrap();
-            tracing::debug!("Writing leaf page: {:#?}", self.page);
-            Database::write_leaf_unsafe(writer.as_mut(), self.page.clone())?;
-            self.db.meta.last_pgno = std::cmp::max(self.db.meta.last_pgno, self.page.pageno as u64);
-            self.db.meta.main.entries += self.page.nodes.len() as u64;
-            self.db.meta.main.leaf_pages += 1;
-            self.db.meta.main.depth = 1;
-            self.db.meta.main.root =
-                Some(self.db.meta.main.root.unwrap_or(self.page.pageno as u64));
-            tracing::debug!("Updated meta: {:#?}", self.db.meta);
-
-            self.page = model::Leaf {
-                pageno: self.page.pageno + 1,
-                flags: model::header::Flags::LEAF,
-                nodes: Vec::<model::Node>::new(),
-            };
+        while self
+            .databases
+            .front()
+            .is_some_and(|(name, _)| self.compare(name, &node.key).is_lt())
+        {
+            self.push_database()?;
+        }
+        self.append_node(node)
+    }
+
+    /// Record the next pending named database in the main tree.
+    fn push_database(&mut self) -> Result<(), Error> {
+        let (name, tree) = self.databases.pop_front().unwrap();
+        self.append_node(model::Node {
+            flags: model::NodeFlags::SUBDATA,
+            key: name,
+            data: model::NodeData::SubData(tree),
+        })
+    }
+
+    fn append_node(&mut self, node: model::Node) -> Result<(), Error> {
+        self.check_key_order(&node.key)?;
+        self.last_key = Some(node.key.clone());
+
+        let word_size = self.db.writer.as_ref().unwrap().lock().unwrap().word_size();
+        // Keys of LEAF2 pages are packed, without pointer nor node header
+        let leaf2 = self.page.flags.contains(model::header::Flags::LEAF2);
+        let space = |node: &model::Node| {
+            if leaf2 {
+                node.key.len()
+            } else {
+                2 + lowlevel::even(node.size(word_size))
+            }
+        };
+        let used = self.page.nodes.iter().map(space).sum::<usize>();
+        let room = self.db.page_size - lowlevel::page_header_size(word_size);
+        if !self.page.nodes.is_empty() && used + space(&node) > room {
+            self.flush_leaf()?;
         }
         self.page.nodes.push(node);
 
         Ok(())
     }
 
-    pub fn commit(&mut self) -> Result<(), Error> {
+    fn flush_leaf(&mut self) -> Result<(), Error> {
+        let mut page = std::mem::replace(&mut self.page, Self::empty_leaf(&self.tree));
+        page.pageno = self.allocate(1) as usize;
+
         let mut writer = self.db.writer.as_ref().unwrap().lock().unwrap();
-        tracing::debug!("Writing page: {:#?}", self.page);
-        Database::write_leaf_unsafe(writer.as_mut(), self.page.clone())?;
+        tracing::debug!("Writing leaf page: {:#?}", page);
+        Database::write_leaf_unsafe(writer.as_mut(), page.clone(), self.db.page_size)?;
+        drop(writer);
+
+        self.tree.entries += page.nodes.iter().map(|node| node.entries()).sum::<u64>();
+        self.tree.leaf_pages += 1;
+        self.children.push(model::BranchNode {
+            key: page.nodes[0].key.clone(),
+            pageno: page.pageno as u64,
+        });
+        Ok(())
+    }
+
+    /// Write one level of branch pages above `children`, returning the
+    /// entries for the level above.
+    fn flush_branches(
+        &mut self,
+        children: Vec<model::BranchNode>,
+    ) -> Result<Vec<model::BranchNode>, Error> {
+        let word_size = self.db.writer.as_ref().unwrap().lock().unwrap().word_size();
+        let room = self.db.page_size - lowlevel::page_header_size(word_size);
+
+        // The first key of a branch page is implicit, so it is left empty.
+        let mut pages = Vec::<Vec<model::BranchNode>>::new();
+        let mut used = room;
+        for child in children {
+            let size = 2 + lowlevel::even(lowlevel::NODE_SIZE + child.key.len());
+            if used + size > room {
+                pages.push(Vec::new());
+                used = 2 + lowlevel::NODE_SIZE;
+            } else {
+                used += size;
+            }
+            pages.last_mut().unwrap().push(child);
+        }
+        // Never leave a lone child in the last branch page.
+        let npages = pages.len();
+        if npages > 1 && pages[npages - 1].len() == 1 {
+            let child = pages[npages - 2].pop().unwrap();
+            pages.last_mut().unwrap().insert(0, child);
+        }
+
+        let mut parents = Vec::<model::BranchNode>::new();
+        for mut nodes in pages {
+            let key = std::mem::take(&mut nodes[0].key);
+            let branch = model::Branch {
+                pageno: self.allocate(1) as usize,
+                flags: model::header::Flags::BRANCH,
+                nodes,
+            };
+            parents.push(model::BranchNode {
+                key,
+                pageno: branch.pageno as u64,
+            });
+
+            let mut writer = self.db.writer.as_ref().unwrap().lock().unwrap();
+            tracing::debug!("Writing branch page: {:#?}", branch);
+            Database::write_branch_unsafe(writer.as_mut(), branch, self.db.page_size)?;
+            drop(writer);
+            self.tree.branch_pages += 1;
+        }
+        Ok(parents)
+    }
+
+    /// Write the pending leaf and the branch pages above all leaves, and
+    /// return the resulting tree.
+    pub(crate) fn finish(&mut self) -> Result<model::Database, Error> {
+        self.flush_duplicates()?;
+        while !self.databases.is_empty() {
+            self.push_database()?;
+        }
+        if !self.page.nodes.is_empty() {
+            self.flush_leaf()?;
+        }
+
+        let mut level = std::mem::take(&mut self.children);
+        let mut depth = if level.is_empty() { 0 } else { 1 };
+        while level.len() > 1 {
+            level = self.flush_branches(level)?;
+            depth += 1;
+        }
+        self.tree.depth = depth;
+        self.tree.root = level.first().map(|node| node.pageno);
+        Ok(self.tree.clone())
+    }
+
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if let Some(name) = self.name.clone() {
+            return self.commit_named(name);
+        }
+        let tree = self.finish()?;
+
         let mut meta = self.db.meta.clone();
-        meta.last_pgno = std::cmp::max(meta.last_pgno, self.page.pageno as u64);
         meta.txnid += 1;
-        meta.main.entries += self.page.nodes.len() as u64;
-        meta.main.leaf_pages += 1;
-        meta.main.depth = 1;
-        meta.main.root = Some(meta.main.root.unwrap_or(self.page.pageno as u64));
+        meta.main = tree;
+        meta.mapsize = std::cmp::max(
+            meta.mapsize,
+            (meta.last_pgno + 1) * self.db.page_size as u64,
+        );
         tracing::debug!("Output: {:#?}", meta);
-        Database::write_meta_unsafe(writer.as_mut(), meta, (self.db.meta_id + 1) % 2)?;
+
+        let meta_id = (self.db.meta_id + 1) % 2;
+        let mut writer = self.db.writer.as_ref().unwrap().lock().unwrap();
+        Database::write_meta_unsafe(writer.as_mut(), meta.clone(), meta_id)?;
         writer.flush()?;
-        self.page = model::Leaf {
-            pageno: self.page.pageno + 1,
-            flags: model::header::Flags::LEAF,
-            nodes: Vec::<model::Node>::new(),
-        };
+        drop(writer);
+
+        self.db.meta = meta;
+        self.db.meta_id = meta_id;
+        Ok(())
+    }
+    fn commit_named(&mut self, name: Vec<u8>) -> Result<(), Error> {
+        if self.db.meta.txnid > 0 {
+            return Err(Report::new(Error::WriteError)
+                .attach_printable("named databases must be committed before the main database"));
+        }
+        if self.db.named.iter().any(|(other, _)| *other == name) {
+            return Err(Report::new(Error::InvalidKeyOrder)
+                .attach_printable(format!("database {:?} written twice", name)));
+        }
+        let tree = self.finish()?;
+        self.db.named.push((name, tree));
         Ok(())
     }
 }
@@ -1,20 +1,23 @@
 use std::sync::Mutex;
 use std::vec;
 
-use byteorder;
-
 use error_stack::Report;
 use error_stack::Result;
 use error_stack::ResultExt;
 
-use super::cursor::ReadCursor;
+use super::compare;
+use super::cursor::TreeCursor;
 use super::cursor::WriteCursor;
+use super::cursor::CURSOR_STACK;
+use super::cursor::{OwnedReadCursor, ReadCursor};
 use super::model;
-use super::model::lowlevel;
+use super::Endianness;
 
 use super::error::Error;
 
 pub trait DatabaseReader {
+    fn word_size(&self) -> usize;
+    fn endianness(&self) -> Endianness;
     fn seek(&mut self, pos: std::io::SeekFrom) -> Result<usize, Error>;
     fn pos(&mut self) -> Result<usize, Error> {
         self.seek(std::io::SeekFrom::Current(0))
@@ -28,6 +31,7 @@ pub trait DatabaseReader {
 
 pub trait DatabaseWriter {
     fn word_size(&self) -> usize;
+    fn endianness(&self) -> Endianness;
     fn seek(&mut self, pos: std::io::SeekFrom) -> Result<usize, Error>;
     fn pos(&mut self) -> Result<usize, Error> {
         self.seek(std::io::SeekFrom::Current(0))
@@ -38,17 +42,21 @@ pub trait DatabaseWriter {
     fn write_u32(&mut self, n: u32) -> Result<(), Error>;
     fn write_exact(&mut self, buf: &[u8]) -> Result<(), Error>;
     fn write_fill(&mut self, n: usize) -> Result<(), Error> {
-        let buf = vec![0 as u8; n - 1];
-        Ok(self.write_exact(&buf).change_context(Error::WriteError)?)
+        let buf = vec![0_u8; n];
+        self.write_exact(&buf).change_context(Error::WriteError)
     }
     fn flush(&mut self) -> Result<(), Error>;
+    /// Flush and wait for the data to reach the disk, as `fdatasync`.
+    fn sync(&mut self) -> Result<(), Error>;
 }
 
 pub struct Database<'a> {
     pub(crate) reader: Option<Mutex<Box<dyn DatabaseReader + 'a>>>,
     pub(crate) writer: Option<Mutex<Box<dyn DatabaseWriter + 'a>>>,
     pub(crate) meta_id: usize,
-    pub(crate) meta: model::Metadata,
+    pub meta: model::Metadata,
+    pub page_size: usize,
+    pub(crate) named: Vec<(Vec<u8>, model::Database)>,
 }
 
 impl<'a> Database<'a> {
@@ -58,12 +66,15 @@ impl<'a> Database<'a> {
     {
         let rdr: &mut (dyn DatabaseReader + 'a) = &mut reader;
         let (meta, meta_id) = Self::pick_meta_unsafe(rdr)?;
+        let page_size = meta.free.pad as usize;
 
         Ok(Self {
             reader: Some(Mutex::new(Box::new(reader))),
             writer: None,
             meta_id,
             meta,
+            page_size,
+            named: Vec::new(),
         })
     }
 
@@ -76,12 +87,76 @@ impl<'a> Database<'a> {
         Self::read_from(reader)
     }
 
-    pub fn write_from<DW>(mut writer: DW) -> Result<Self, Error>
+    /// Open the snapshot of meta page `meta_id` (0 or 1) rather than the
+    /// newest usable one.
+    pub fn read_from_meta<DR>(mut reader: DR, meta_id: usize) -> Result<Self, Error>
+    where
+        DR: DatabaseReader + 'a,
+    {
+        let rdr: &mut (dyn DatabaseReader + 'a) = &mut reader;
+        let meta = Self::select_meta_unsafe(rdr, meta_id)?;
+        let page_size = meta.free.pad as usize;
+
+        Ok(Self {
+            reader: Some(Mutex::new(Box::new(reader))),
+            writer: None,
+            meta_id,
+            meta,
+            page_size,
+            named: Vec::new(),
+        })
+    }
+
+    pub fn from_reader_meta<DR, R>(reader: R, meta_id: usize) -> Result<Self, Error>
+    where
+        R: std::io::Read + std::io::Seek,
+        DR: DatabaseReader + From<R> + 'a,
+    {
+        Self::read_from_meta(DR::from(reader), meta_id)
+    }
+
+    /// Index (0 or 1) of the meta page in use.
+    pub fn meta_id(&self) -> usize {
+        self.meta_id
+    }
+
+    /// Open an existing database for updates, see `Database::put`. Both
+    /// ends must work on the same file.
+    pub fn read_write_from<DR, DW>(mut reader: DR, writer: DW) -> Result<Self, Error>
+    where
+        DR: DatabaseReader + 'a,
+        DW: DatabaseWriter + 'a,
+    {
+        let rdr: &mut (dyn DatabaseReader + 'a) = &mut reader;
+        let (meta, meta_id) = Self::pick_meta_unsafe(rdr)?;
+        let page_size = meta.free.pad as usize;
+
+        Ok(Self {
+            reader: Some(Mutex::new(Box::new(reader))),
+            writer: Some(Mutex::new(Box::new(writer))),
+            meta_id,
+            meta,
+            page_size,
+            named: Vec::new(),
+        })
+    }
+
+    pub fn from_reader_writer<DR, DW, R, W>(reader: R, writer: W) -> Result<Self, Error>
+    where
+        R: std::io::Read + std::io::Seek,
+        W: std::io::Write + std::io::Seek,
+        DR: DatabaseReader + From<R> + 'a,
+        DW: DatabaseWriter + From<W> + 'a,
+    {
+        Self::read_write_from(DR::from(reader), DW::from(writer))
+    }
+
+    pub fn write_from<DW>(mut writer: DW, page_size: usize) -> Result<Self, Error>
     where
         DW: DatabaseWriter + 'a,
     {
         let wtr: &mut (dyn DatabaseWriter + 'a) = &mut writer;
-        let (meta1, meta2) = Self::init_meta_unsafe()?;
+        let (meta1, meta2) = Self::init_meta_unsafe(page_size)?;
         Self::write_meta_unsafe(wtr, meta1.clone(), 0)?;
         Self::write_meta_unsafe(wtr, meta2.clone(), 1)?;
 
@@ -90,40 +165,295 @@ impl<'a> Database<'a> {
             writer: Some(Mutex::new(Box::new(writer))),
             meta_id: 0,
             meta: meta1,
+            page_size,
+            named: Vec::new(),
         })
     }
 
-    pub fn from_writer<DW, W>(writer: W) -> Result<Self, Error>
+    pub fn from_writer<DW, W>(writer: W, page_size: usize) -> Result<Self, Error>
     where
         W: std::io::Write + std::io::Seek,
         DW: DatabaseWriter + From<W> + 'a,
     {
         let writer = DW::from(writer);
-        Self::write_from(writer)
+        Self::write_from(writer, page_size)
     }
 
     pub fn close(&mut self) -> Result<(), Error> {
-        return Ok(());
+        // Record named databases nobody committed a main tree for
+        if !self.named.is_empty() {
+            self.write_cursor()?.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Environment flags, such as `MDB_NOSUBDIR`, which LMDB keeps next to
+    /// `INTEGERKEY` in the flags of the free database.
+    pub fn env_flags(&self) -> u16 {
+        self.meta.free.flags.bits() & !model::metadata::Flags::INTEGERKEY.bits()
+    }
+
+    /// Set the environment flags of a new database, on both meta pages as
+    /// `mdb_env_init_meta` does.
+    pub fn set_env_flags(&mut self, flags: u16) -> Result<(), Error> {
+        if self.meta.txnid > 0 {
+            return Err(Report::new(Error::WriteError)
+                .attach_printable("environment flags must be set before the first commit"));
+        }
+        let writer = self.writer.as_ref().ok_or(Report::new(Error::NoWriter))?;
+        let mut writer = writer.lock().unwrap();
+        self.meta.free.flags =
+            model::metadata::Flags::from_bits_retain(flags) | model::metadata::Flags::INTEGERKEY;
+        Self::write_meta_unsafe(writer.as_mut(), self.meta.clone(), 0)?;
+        Self::write_meta_unsafe(writer.as_mut(), self.meta.clone(), 1)?;
+        Ok(())
+    }
+
+    /// Copy the records of this database into `out`, a database just
+    /// created, as `mdb_copy -c` does: only the pages reachable from the
+    /// current meta page are read, and they are rewritten densely in a
+    /// single transaction, leaving out the free list and any dead page.
+    pub fn compact_into(&mut self, out: &mut Database<'_>) -> Result<(), Error> {
+        if out.meta.txnid > 0 {
+            return Err(Report::new(Error::WriteError)
+                .attach_printable("compacting into a database already written"));
+        }
+        out.set_env_flags(self.env_flags())?;
+        out.meta.mapsize = self.meta.mapsize;
+
+        for (name, tree) in self.databases()? {
+            let mut cur = out.write_cursor_named(&name, tree.flags)?;
+            for element in self.read_cursor_named(&name)? {
+                cur.push_element(element?)?;
+            }
+            cur.commit()?;
+        }
+
+        out.meta.main.flags = self.meta.main.flags;
+        let mut cur = out.write_cursor()?;
+        for element in self.read_cursor()? {
+            cur.push_element(element?)?;
+        }
+        cur.commit()
+    }
+
+    /// List the named databases recorded in the main tree, in key order.
+    pub fn databases(&mut self) -> Result<Vec<(Vec<u8>, model::Database)>, Error> {
+        let mut databases = Vec::new();
+        let mut cur = self.read_cursor()?;
+        while let Some(node) = cur.next_node()? {
+            if let model::NodeData::SubData(db) = node.data {
+                databases.push((node.key, db));
+            }
+        }
+        Ok(databases)
+    }
+
+    /// List the records of the free list (`FREE_DBI`), in `txnid` order:
+    /// the pages released by each transaction, in descending order as
+    /// stored by LMDB.
+    pub fn freelist(&mut self) -> Result<Vec<(u64, Vec<u64>)>, Error> {
+        let word_size = self.word_size();
+        let endianness = self.endianness();
+        let tree = self.meta.free.clone();
+        let mut records = Vec::new();
+        for element in ReadCursor::init_with(&mut *self, tree)? {
+            let element = element?;
+            if element.key.len() != word_size || element.value.len() < word_size {
+                return Err(Report::new(Error::InvalidFileFormat)
+                    .attach_printable(format!("invalid free list record {:?}", element.key)));
+            }
+            let txnid = endianness.read_words(&element.key, word_size)[0];
+            let words = endianness.read_words(&element.value, word_size);
+            let count = words[0] as usize;
+            if count + 1 > words.len() {
+                return Err(
+                    Report::new(Error::InvalidFileFormat).attach_printable(format!(
+                        "free list record {} holds {} pages, {} announced",
+                        txnid,
+                        words.len() - 1,
+                        count
+                    )),
+                );
+            }
+            records.push((txnid, words[1..count + 1].to_vec()));
+        }
+        Ok(records)
+    }
+
+    pub fn endianness(&self) -> Endianness {
+        match (&self.reader, &self.writer) {
+            (Some(reader), _) => reader.lock().unwrap().endianness(),
+            (None, Some(writer)) => writer.lock().unwrap().endianness(),
+            (None, None) => Endianness::Little,
+        }
+    }
+
+    pub fn word_size(&self) -> usize {
+        match (&self.reader, &self.writer) {
+            (Some(reader), _) => reader.lock().unwrap().word_size(),
+            (None, Some(writer)) => writer.lock().unwrap().word_size(),
+            (None, None) => 8,
+        }
+    }
+
+    /// Look up `key` in the main tree.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let tree = self.meta.main.clone();
+        self.get_from(&tree, key)
+    }
+
+    /// Look up `key` in the given tree, e.g. a named database. The first
+    /// value is returned for keys with duplicates, and none for named
+    /// databases.
+    pub fn get_from(
+        &mut self,
+        tree: &model::Database,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let node = match self.lookup(tree, key)? {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        match node.data {
+            model::NodeData::Data(data) => Ok(Some(data)),
+            model::NodeData::Overflow(overflow, size) => {
+                Ok(Some(self.read_overflow(overflow as usize, size)?))
+            }
+            model::NodeData::SubPage(sub) => Ok(sub.nodes.first().map(|dup| dup.key.clone())),
+            model::NodeData::SubData(db) if node.flags.contains(model::NodeFlags::DUPDATA) => {
+                let mut dups = TreeCursor::init(self, db)?;
+                Ok(dups.next_node(self)?.map(|dup| dup.key))
+            }
+            model::NodeData::SubData(_) => Ok(None),
+        }
+    }
+
+    /// Find the node of `key`, descending from the root of the tree with its
+    /// comparator.
+    pub(crate) fn lookup(
+        &mut self,
+        tree: &model::Database,
+        key: &[u8],
+    ) -> Result<Option<model::Node>, Error> {
+        let endianness = self.endianness();
+        let cmp = |a: &[u8], b: &[u8]| compare::compare(tree.flags, endianness, a, b);
+
+        if tree.branch_pages == 0 && tree.leaf_pages > 1 {
+            // Leaves laid out without branch pages, by earlier versions of
+            // this tool
+            let mut cur = TreeCursor::init(self, tree.clone())?;
+            while let Some(node) = cur.next_node(self)? {
+                if cmp(&node.key, key).is_eq() {
+                    return Ok(Some(node));
+                }
+            }
+            return Ok(None);
+        }
+
+        let mut pageno = match tree.root {
+            Some(root) => root as usize,
+            None => return Ok(None),
+        };
+        for _ in 0..CURSOR_STACK {
+            match self.read_page(pageno)? {
+                model::Page::Branch(branch) => {
+                    // Last child whose separator is not above the key, the
+                    // first separator being implicit
+                    let idx = branch
+                        .nodes
+                        .iter()
+                        .skip(1)
+                        .take_while(|node| cmp(&node.key, key).is_le())
+                        .count();
+                    pageno = match branch.nodes.get(idx) {
+                        Some(node) => node.pageno as usize,
+                        None => {
+                            return Err(Report::new(Error::InvalidFileFormat)
+                                .attach_printable(format!("empty branch page {}", pageno)))
+                        }
+                    };
+                }
+                model::Page::Leaf(mut leaf) => {
+                    return Ok(leaf
+                        .nodes
+                        .binary_search_by(|node| cmp(&node.key, key))
+                        .ok()
+                        .map(|idx| leaf.nodes.swap_remove(idx)));
+                }
+            }
+        }
+        Err(Report::new(Error::InvalidFileFormat)
+            .attach_printable(format!("B-tree deeper than {}", CURSOR_STACK)))
+    }
+
+    pub fn database(&mut self, name: &[u8]) -> Result<Option<model::Database>, Error> {
+        let tree = self.meta.main.clone();
+        Ok(match self.lookup(&tree, name)? {
+            Some(model::Node {
+                data: model::NodeData::SubData(db),
+                flags,
+                ..
+            }) if !flags.contains(model::NodeFlags::DUPDATA) => Some(db),
+            _ => None,
+        })
     }
 }
 
 impl<'a> Database<'a> {
-    pub fn read_cursor<'b>(&'b mut self) -> Result<ReadCursor<'a, 'b>, Error> {
+    pub fn read_cursor<'b>(&'b mut self) -> Result<ReadCursor<&'b mut Database<'a>>, Error> {
         ReadCursor::init(self)
     }
 
+    pub fn read_cursor_named<'b>(
+        &'b mut self,
+        name: &[u8],
+    ) -> Result<ReadCursor<&'b mut Database<'a>>, Error> {
+        let tree = self.database(name)?.ok_or_else(|| {
+            Report::new(Error::DatabaseNotFound)
+                .attach_printable(String::from_utf8_lossy(name).to_string())
+        })?;
+        ReadCursor::init_with(self, tree)
+    }
+
+    /// Same as `read_cursor`, the cursor taking ownership of the database.
+    pub fn into_read_cursor(self) -> Result<OwnedReadCursor<'a>, Error> {
+        ReadCursor::init(self)
+    }
+
+    /// Same as `read_cursor_named`, the cursor taking ownership of the
+    /// database.
+    pub fn into_read_cursor_named(mut self, name: &[u8]) -> Result<OwnedReadCursor<'a>, Error> {
+        let tree = self.database(name)?.ok_or_else(|| {
+            Report::new(Error::DatabaseNotFound)
+                .attach_printable(String::from_utf8_lossy(name).to_string())
+        })?;
+        ReadCursor::init_with(self, tree)
+    }
+
     pub fn write_cursor<'b>(&'b mut self) -> Result<WriteCursor<'a, 'b>, Error> {
         WriteCursor::init(self)
     }
+
+    pub fn write_cursor_named<'b>(
+        &'b mut self,
+        name: &[u8],
+        flags: model::metadata::Flags,
+    ) -> Result<WriteCursor<'a, 'b>, Error> {
+        WriteCursor::init_named(self, name, flags)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::sync::Once;
 
+    use crate::lmdb::Endianness;
     use crate::lmdb::Factory;
     use crate::lmdb::WordSize;
 
+    use std::ops::Bound;
+
     use super::*;
 
     macro_rules! test_case {
@@ -134,7 +464,7 @@ mod tests {
 
     static INIT: Once = Once::new();
 
-    pub fn setup() -> () {
+    pub fn setup() {
         INIT.call_once(|| {
             tracing_subscriber::fmt::fmt()
                 .with_max_level(tracing::Level::DEBUG)
@@ -147,11 +477,9 @@ mod tests {
         setup();
 
         let mut db = Factory::open(test_case!("mender-store.64bits")).unwrap();
-        let mut cur = db.read_cursor().unwrap();
-        let mut i = 0;
-        while let Some(node) = cur.next().unwrap() {
+        for (i, node) in db.read_cursor().unwrap().enumerate() {
+            let node = node.unwrap();
             tracing::debug!("#{}: {:#?}", i, node);
-            i += 1;
         }
     }
 
@@ -160,11 +488,9 @@ mod tests {
         setup();
 
         let mut db = Factory::open(test_case!("mender-store.32bits")).unwrap();
-        let mut cur = db.read_cursor().unwrap();
-        let mut i = 0;
-        while let Some(node) = cur.next().unwrap() {
+        for (i, node) in db.read_cursor().unwrap().enumerate() {
+            let node = node.unwrap();
             tracing::debug!("#{}: {:#?}", i, node);
-            i += 1;
         }
     }
 
@@ -173,12 +499,79 @@ mod tests {
         setup();
 
         let mut db = Factory::open(test_case!("mender-store.32bits.2")).unwrap();
+        for (i, node) in db.read_cursor().unwrap().enumerate() {
+            let node = node.unwrap();
+            tracing::debug!("#{}: {:#?}", i, node);
+        }
+    }
+
+    #[test]
+    fn test_read_btree_64() {
+        setup();
+
+        let mut db = Factory::open(test_case!("btree.64bits")).unwrap();
+        assert_eq!(db.meta.main.depth, 3);
         let mut cur = db.read_cursor().unwrap();
         let mut i = 0;
-        while let Some(node) = cur.next().unwrap() {
-            tracing::debug!("#{}: {:#?}", i, node);
+        for node in &mut cur {
+            let node = node.unwrap();
+            let prefix = format!("key-{:04}-", i);
+            assert!(node.key.starts_with(prefix.as_bytes()));
+            assert_eq!(node.value, format!("value-{}", i).as_bytes());
+            i += 1;
+        }
+        assert_eq!(i, 150);
+    }
+
+    #[test]
+    fn test_read_overflow_64() {
+        setup();
+
+        let mut db = Factory::open(test_case!("overflow.64bits")).unwrap();
+        assert_eq!(db.meta.main.overflow_pages, 8);
+        let mut cur = db.read_cursor().unwrap();
+        let mut sizes = vec![];
+        for node in &mut cur {
+            let node = node.unwrap();
+            assert!(node
+                .value
+                .iter()
+                .enumerate()
+                .all(|(i, &b)| b == (i % 251) as u8));
+            sizes.push(node.value.len());
+        }
+        assert_eq!(sizes, vec![10, 5000, 20000, 2030, 2029]);
+    }
+
+    #[test]
+    fn test_write_overflow_32() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word32).unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        let sizes = [10, 5000, 20000, 2032, 2031];
+        for (i, size) in sizes.iter().enumerate() {
+            let value = (0..*size).map(|j| (j % 251) as u8).collect();
+            cur.push(vec![b'a' + i as u8], value).unwrap();
+        }
+        cur.commit().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert_eq!(db.meta.main.overflow_pages, 2 + 5 + 1);
+        assert_eq!(db.meta.last_pgno, 1 + 8 + 1);
+        let mut cur = db.read_cursor().unwrap();
+        let mut i = 0;
+        for node in &mut cur {
+            let node = node.unwrap();
+            assert_eq!(node.value.len(), sizes[i]);
+            assert!(node
+                .value
+                .iter()
+                .enumerate()
+                .all(|(i, &b)| b == (i % 251) as u8));
             i += 1;
         }
+        assert_eq!(i, sizes.len());
     }
 
     #[test]
@@ -193,11 +586,9 @@ mod tests {
         tracing::info!("Reading back {:?}", file.path());
 
         let mut db = Factory::open(file.path().into()).unwrap();
-        let mut cur = db.read_cursor().unwrap();
-        let mut i = 0;
-        while let Some(node) = cur.next().unwrap() {
+        for (i, node) in db.read_cursor().unwrap().enumerate() {
+            let node = node.unwrap();
             tracing::debug!("#{}: {:#?}", i, node);
-            i += 1;
         }
     }
 
@@ -213,14 +604,53 @@ mod tests {
         tracing::info!("Reading back {:?}", file.path());
 
         let mut db = Factory::open(file.path().into()).unwrap();
-        let mut cur = db.read_cursor().unwrap();
-        let mut i = 0;
-        while let Some(node) = cur.next().unwrap() {
+        for (i, node) in db.read_cursor().unwrap().enumerate() {
+            let node = node.unwrap();
             tracing::debug!("#{}: {:#?}", i, node);
-            i += 1;
         }
     }
 
+    #[test]
+    fn test_write_key_size_64() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word64).unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        assert!(cur.push(vec![], b"value".to_vec()).is_err());
+        assert!(cur.push(vec![b'k'; 512], b"value".to_vec()).is_err());
+        assert!(cur.push(vec![b'k'; 5000], b"value".to_vec()).is_err());
+        cur.push(vec![b'k'; 511], b"value".to_vec()).unwrap();
+        cur.commit().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert_eq!(db.get(&[b'k'; 511]).unwrap().unwrap(), b"value");
+
+        // Duplicates are stored as keys
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word64).unwrap();
+        db.meta.main.flags = model::metadata::Flags::DUPSORT;
+        let mut cur = db.write_cursor().unwrap();
+        assert!(cur.push(b"key".to_vec(), vec![b'v'; 512]).is_err());
+
+        // Smaller pages hold smaller keys
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create_with(
+            file.path().into(),
+            WordSize::Word64,
+            Endianness::Little,
+            512,
+        )
+        .unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        assert!(cur.push(vec![b'k'; 500], b"value".to_vec()).is_err());
+        assert!(cur.push(vec![b'k'; 191], vec![b'v'; 1000]).is_err());
+        cur.push(vec![b'k'; 190], vec![b'v'; 1000]).unwrap();
+        cur.commit().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert_eq!(db.get(&[b'k'; 190]).unwrap().unwrap(), vec![b'v'; 1000]);
+    }
+
     #[test]
     fn test_write_multi_page_64() {
         setup();
@@ -228,8 +658,8 @@ mod tests {
         let mut db = Factory::create(file.path().into(), WordSize::Word64).unwrap();
         let mut cur = db.write_cursor().unwrap();
 
-        for i in 0..4096 {
-            cur.push(vec![(i % 255) as u8; 1], vec![(i % 255) as u8; 2])
+        for i in 0..4096u16 {
+            cur.push(i.to_be_bytes().to_vec(), vec![(i % 255) as u8; 2])
                 .unwrap();
         }
         cur.commit().unwrap();
@@ -238,8 +668,11 @@ mod tests {
 
         let mut db = Factory::open(file.path().into()).unwrap();
         let mut cur = db.read_cursor().unwrap();
-        let mut i = 0;
-        while cur.next().unwrap().is_some() {
+        let mut i = 0u16;
+        for node in &mut cur {
+            let node = node.unwrap();
+            assert_eq!(node.key, i.to_be_bytes());
+            assert_eq!(node.value, vec![(i % 255) as u8; 2]);
             i += 1;
         }
         assert_eq!(i, 4096);
@@ -247,5 +680,744 @@ mod tests {
         tracing::debug!("Metadata: {:?}", db.meta);
         assert_ne!(db.meta.last_pgno, 1);
         assert_eq!(db.meta.main.entries, 4096);
+        assert_eq!(db.meta.main.depth, 2);
+        assert_eq!(db.meta.main.branch_pages, 1);
+    }
+
+    #[test]
+    fn test_write_multi_level_32() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word32).unwrap();
+        let mut cur = db.write_cursor().unwrap();
+
+        let key = |i: usize| format!("key-{:05}-{}", i, "k".repeat(200)).into_bytes();
+        for i in 0..3000 {
+            cur.push(key(i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        assert!(cur.push(key(0), vec![]).is_err());
+        cur.commit().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert_eq!(db.meta.main.depth, 3);
+        assert_eq!(db.meta.main.entries, 3000);
+        assert_eq!(
+            db.meta.last_pgno,
+            1 + db.meta.main.leaf_pages + db.meta.main.branch_pages
+        );
+        let mut cur = db.read_cursor().unwrap();
+        let mut i = 0;
+        for node in &mut cur {
+            let node = node.unwrap();
+            assert_eq!(node.key, key(i));
+            assert_eq!(node.value, format!("value-{}", i).into_bytes());
+            i += 1;
+        }
+        assert_eq!(i, 3000);
+    }
+
+    #[test]
+    fn test_write_page_size_32() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create_with(
+            file.path().into(),
+            WordSize::Word32,
+            Endianness::Little,
+            16384,
+        )
+        .unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        for i in 0..2000u16 {
+            let size = if i.is_multiple_of(100) { 40000 } else { 100 };
+            cur.push(i.to_be_bytes().to_vec(), vec![(i % 255) as u8; size])
+                .unwrap();
+        }
+        cur.commit().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert_eq!(db.page_size, 16384);
+        assert_eq!(db.meta.main.overflow_pages, 20 * 3);
+        assert_eq!(
+            file.as_file().metadata().unwrap().len(),
+            (db.meta.last_pgno + 1) * 16384
+        );
+        let mut cur = db.read_cursor().unwrap();
+        let mut i = 0u16;
+        for node in &mut cur {
+            let node = node.unwrap();
+            assert_eq!(node.key, i.to_be_bytes());
+            assert_eq!(
+                node.value.len(),
+                if i.is_multiple_of(100) { 40000 } else { 100 }
+            );
+            i += 1;
+        }
+        assert_eq!(i, 2000);
+    }
+
+    #[test]
+    fn test_invalid_page_size() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert!(Factory::create_with(
+            file.path().into(),
+            WordSize::Word64,
+            Endianness::Little,
+            1000
+        )
+        .is_err());
+        assert!(Factory::create_with(
+            file.path().into(),
+            WordSize::Word64,
+            Endianness::Little,
+            65536
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_write_big_endian_64() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db =
+            Factory::create_with(file.path().into(), WordSize::Word64, Endianness::Big, 4096)
+                .unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        for i in 0..1000u16 {
+            let size = if i.is_multiple_of(100) { 10000 } else { 100 };
+            cur.push(i.to_be_bytes().to_vec(), vec![(i % 255) as u8; size])
+                .unwrap();
+        }
+        cur.commit().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert_eq!(db.page_size, 4096);
+        assert_eq!(db.meta.main.entries, 1000);
+        assert_eq!(db.meta.main.depth, 2);
+        let mut cur = db.read_cursor().unwrap();
+        let mut i = 0u16;
+        for node in &mut cur {
+            let node = node.unwrap();
+            assert_eq!(node.key, i.to_be_bytes());
+            assert_eq!(node.value[0], (i % 255) as u8);
+            i += 1;
+        }
+        assert_eq!(i, 1000);
+    }
+
+    #[test]
+    fn test_read_named_64() {
+        setup();
+
+        let mut db = Factory::open(test_case!("named.64bits")).unwrap();
+        assert_eq!(db.meta.main.entries, 4);
+        let names = db
+            .databases()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![b"alpha".to_vec(), b"beta".to_vec()]);
+
+        let mut cur = db.read_cursor().unwrap();
+        let mut keys = vec![];
+        for node in &mut cur {
+            let node = node.unwrap();
+            keys.push(node.key);
+        }
+        assert_eq!(keys, vec![b"aaa".to_vec(), b"zzz".to_vec()]);
+
+        let alpha = db.database(b"alpha").unwrap().unwrap();
+        assert_eq!(alpha.entries, 300);
+        assert_eq!(alpha.depth, 2);
+        let mut cur = db.read_cursor_named(b"alpha").unwrap();
+        let mut i = 0;
+        for node in &mut cur {
+            let node = node.unwrap();
+            assert_eq!(node.key, format!("alpha-{:04}", i).as_bytes());
+            assert_eq!(node.value, format!("A{}", i).as_bytes());
+            i += 1;
+        }
+        assert_eq!(i, 300);
+
+        let mut cur = db.read_cursor_named(b"beta").unwrap();
+        let mut sizes = vec![];
+        for node in &mut cur {
+            let node = node.unwrap();
+            sizes.push(node.value.len());
+        }
+        assert_eq!(sizes, vec![3, 9000, 5]);
+
+        assert!(db.read_cursor_named(b"gamma").is_err());
+    }
+
+    #[test]
+    fn test_write_named_32() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word32).unwrap();
+        let mut cur = db
+            .write_cursor_named(b"sub", model::metadata::Flags::empty())
+            .unwrap();
+        for i in 0..500 {
+            cur.push(format!("sub-{:04}", i).into_bytes(), vec![1; 20])
+                .unwrap();
+        }
+        cur.commit().unwrap();
+        let mut cur = db
+            .write_cursor_named(b"empty", model::metadata::Flags::INTEGERKEY)
+            .unwrap();
+        cur.commit().unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        cur.push(b"a".to_vec(), b"1".to_vec()).unwrap();
+        cur.push(b"f".to_vec(), b"2".to_vec()).unwrap();
+        cur.push(b"z".to_vec(), b"3".to_vec()).unwrap();
+        cur.commit().unwrap();
+        assert!(db
+            .write_cursor_named(b"late", model::metadata::Flags::empty())
+            .unwrap()
+            .commit()
+            .is_err());
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert_eq!(db.meta.main.entries, 5);
+        let databases = db.databases().unwrap();
+        assert_eq!(databases.len(), 2);
+        assert_eq!(databases[0].0, b"empty");
+        assert_eq!(databases[0].1.root, None);
+        assert_eq!(databases[0].1.flags, model::metadata::Flags::INTEGERKEY);
+        assert_eq!(databases[1].0, b"sub");
+        assert_eq!(databases[1].1.entries, 500);
+
+        let mut cur = db.read_cursor().unwrap();
+        let mut keys = vec![];
+        for node in &mut cur {
+            let node = node.unwrap();
+            keys.push(node.key);
+        }
+        assert_eq!(keys, vec![b"a".to_vec(), b"f".to_vec(), b"z".to_vec()]);
+
+        let mut cur = db.read_cursor_named(b"sub").unwrap();
+        let mut i = 0;
+        for node in &mut cur {
+            let node = node.unwrap();
+            assert_eq!(node.key, format!("sub-{:04}", i).as_bytes());
+            i += 1;
+        }
+        assert_eq!(i, 500);
+    }
+
+    #[test]
+    fn test_write_named_on_close_64() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word64).unwrap();
+        let mut cur = db
+            .write_cursor_named(b"only", model::metadata::Flags::empty())
+            .unwrap();
+        cur.push(b"k".to_vec(), b"v".to_vec()).unwrap();
+        cur.commit().unwrap();
+        db.close().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert!(db.read_cursor().unwrap().next().is_none());
+        let node = db
+            .read_cursor_named(b"only")
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(node.value, b"v");
+    }
+
+    #[test]
+    fn test_read_dupsort_64() {
+        setup();
+
+        let mut db = Factory::open(test_case!("dupsort.64bits")).unwrap();
+        let dups = db.database(b"dups").unwrap().unwrap();
+        assert!(dups.flags.contains(model::metadata::Flags::DUPSORT));
+        assert_eq!(dups.entries, 504);
+        let mut cur = db.read_cursor_named(b"dups").unwrap();
+        let mut elements = vec![];
+        for element in &mut cur {
+            let element = element.unwrap();
+            elements.push((element.key, element.value));
+        }
+        assert_eq!(elements.len(), 504);
+        // Inline sub-page
+        assert_eq!(elements[0], (b"few".to_vec(), b"few-0".to_vec()));
+        assert_eq!(elements[2], (b"few".to_vec(), b"few-2".to_vec()));
+        // Sub-tree of duplicates
+        for i in 0..500 {
+            assert_eq!(elements[3 + i].0, b"many");
+            assert_eq!(elements[3 + i].1, format!("many-{:04}", i).as_bytes());
+        }
+        assert_eq!(elements[503], (b"single".to_vec(), b"only".to_vec()));
+
+        let mut cur = db.read_cursor_named(b"fixed").unwrap();
+        let mut elements = vec![];
+        for element in &mut cur {
+            let element = element.unwrap();
+            let value = u32::from_le_bytes(element.value.try_into().unwrap());
+            elements.push((element.key, value));
+        }
+        assert_eq!(elements.len(), 2003);
+        assert_eq!(
+            elements[..3],
+            [
+                (b"few".to_vec(), 8),
+                (b"few".to_vec(), 9),
+                (b"few".to_vec(), 10)
+            ]
+        );
+        for i in 0..2000 {
+            assert_eq!(elements[3 + i], (b"many".to_vec(), i as u32));
+        }
+    }
+
+    #[test]
+    fn test_write_dupsort_32() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word32).unwrap();
+        let mut cur = db
+            .write_cursor_named(b"dups", model::metadata::Flags::DUPSORT)
+            .unwrap();
+        cur.push(b"few".to_vec(), b"a".to_vec()).unwrap();
+        cur.push(b"few".to_vec(), b"b".to_vec()).unwrap();
+        assert!(cur.push(b"few".to_vec(), b"a".to_vec()).is_err());
+        for i in 0..1000 {
+            cur.push(b"many".to_vec(), format!("many-{:04}", i).into_bytes())
+                .unwrap();
+        }
+        cur.push(b"one".to_vec(), b"value".to_vec()).unwrap();
+        assert!(cur.push(b"many".to_vec(), b"late".to_vec()).is_err());
+        cur.commit().unwrap();
+
+        let flags = model::metadata::Flags::DUPSORT
+            | model::metadata::Flags::DUPFIXED
+            | model::metadata::Flags::INTEGERDUP;
+        let mut cur = db.write_cursor_named(b"fixed", flags).unwrap();
+        for i in 0..3000u32 {
+            cur.push(b"key".to_vec(), i.to_le_bytes().to_vec()).unwrap();
+        }
+        cur.commit().unwrap();
+        db.close().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        let dups = db.database(b"dups").unwrap().unwrap();
+        assert_eq!(dups.entries, 1003);
+        assert_eq!(dups.leaf_pages, 1);
+        let mut cur = db.read_cursor_named(b"dups").unwrap();
+        let mut elements = vec![];
+        for element in &mut cur {
+            let element = element.unwrap();
+            elements.push((element.key, element.value));
+        }
+        assert_eq!(elements.len(), 1003);
+        assert_eq!(elements[1], (b"few".to_vec(), b"b".to_vec()));
+        assert_eq!(elements[2].1, b"many-0000");
+        assert_eq!(elements[1001].1, b"many-0999");
+        assert_eq!(elements[1002], (b"one".to_vec(), b"value".to_vec()));
+
+        let fixed = db.database(b"fixed").unwrap().unwrap();
+        assert_eq!(fixed.entries, 3000);
+        let mut cur = db.read_cursor_named(b"fixed").unwrap();
+        let mut i = 0u32;
+        for element in &mut cur {
+            let element = element.unwrap();
+            assert_eq!(element.value, i.to_le_bytes());
+            i += 1;
+        }
+        assert_eq!(i, 3000);
+    }
+
+    #[test]
+    fn test_get_64() {
+        setup();
+
+        let mut db = Factory::open(test_case!("btree.64bits")).unwrap();
+        let mut keys = vec![];
+        let mut cur = db.read_cursor().unwrap();
+        for node in &mut cur {
+            let node = node.unwrap();
+            keys.push(node.key);
+        }
+        for (i, key) in keys.iter().enumerate() {
+            let value = db.get(key).unwrap();
+            assert_eq!(value.unwrap(), format!("value-{}", i).as_bytes());
+        }
+        assert_eq!(db.get(b"key-0000").unwrap(), None);
+        assert_eq!(db.get(b"").unwrap(), None);
+        assert_eq!(db.get(b"zzz").unwrap(), None);
+
+        let mut db = Factory::open(test_case!("overflow.64bits")).unwrap();
+        assert_eq!(db.get(b"c").unwrap().unwrap().len(), 20000);
+
+        let mut db = Factory::open(test_case!("dupsort.64bits")).unwrap();
+        let dups = db.database(b"dups").unwrap().unwrap();
+        assert_eq!(db.get_from(&dups, b"few").unwrap().unwrap(), b"few-0");
+        assert_eq!(db.get_from(&dups, b"many").unwrap().unwrap(), b"many-0000");
+        assert_eq!(db.get_from(&dups, b"single").unwrap().unwrap(), b"only");
+        assert_eq!(db.get(b"dups").unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_integer_key_32() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word32).unwrap();
+        db.meta.main.flags = model::metadata::Flags::INTEGERKEY;
+        let mut cur = db.write_cursor().unwrap();
+        for i in 0..5000u32 {
+            cur.push(i.to_le_bytes().to_vec(), format!("{}", i).into_bytes())
+                .unwrap();
+        }
+        cur.commit().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert_eq!(db.meta.main.depth, 2);
+        for i in (0..5000u32).step_by(7) {
+            let value = db.get(&i.to_le_bytes()).unwrap().unwrap();
+            assert_eq!(value, format!("{}", i).as_bytes());
+        }
+        assert_eq!(db.get(&5000u32.to_le_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_cursor_navigation_64() {
+        setup();
+
+        let mut db = Factory::open(test_case!("btree.64bits")).unwrap();
+        let key = |i: usize| format!("key-{:04}-", i).into_bytes();
+        let mut cur = db.read_cursor().unwrap();
+        assert!(cur.seek_last().unwrap().unwrap().key.starts_with(&key(149)));
+        assert!(cur.prev().unwrap().unwrap().key.starts_with(&key(148)));
+        assert!(cur
+            .seek(&key(50))
+            .unwrap()
+            .unwrap()
+            .key
+            .starts_with(&key(50)));
+        assert!(cur.prev().unwrap().unwrap().key.starts_with(&key(49)));
+        assert!(cur.next().unwrap().unwrap().key.starts_with(&key(50)));
+        assert!(cur.next().unwrap().unwrap().key.starts_with(&key(51)));
+        assert!(cur.seek_first().unwrap().unwrap().key.starts_with(&key(0)));
+        assert!(cur.prev().unwrap().is_none());
+        assert!(cur.seek(b"zzz").unwrap().is_none());
+
+        let mut cur = db.read_cursor().unwrap();
+        let mut i = 150;
+        while let Some(node) = cur.prev().unwrap() {
+            i -= 1;
+            assert!(node.key.starts_with(&key(i)));
+        }
+        assert_eq!(i, 0);
+
+        let mut cur = db.read_cursor().unwrap();
+        cur.set_range(Bound::Included(key(10)), Bound::Excluded(key(20)));
+        let mut keys = vec![];
+        for node in &mut cur {
+            let node = node.unwrap();
+            keys.push(node.key);
+        }
+        assert_eq!(keys.len(), 10);
+        assert!(keys[0].starts_with(&key(10)));
+        let mut reversed = vec![];
+        while let Some(node) = cur.prev().unwrap() {
+            reversed.push(node.key);
+        }
+        reversed.reverse();
+        assert_eq!(reversed, keys[..9]);
+        assert!(cur.seek_last().unwrap().unwrap().key.starts_with(&key(19)));
+
+        let mut cur = db.read_cursor().unwrap();
+        cur.set_prefix(b"key-012".to_vec());
+        let mut i = 120;
+        for node in &mut cur {
+            let node = node.unwrap();
+            assert!(node.key.starts_with(&key(i)));
+            i += 1;
+        }
+        assert_eq!(i, 130);
+        assert!(cur.seek_last().unwrap().unwrap().key.starts_with(&key(129)));
+    }
+
+    #[test]
+    fn test_cursor_reverse_dupsort_64() {
+        setup();
+
+        let mut db = Factory::open(test_case!("dupsort.64bits")).unwrap();
+        for name in [b"dups".as_slice(), b"fixed".as_slice()] {
+            let mut forward = vec![];
+            let mut cur = db.read_cursor_named(name).unwrap();
+            for element in &mut cur {
+                let element = element.unwrap();
+                forward.push((element.key, element.value));
+            }
+            let mut backward = vec![];
+            while let Some(element) = cur.prev().unwrap() {
+                backward.push((element.key, element.value));
+            }
+            // The cursor stays on the last element when exhausted
+            backward.insert(0, forward.last().unwrap().clone());
+            backward.reverse();
+            assert_eq!(forward, backward);
+        }
+
+        let mut cur = db.read_cursor_named(b"dups").unwrap();
+        assert_eq!(cur.seek(b"many").unwrap().unwrap().value, b"many-0000");
+        assert_eq!(cur.prev().unwrap().unwrap().value, b"few-2");
+        assert_eq!(cur.next().unwrap().unwrap().value, b"many-0000");
+        assert_eq!(cur.next().unwrap().unwrap().value, b"many-0001");
+        assert_eq!(cur.seek_last().unwrap().unwrap().value, b"only");
+        assert_eq!(cur.prev().unwrap().unwrap().value, b"many-0499");
+    }
+
+    #[test]
+    fn test_cursor_reverse_resources() {
+        setup();
+
+        for name in [
+            "mender-store.32bits",
+            "mender-store.32bits.2",
+            "mender-store.64bits",
+            "mender-store.64bits.converted",
+            "named.64bits",
+        ] {
+            let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("resources")
+                .join(name);
+            let mut db = Factory::open(path).unwrap();
+            let mut forward = vec![];
+            let mut cur = db.read_cursor().unwrap();
+            for element in &mut cur {
+                let element = element.unwrap();
+                forward.push(element.key);
+            }
+            let mut backward = vec![];
+            let mut cur = db.read_cursor().unwrap();
+            while let Some(element) = cur.prev().unwrap() {
+                backward.push(element.key);
+            }
+            backward.reverse();
+            assert_eq!(forward, backward, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_cursor_iterator_64() {
+        setup();
+
+        let mut db = Factory::open(test_case!("btree.64bits")).unwrap();
+        let keys = db
+            .read_cursor()
+            .unwrap()
+            .map(|element| element.unwrap().key)
+            .filter(|key| key.ends_with(b"k"))
+            .take(3)
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), 3);
+        assert!(keys[0].starts_with(b"key-0000-"));
+
+        let cur = Factory::open(test_case!("dupsort.64bits"))
+            .unwrap()
+            .into_read_cursor_named(b"dups")
+            .unwrap();
+        let elements = cur.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(elements.len(), 504);
+        assert_eq!(elements[3].value, b"many-0000");
+    }
+
+    #[test]
+    fn test_freelist_64() {
+        setup();
+
+        // As listed by mdb_stat -fff
+        let mut db = Factory::open(test_case!("mender-store.64bits.json-error")).unwrap();
+        let records = db.freelist().unwrap();
+        assert_eq!(
+            records,
+            vec![(5, vec![11]), (6, vec![6, 5, 4]), (7, vec![9, 8, 7])]
+        );
+    }
+
+    #[test]
+    fn test_freelist_32() {
+        setup();
+
+        let mut db = Factory::open(test_case!("mender-store.32bits.2")).unwrap();
+        let records = db.freelist().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0, 43);
+        for (_, pages) in records {
+            assert!(pages.windows(2).all(|w| w[0] > w[1]));
+            assert!(pages
+                .iter()
+                .all(|&page| page > 1 && page <= db.meta.last_pgno));
+        }
+    }
+
+    #[test]
+    fn test_write_twice_64() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word64).unwrap();
+        let mut cur = db
+            .write_cursor_named(b"alpha", model::metadata::Flags::empty())
+            .unwrap();
+        cur.push(b"a".to_vec(), b"1".to_vec()).unwrap();
+        cur.commit().unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        for i in 0..500 {
+            cur.push(format!("key-{:04}", i).into_bytes(), vec![b'x'; 100])
+                .unwrap();
+        }
+        cur.commit().unwrap();
+
+        // A second main tree would drop the committed records
+        assert!(db.write_cursor().is_err());
+        db.close().unwrap();
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert_eq!(db.meta.txnid, 1);
+        assert!(db.meta.free.root.is_none());
+        assert_eq!(db.meta.main.entries, 501);
+        assert_eq!(db.get(b"key-0042").unwrap().unwrap(), vec![b'x'; 100]);
+        let alpha = db.database(b"alpha").unwrap().unwrap();
+        assert_eq!(db.get_from(&alpha, b"a").unwrap().unwrap(), b"1");
+    }
+
+    #[test]
+    fn test_env_flags_32() {
+        setup();
+        let db = Factory::open(test_case!("mender-store.32bits")).unwrap();
+        assert_eq!(db.env_flags(), 0x4000);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word32).unwrap();
+        db.set_env_flags(0x4000).unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        cur.push(b"key".to_vec(), b"value".to_vec()).unwrap();
+        cur.commit().unwrap();
+        assert!(db.set_env_flags(0).is_err());
+
+        let db = Factory::open(file.path().into()).unwrap();
+        assert_eq!(db.env_flags(), 0x4000);
+        assert_eq!(db.meta.free.flags.bits(), 0x4008);
+    }
+
+    #[test]
+    fn test_compact_32() {
+        setup();
+        let mut db = Factory::open(test_case!("mender-store.32bits.2")).unwrap();
+        assert!(!db.freelist().unwrap().is_empty());
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut out = Factory::create(file.path().into(), WordSize::Word32).unwrap();
+        db.compact_into(&mut out).unwrap();
+        out.close().unwrap();
+
+        let mut out = Factory::open(file.path().into()).unwrap();
+        assert!(out.freelist().unwrap().is_empty());
+        assert_eq!(out.meta.txnid, 1);
+        assert_eq!(out.env_flags(), db.env_flags());
+        let main = &out.meta.main;
+        assert_eq!(
+            out.meta.last_pgno,
+            1 + main.branch_pages + main.leaf_pages + main.overflow_pages
+        );
+        assert!(out.meta.last_pgno < db.meta.last_pgno);
+
+        let elements = |db: &mut Database| {
+            db.read_cursor()
+                .unwrap()
+                .map(|element| {
+                    let element = element.unwrap();
+                    (element.key, element.value)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(elements(&mut out), elements(&mut db));
+    }
+
+    #[test]
+    fn test_compact_named_64() {
+        setup();
+        let mut db = Factory::open(test_case!("dupsort.64bits")).unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut out = Factory::create(file.path().into(), WordSize::Word64).unwrap();
+        db.compact_into(&mut out).unwrap();
+        out.close().unwrap();
+
+        let mut out = Factory::open(file.path().into()).unwrap();
+        let databases = db.databases().unwrap();
+        assert_eq!(out.databases().unwrap().len(), databases.len());
+        for (name, tree) in databases {
+            let copy = out.database(&name).unwrap().unwrap();
+            assert_eq!(copy.flags, tree.flags);
+            assert_eq!(copy.entries, tree.entries);
+            let count = out.read_cursor_named(&name).unwrap().count();
+            assert_eq!(count, db.read_cursor_named(&name).unwrap().count());
+        }
+    }
+
+    /// A copy of `name` with `bytes` written at `offset`.
+    fn damaged(name: &str, offset: u64, bytes: &[u8]) -> tempfile::NamedTempFile {
+        use std::io::{Seek, Write};
+
+        let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("resources")
+            .join(name);
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&std::fs::read(path).unwrap()).unwrap();
+        file.seek(std::io::SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+        file.flush().unwrap();
+        file
+    }
+
+    #[test]
+    fn test_torn_meta_64() {
+        setup();
+        let db = Factory::open(test_case!("mender-store.64bits")).unwrap();
+        assert_eq!((db.meta_id(), db.meta.txnid), (1, 3));
+
+        // Meta pages wiped out, the other one is used
+        let file = damaged("mender-store.64bits", 4096, &[0; 4096]);
+        let db = Factory::open(file.path().into()).unwrap();
+        assert_eq!((db.meta_id(), db.meta.txnid), (0, 2));
+        let file = damaged("mender-store.64bits", 0, &[0; 4096]);
+        let db = Factory::open(file.path().into()).unwrap();
+        assert_eq!((db.meta_id(), db.meta.txnid), (1, 3));
+
+        // Root of the main tree past the end of the file: after 16 bytes of
+        // page header, 8 of magic and version, 16 of address and map size,
+        // the root is the last word of the second 48 bytes MDB_db record
+        let file = damaged("mender-store.64bits", 4096 + 16 + 8 + 16 + 48 + 40, &[99]);
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert_eq!((db.meta_id(), db.meta.txnid), (0, 2));
+        let check = db.check().unwrap();
+        assert_eq!(check.problems.len(), 1);
+        assert!(check.problems[0].message.contains("transaction 3"));
+        assert!(Factory::open_meta(file.path().into(), 1).is_err());
+
+        let file = damaged("mender-store.64bits", 0, &[0; 8192]);
+        assert!(Factory::open(file.path().into()).is_err());
+    }
+
+    #[test]
+    fn test_open_meta_32() {
+        setup();
+        let mut db = Factory::open(test_case!("mender-store.32bits.2")).unwrap();
+        let newest = (db.meta_id(), db.meta.txnid);
+        let mut older =
+            Factory::open_meta(test_case!("mender-store.32bits.2"), 1 - newest.0).unwrap();
+        assert!(older.meta.txnid < newest.1);
+        assert!(older.check().unwrap().is_ok());
+        assert!(db.check().unwrap().is_ok());
     }
 }
@@ -1,3 +1,4 @@
+use error_stack::Report;
 use error_stack::Result;
 use error_stack::ResultExt;
 
@@ -5,22 +6,51 @@ use super::database::Database;
 
 use super::error::Error;
 
+use super::model::lowlevel;
 use super::model::Leaf;
+use super::model::Page;
 
 impl<'a> Database<'a> {
     pub fn read(&mut self, page: usize) -> Result<Leaf, Error> {
         let reader = self.reader.as_mut().ok_or(Error::NoReader)?;
         let reader = reader.get_mut().unwrap();
-        Self::seek_page_unsafe(reader.as_mut(), page)?;
+        Self::seek_page_unsafe(reader.as_mut(), page, self.page_size)?;
         Self::read_leaf_unsafe(reader.as_mut())
             .attach_printable(format!("failed to read page {}", page))
     }
 
+    pub fn read_page(&mut self, page: usize) -> Result<Page, Error> {
+        let reader = self.reader.as_mut().ok_or(Error::NoReader)?;
+        let reader = reader.get_mut().unwrap();
+        Self::seek_page_unsafe(reader.as_mut(), page, self.page_size)?;
+        Self::read_page_unsafe(reader.as_mut())
+            .attach_printable(format!("failed to read page {}", page))
+    }
+
     pub fn read_overflow(&mut self, page: usize, size: usize) -> Result<Vec<u8>, Error> {
+        let word_size = self
+            .reader
+            .as_mut()
+            .ok_or(Error::NoReader)?
+            .get_mut()
+            .unwrap()
+            .word_size();
+        let pages = lowlevel::overflow_pages(word_size, self.page_size, size);
+        if page < 2 || (page + pages - 1) as u64 > self.meta.last_pgno {
+            return Err(
+                Report::new(Error::InvalidFileFormat).attach_printable(format!(
+                    "overflow run {}..{} out of bounds, last page is {}",
+                    page,
+                    page + pages,
+                    self.meta.last_pgno
+                )),
+            );
+        }
+
         let reader = self.reader.as_mut().ok_or(Error::NoReader)?;
         let reader = reader.get_mut().unwrap();
-        Self::seek_page_unsafe(reader.as_mut(), page)?;
-        Self::read_overflow_unsafe(reader.as_mut(), size)
+        Self::seek_page_unsafe(reader.as_mut(), page, self.page_size)?;
+        Self::read_overflow_unsafe(reader.as_mut(), size, self.page_size)
             .attach_printable(format!("failed to read overflow page {}", page))
     }
 }
@@ -54,14 +84,14 @@ mod tests {
 
         let file = std::fs::File::open(test_case!("mender-store.64bits")).unwrap();
         let reader = std::io::BufReader::new(file);
-        let mut reader = Reader64::from(reader);
+        let mut reader: Reader64<_> = Reader64::from(reader);
         let dr = &mut reader;
 
         let (meta, _) = Database::pick_meta_unsafe(dr).unwrap();
         tracing::debug!("Metadata: {:?}", meta);
 
         for i in 2..(meta.last_pgno as usize) + 1 {
-            Database::seek_page_unsafe(dr, i).unwrap();
+            Database::seek_page_unsafe(dr, i, 4096).unwrap();
             Database::read_leaf_unsafe(dr).unwrap();
         }
     }
@@ -72,14 +102,14 @@ mod tests {
 
         let file = std::fs::File::open(test_case!("mender-store.32bits")).unwrap();
         let reader = std::io::BufReader::new(file);
-        let mut reader = Reader32::from(reader);
+        let mut reader: Reader32<_> = Reader32::from(reader);
         let dr = &mut reader;
 
         let (meta, _) = Database::pick_meta_unsafe(dr).unwrap();
         tracing::debug!("Metadata: {:?}", meta);
 
         for i in 2..(meta.last_pgno as usize) + 1 {
-            Database::seek_page_unsafe(dr, i).unwrap();
+            Database::seek_page_unsafe(dr, i, 4096).unwrap();
             Database::read_leaf_unsafe(dr).unwrap();
         }
     }
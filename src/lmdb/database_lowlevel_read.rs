@@ -21,7 +21,7 @@ impl<'a> Database<'a> {
         let free_lower = reader.read_u16()?;
         let free_upper = reader.read_u16()?;
         let header = model::Header {
-            pageno: pageno,
+            pageno,
             pad,
             flags: model::header::Flags::from_bits_retain(flags),
             free_lower,
@@ -77,7 +77,7 @@ impl<'a> Database<'a> {
             ptrs[i as usize] = reader.read_u16()? as usize;
         }
         let header = model::Header2 {
-            pageno: pageno,
+            pageno,
             pad,
             flags: model::header::Flags::from_bits_retain(flags),
             free_lower,
@@ -91,8 +91,9 @@ impl<'a> Database<'a> {
     pub(super) fn seek_page_unsafe<'b>(
         reader: &'b mut (dyn DatabaseReader + 'a),
         page: usize,
+        page_size: usize,
     ) -> Result<(), Error> {
-        reader.seek(std::io::SeekFrom::Start((page * 4096) as u64))?;
+        reader.seek(std::io::SeekFrom::Start((page * page_size) as u64))?;
         Ok(())
     }
 
@@ -143,16 +144,34 @@ impl<'a> Database<'a> {
     pub(super) fn read_overflow_unsafe<'b>(
         reader: &'b mut (dyn DatabaseReader + 'a),
         size: usize,
+        page_size: usize,
     ) -> Result<Vec<u8>, Error> {
-        let header =
-            Self::read_page_header_unsafe(reader).attach_printable("failed to read header")?;
+        /* MDB_page struct, with mp_pages in place of the bounds */
+        let pageno = reader.read_word()?;
+        let _pad = reader.read_u16()?;
+        let flags = model::header::Flags::from_bits_retain(reader.read_u16()?);
+        let pages = reader.read_u32()? as usize;
+        tracing::debug!("Overflow page {}: {} pages, {:?}", pageno, pages, flags);
 
-        if header.flags & model::header::Flags::OVERFLOW != model::header::Flags::OVERFLOW {
+        if !flags.contains(model::header::Flags::OVERFLOW) {
             return Err(
                 Report::new(Error::InvalidFileFormat).attach_printable("not an overflow page")
             );
         }
 
+        let expected = lowlevel::overflow_pages(reader.word_size(), page_size, size);
+        if pages == 0 && expected == 1 {
+            // Earlier versions of this tool left the page count empty
+            tracing::warn!("overflow page {} has no page count", pageno);
+        } else if pages < expected {
+            return Err(
+                Report::new(Error::InvalidFileFormat).attach_printable(format!(
+                    "overflow page {} holds {} pages, {} needed for {} bytes",
+                    pageno, pages, expected, size
+                )),
+            );
+        }
+
         let mut data = vec![0u8; size];
         reader
             .read_exact(&mut data)
@@ -163,17 +182,88 @@ impl<'a> Database<'a> {
     pub(super) fn read_leaf_unsafe<'b>(
         reader: &'b mut (dyn DatabaseReader + 'a),
     ) -> Result<model::Leaf, Error> {
+        match Self::read_page_unsafe(reader)? {
+            model::Page::Leaf(leaf) => Ok(leaf),
+            model::Page::Branch(_) => {
+                Err(Report::new(Error::InvalidFileFormat).attach_printable("not a leaf page"))
+            }
+        }
+    }
+
+    pub(super) fn read_page_unsafe<'b>(
+        reader: &'b mut (dyn DatabaseReader + 'a),
+    ) -> Result<model::Page, Error> {
         let start = reader.pos()?;
         let header =
             Self::read_page_header2_unsafe(reader).attach_printable("failed to read header")?;
 
-        if header.flags & model::header::Flags::LEAF != model::header::Flags::LEAF {
-            return Err(Report::new(Error::InvalidFileFormat).attach_printable("not a leaf page"));
+        if header.flags.contains(model::header::Flags::BRANCH) {
+            let nodes = Self::read_branch_nodes_unsafe(reader, start, &header)?;
+            let branch = model::Branch {
+                pageno: header.pageno as usize,
+                flags: header.flags,
+                nodes,
+            };
+            tracing::debug!("{:#?}", branch);
+            Ok(model::Page::Branch(branch))
+        } else if header.flags.contains(model::header::Flags::LEAF) {
+            let nodes = if header.flags.contains(model::header::Flags::LEAF2) {
+                Self::read_leaf2_nodes_unsafe(reader, start, &header)?
+            } else {
+                Self::read_leaf_nodes_unsafe(reader, start, &header)?
+            };
+            let leaf = model::Leaf {
+                pageno: header.pageno as usize,
+                flags: header.flags,
+                nodes,
+            };
+            tracing::debug!("{:#?}", leaf);
+            Ok(model::Page::Leaf(leaf))
+        } else {
+            Err(Report::new(Error::InvalidFileFormat)
+                .attach_printable(format!("not a branch or leaf page: {:?}", header.flags)))
         }
+    }
 
+    fn read_branch_nodes_unsafe<'b>(
+        reader: &'b mut (dyn DatabaseReader + 'a),
+        start: usize,
+        header: &model::Header2,
+    ) -> Result<Vec<model::BranchNode>, Error> {
         let mut nodes = Vec::<_>::new();
-        for i in 0..header.ptrs.len() {
-            reader.seek(std::io::SeekFrom::Start((start + header.ptrs[i]) as u64))?;
+        for (i, ptr) in header.ptrs.iter().enumerate() {
+            reader.seek(std::io::SeekFrom::Start((start + ptr) as u64))?;
+
+            tracing::debug!("Reading branch node @{}", reader.pos()?);
+            // Branch nodes store the child page number in place of the data
+            // size, the high bits spilling into the flags on 64 bits.
+            let lo = reader.read_u32()?;
+            let hi = reader.read_u16()?;
+            let ksize = reader.read_u16()?;
+            let pageno = if reader.word_size() > 4 {
+                (lo as u64) | ((hi as u64) << 32)
+            } else {
+                lo as u64
+            };
+
+            let mut key = vec![0u8; ksize as usize];
+            reader
+                .read_exact(&mut key)
+                .attach_printable(format!("failed to read key #{} ({})", i, ksize))?;
+
+            nodes.push(model::BranchNode { key, pageno });
+        }
+        Ok(nodes)
+    }
+
+    fn read_leaf_nodes_unsafe<'b>(
+        reader: &'b mut (dyn DatabaseReader + 'a),
+        start: usize,
+        header: &model::Header2,
+    ) -> Result<Vec<model::Node>, Error> {
+        let mut nodes = Vec::<_>::new();
+        for (i, ptr) in header.ptrs.iter().enumerate() {
+            reader.seek(std::io::SeekFrom::Start((start + ptr) as u64))?;
 
             tracing::debug!("Reading node @{}", reader.pos()?);
             let size = reader.read_u32()?;
@@ -186,49 +276,179 @@ impl<'a> Database<'a> {
                 .read_exact(&mut key)
                 .attach_printable(format!("failed to read key #{} ({})", i, ksize))?;
 
-            let data = if flags.contains(model::NodeFlags::BIGDATA) {
-                // Read overflow page number
-                let overflow = reader
-                    .read_word()
-                    .attach_printable(format!("failed to read overflow page #{}", i))?;
-                model::NodeData::Overflow(overflow, size as usize)
-            } else {
-                // Simple read
-                let mut data = vec![0u8; size as usize];
-                reader
-                    .read_exact(&mut data)
-                    .attach_printable(format!("failed to read data #{} ({})", i, size))?;
-                model::NodeData::Data(data)
-            };
+            let data =
+                if flags.contains(model::NodeFlags::BIGDATA) {
+                    // Read overflow page number
+                    let overflow = reader
+                        .read_word()
+                        .attach_printable(format!("failed to read overflow page #{}", i))?;
+                    model::NodeData::Overflow(overflow, size as usize)
+                } else if flags.contains(model::NodeFlags::SUBDATA) {
+                    // Named database or tree of duplicates, the value is an
+                    // MDB_db record
+                    if size as usize != lowlevel::db_size(reader.word_size()) {
+                        return Err(Report::new(Error::InvalidFileFormat).attach_printable(
+                            format!("invalid sub-database record #{} ({})", i, size),
+                        ));
+                    }
+                    let db = Self::read_meta_db_unsafe(reader)
+                        .attach_printable(format!("failed to read sub-database #{}", i))?;
+                    model::NodeData::SubData(db)
+                } else if flags.contains(model::NodeFlags::DUPDATA) {
+                    // Duplicates of the key, held in a sub-page
+                    match Self::read_page_unsafe(reader)
+                        .attach_printable(format!("failed to read sub-page #{}", i))?
+                    {
+                        model::Page::Leaf(leaf) => model::NodeData::SubPage(leaf),
+                        model::Page::Branch(_) => {
+                            return Err(Report::new(Error::InvalidFileFormat)
+                                .attach_printable(format!("branch sub-page #{}", i)))
+                        }
+                    }
+                } else {
+                    // Simple read, bigger values would be in overflow pages
+                    if size as usize > lowlevel::MAX_PAGE_SIZE {
+                        return Err(Report::new(Error::InvalidFileFormat)
+                            .attach_printable(format!("invalid data size #{} ({})", i, size)));
+                    }
+                    let mut data = vec![0u8; size as usize];
+                    reader
+                        .read_exact(&mut data)
+                        .attach_printable(format!("failed to read data #{} ({})", i, size))?;
+                    model::NodeData::Data(data)
+                };
 
             nodes.push(model::Node { flags, key, data });
         }
+        Ok(nodes)
+    }
 
-        let leaf = model::Leaf {
-            pageno: header.pageno as usize,
-            flags: header.flags,
-            nodes,
+    /// Read the keys of a `LEAF2` page, packed after the header with the
+    /// fixed size recorded in `mp_pad`.
+    fn read_leaf2_nodes_unsafe<'b>(
+        reader: &'b mut (dyn DatabaseReader + 'a),
+        start: usize,
+        header: &model::Header2,
+    ) -> Result<Vec<model::Node>, Error> {
+        let ksize = header.pad as usize;
+        let offset = start + lowlevel::page_header_size(reader.word_size());
+        reader.seek(std::io::SeekFrom::Start(offset as u64))?;
+
+        let mut nodes = Vec::<_>::new();
+        for i in 0..header.ptrs.len() {
+            let mut key = vec![0u8; ksize];
+            reader
+                .read_exact(&mut key)
+                .attach_printable(format!("failed to read key #{} ({})", i, ksize))?;
+            nodes.push(model::Node {
+                flags: model::NodeFlags::empty(),
+                key,
+                data: model::NodeData::Data(vec![]),
+            });
+        }
+        Ok(nodes)
+    }
+
+    /// Read both meta pages, with the outcome of their validation. When the
+    /// first one cannot be read, the page size locating the second one is
+    /// guessed.
+    pub(super) fn read_metas_unsafe<'b>(
+        reader: &'b mut (dyn DatabaseReader + 'a),
+    ) -> Result<[Result<model::Metadata, Error>; 2], Error> {
+        Self::seek_page_unsafe(reader, 0, 0)?;
+        let meta1 = Self::read_meta_unsafe(reader).and_then(|meta| {
+            Self::validate_meta_unsafe(reader, meta).attach_printable("meta page 0")
+        });
+
+        // The second meta page sits one page further, if the first one tells
+        // how far that is
+        let page_sizes = match &meta1 {
+            Ok(meta) => vec![meta.free.pad as usize],
+            Err(_) => lowlevel::page_sizes().collect(),
         };
-        tracing::debug!("{:#?}", leaf);
+        let mut meta2 =
+            Err(Report::new(Error::InvalidFileFormat).attach_printable("meta page 1: not found"));
+        for page_size in page_sizes {
+            Self::seek_page_unsafe(reader, 1, page_size)?;
+            let meta = match Self::read_meta_unsafe(reader) {
+                Ok(meta) if meta.free.pad as usize == page_size => meta,
+                _ => continue,
+            };
+            meta2 = Self::validate_meta_unsafe(reader, meta).attach_printable("meta page 1");
+            break;
+        }
+        Ok([meta1, meta2])
+    }
 
-        Ok(leaf)
+    /// A meta page is usable when its page size is valid and its trees are
+    /// rooted on branch or leaf pages within `last_pgno`. A torn write of
+    /// the meta page, or of the pages it points to, fails this check.
+    fn validate_meta_unsafe<'b>(
+        reader: &'b mut (dyn DatabaseReader + 'a),
+        meta: model::Metadata,
+    ) -> Result<model::Metadata, Error> {
+        let page_size = meta.free.pad as usize;
+        if !lowlevel::is_valid_page_size(page_size) {
+            return Err(Report::new(Error::InvalidPageSize)
+                .attach_printable(format!("Invalid page size: {}", page_size)));
+        }
+        for root in [meta.free.root, meta.main.root].into_iter().flatten() {
+            if root < 2 || root > meta.last_pgno {
+                return Err(
+                    Report::new(Error::InvalidFileFormat).attach_printable(format!(
+                        "root page {} out of bounds, last page is {}",
+                        root, meta.last_pgno
+                    )),
+                );
+            }
+            Self::seek_page_unsafe(reader, root as usize, page_size)?;
+            let header = Self::read_page_header_unsafe(reader)
+                .attach_printable(format!("failed to read root page {}", root))?;
+            let flags = model::header::Flags::BRANCH | model::header::Flags::LEAF;
+            if header.pageno != root || !header.flags.intersects(flags) {
+                return Err(
+                    Report::new(Error::InvalidPageHeader).attach_printable(format!(
+                        "root page {} has page number {} and flags {:?}",
+                        root, header.pageno, header.flags
+                    )),
+                );
+            }
+        }
+        Ok(meta)
     }
 
+    /// Pick the usable meta page with the highest `txnid`, as LMDB does, so
+    /// that a torn write of the newest one falls back to the previous
+    /// snapshot.
     pub(super) fn pick_meta_unsafe<'b>(
         reader: &'b mut (dyn DatabaseReader + 'a),
     ) -> Result<(model::Metadata, usize), Error> {
-        // Read the first metadata
-        Self::seek_page_unsafe(reader, 0)?;
-        let meta1 = Self::read_meta_unsafe(reader)?;
-
-        // And the second metadata
-        Self::seek_page_unsafe(reader, 1)?;
-        let meta2 = Self::read_meta_unsafe(reader)?;
+        match Self::read_metas_unsafe(reader)? {
+            [Ok(meta1), Ok(meta2)] if meta1.txnid < meta2.txnid => Ok((meta2, 1)),
+            [Ok(meta1), Ok(_)] => Ok((meta1, 0)),
+            [Ok(meta1), Err(e)] => {
+                tracing::warn!("Ignoring meta page 1: {:?}", e);
+                Ok((meta1, 0))
+            }
+            [Err(e), Ok(meta2)] => {
+                tracing::warn!("Ignoring meta page 0: {:?}", e);
+                Ok((meta2, 1))
+            }
+            [Err(e), Err(_)] => Err(e.attach_printable("no usable meta page")),
+        }
+    }
 
-        if meta1.txnid < meta2.txnid {
-            Ok((meta2, 1))
-        } else {
-            Ok((meta1, 0))
+    /// Use meta page `meta_id` whatever its `txnid`, provided it is usable.
+    pub(super) fn select_meta_unsafe<'b>(
+        reader: &'b mut (dyn DatabaseReader + 'a),
+        meta_id: usize,
+    ) -> Result<model::Metadata, Error> {
+        let [meta1, meta2] = Self::read_metas_unsafe(reader)?;
+        match meta_id {
+            0 => meta1,
+            1 => meta2,
+            _ => Err(Report::new(Error::InvalidFileFormat)
+                .attach_printable(format!("no meta page {}", meta_id))),
         }
     }
 }
@@ -259,14 +479,14 @@ mod tests {
         let _guard = init_tracing();
         let file = std::fs::File::open(test_case!("mender-store.64bits")).unwrap();
         let reader = std::io::BufReader::new(file);
-        let mut reader = Reader64::from(reader);
+        let mut reader: Reader64<_> = Reader64::from(reader);
         let dr = &mut reader;
 
         let (meta, _) = Database::pick_meta_unsafe(dr).unwrap();
         tracing::debug!("Metadata: {:?}", meta);
 
         for i in 2..(meta.last_pgno as usize) + 1 {
-            Database::seek_page_unsafe(dr, i).unwrap();
+            Database::seek_page_unsafe(dr, i, 4096).unwrap();
             Database::read_leaf_unsafe(dr).unwrap();
         }
     }
@@ -276,15 +496,36 @@ mod tests {
         let _guard = init_tracing();
         let file = std::fs::File::open(test_case!("mender-store.32bits")).unwrap();
         let reader = std::io::BufReader::new(file);
-        let mut reader = Reader32::from(reader);
+        let mut reader: Reader32<_> = Reader32::from(reader);
         let dr = &mut reader;
 
         let (meta, _) = Database::pick_meta_unsafe(dr).unwrap();
         tracing::debug!("Metadata: {:?}", meta);
 
         for i in 2..(meta.last_pgno as usize) + 1 {
-            Database::seek_page_unsafe(dr, i).unwrap();
+            Database::seek_page_unsafe(dr, i, 4096).unwrap();
             Database::read_leaf_unsafe(dr).unwrap();
         }
     }
+
+    #[test]
+    fn test_read_branch_64() {
+        let _guard = init_tracing();
+        let file = std::fs::File::open(test_case!("btree.64bits")).unwrap();
+        let reader = std::io::BufReader::new(file);
+        let mut reader: Reader64<_> = Reader64::from(reader);
+        let dr = &mut reader;
+
+        let (meta, _) = Database::pick_meta_unsafe(dr).unwrap();
+        Database::seek_page_unsafe(dr, meta.main.root.unwrap() as usize, 4096).unwrap();
+        let root = match Database::read_page_unsafe(dr).unwrap() {
+            model::Page::Branch(branch) => branch,
+            page => panic!("root is not a branch: {:?}", page),
+        };
+        assert!(root.nodes.len() > 1);
+        assert!(root.nodes[0].key.is_empty());
+        for node in root.nodes.iter() {
+            assert!(node.pageno > 1 && node.pageno <= meta.last_pgno);
+        }
+    }
 }
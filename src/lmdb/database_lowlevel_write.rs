@@ -1,10 +1,7 @@
 use error_stack::Report;
 use error_stack::Result;
-use error_stack::ResultExt;
 
 use super::database::Database;
-use super::database::DatabaseReader;
-
 use super::database::DatabaseWriter;
 use super::error::Error;
 
@@ -13,14 +10,26 @@ use super::model::lowlevel;
 use super::model::metadata;
 
 impl<'a> Database<'a> {
-    pub(super) fn init_meta_unsafe() -> Result<(model::Metadata, model::Metadata), Error> {
+    pub(super) fn init_meta_unsafe(
+        page_size: usize,
+    ) -> Result<(model::Metadata, model::Metadata), Error> {
+        if !lowlevel::is_valid_page_size(page_size) {
+            return Err(
+                Report::new(Error::InvalidPageSize).attach_printable(format!(
+                    "page size must be a power of two between {} and {}, got {}",
+                    lowlevel::MIN_PAGE_SIZE,
+                    lowlevel::MAX_PAGE_SIZE,
+                    page_size
+                )),
+            );
+        }
         let meta = model::Metadata {
             magic: lowlevel::MAGIC,
             version: lowlevel::VERSION,
             address: 0,
             mapsize: 1048576, // Do know what this is
             main: model::Database {
-                pad: 4096,
+                pad: 0,
                 flags: model::metadata::Flags::empty(),
                 depth: 0,
                 branch_pages: 0,
@@ -30,7 +39,7 @@ impl<'a> Database<'a> {
                 root: None,
             },
             free: model::Database {
-                pad: 4096,
+                pad: page_size as u32,
                 flags: model::metadata::Flags::INTEGERKEY,
                 depth: 0,
                 branch_pages: 0,
@@ -75,84 +84,102 @@ impl<'a> Database<'a> {
     pub(super) fn write_overflow_unsafe<'b>(
         writer: &'b mut (dyn DatabaseWriter + 'a),
         overflow: model::Overflow,
+        page_size: usize,
     ) -> Result<(), Error> {
-        writer.seek(std::io::SeekFrom::Start((overflow.pageno as u64) * 4096))?;
-        let head = writer.pos()?;
-        tracing::debug!("overflow pos: {}", head);
+        let head = overflow.pageno as usize * page_size;
+        writer.seek(std::io::SeekFrom::Start(head as u64))?;
+        let pages = lowlevel::overflow_pages(writer.word_size(), page_size, overflow.data.len());
+        tracing::debug!("overflow pos: {}, pages: {}", head, pages);
 
-        writer.write_word(overflow.pageno as u64)?;
+        writer.write_word(overflow.pageno)?;
         writer.write_u16(0)?;
         writer.write_u16(model::header::Flags::OVERFLOW.bits())?;
-        writer.write_u16(0)?;
-        writer.write_u16(0)?;
+        writer.write_u32(pages as u32)?;
 
         writer.write_exact(&overflow.data)?;
 
         let tail = writer.pos()?;
-        if tail > head {
-            let fill = 4096 - (tail - head);
-            writer.write_fill(fill)?;
-        }
+        writer.write_fill(pages * page_size - (tail - head))?;
         Ok(())
     }
 
-    pub(super) fn write_leaf_unsafe<'b>(
+    /// Write the header and pointer array of a branch or leaf page holding
+    /// nodes of the given sizes, stacked from the end of the page. Returns
+    /// the page start and the offset of each node.
+    fn write_node_page_unsafe<'b>(
         writer: &'b mut (dyn DatabaseWriter + 'a),
-        leaf: model::Leaf,
-    ) -> Result<(), Error> {
-        writer.seek(std::io::SeekFrom::Start((leaf.pageno as u64) * 4096))?;
-
-        let head = writer.pos()?;
-        tracing::debug!("leaf pos: {}", head);
-
-        let nkeys = leaf.nodes.len();
-
-        let mut nodes = leaf.nodes.clone();
-        nodes.sort_by(|a, b| a.key.cmp(&b.key));
-        nodes.reverse();
+        pageno: usize,
+        flags: model::header::Flags,
+        sizes: &[usize],
+        page_size: usize,
+    ) -> Result<(usize, Vec<usize>), Error> {
+        let head = pageno * page_size;
+        writer.seek(std::io::SeekFrom::Start(head as u64))?;
+        tracing::debug!("page {} pos: {}", pageno, head);
 
+        let overflows = || {
+            Report::new(Error::WriteError)
+                .attach_printable(format!("page {} overflows: {:?}", pageno, sizes))
+        };
         let mut ptrs = Vec::<usize>::new();
-        let mut offset = 4096 - 1;
-        for i in 0..nkeys {
-            let node = &nodes[i];
-            offset -= 4 + 2 + 2 + node.key.len();
-            match node.data {
-                model::NodeData::Data(ref data) => offset -= data.len(),
-                model::NodeData::Overflow(_, _) => offset -= writer.word_size(),
-            };
+        let mut offset = page_size;
+        for size in sizes {
+            offset = offset
+                .checked_sub(lowlevel::even(*size))
+                .ok_or_else(overflows)?;
             ptrs.push(offset);
         }
-        ptrs.reverse();
-        tracing::debug!("leaf nkeys: {}, ptrs: {:?}", nkeys, ptrs);
-
-        writer.write_word(leaf.pageno as u64)?;
-        writer.write_u16(0)?;
-        writer.write_u16(leaf.flags.bits())?;
-        let pos = writer.pos()?;
 
-        let free_lower = ((nkeys << 1) + (pos - head + 4)) as u16;
-        let free_upper = offset as u16;
+        let free_lower = lowlevel::page_header_size(writer.word_size()) + (ptrs.len() << 1);
+        let free_upper = offset;
+        if free_lower > free_upper {
+            return Err(overflows());
+        }
         tracing::debug!(
-            "leaf free_lower: {}, free_upper: {}",
+            "page nkeys: {}, free_lower: {}, free_upper: {}",
+            ptrs.len(),
             free_lower,
             free_upper
         );
 
-        writer.write_u16(free_lower)?;
-        writer.write_u16(free_upper)?;
-        for ptr in ptrs.clone() {
-            writer.write_u16(ptr as u16)?;
+        Self::write_page_header_unsafe(
+            writer,
+            model::Header {
+                pageno: pageno as u64,
+                pad: 0,
+                flags,
+                free_lower: free_lower as u16,
+                free_upper: free_upper as u16,
+            },
+        )?;
+        for ptr in ptrs.iter() {
+            writer.write_u16(*ptr as u16)?;
         }
 
         let tail = writer.pos()?;
-        if tail > head {
-            let fill = 4096 - (tail - head);
-            writer.write_fill(fill)?;
-        }
+        writer.write_fill(page_size - (tail - head))?;
+        Ok((head, ptrs))
+    }
 
-        for i in 0..nkeys {
-            let node = &nodes[i];
-            let start = head + ptrs[nkeys - 1 - i];
+    pub(super) fn write_leaf_unsafe<'b>(
+        writer: &'b mut (dyn DatabaseWriter + 'a),
+        leaf: model::Leaf,
+        page_size: usize,
+    ) -> Result<(), Error> {
+        if leaf.flags.contains(model::header::Flags::LEAF2) {
+            return Self::write_leaf2_unsafe(writer, leaf, page_size);
+        }
+        let word_size = writer.word_size();
+        let sizes = leaf
+            .nodes
+            .iter()
+            .map(|node| node.size(word_size))
+            .collect::<Vec<_>>();
+        let (head, ptrs) =
+            Self::write_node_page_unsafe(writer, leaf.pageno, leaf.flags, &sizes, page_size)?;
+
+        for (node, ptr) in leaf.nodes.iter().zip(ptrs) {
+            let start = head + ptr;
             writer.seek(std::io::SeekFrom::Start(start as u64))?;
 
             match node.data {
@@ -168,11 +195,7 @@ impl<'a> Database<'a> {
                     writer.write_u16(node.flags.bits())?;
                     writer.write_u16(node.key.len() as u16)?;
                     writer.write_exact(&node.key)?;
-                    writer.write_exact(&data)?;
-                    assert!(
-                        writer.pos()? == 0
-                            || writer.pos()? - start == 4 + 2 + 2 + data.len() + node.key.len()
-                    );
+                    writer.write_exact(data)?;
                 }
                 model::NodeData::Overflow(overflow, size) => {
                     tracing::debug!(
@@ -187,14 +210,168 @@ impl<'a> Database<'a> {
                     writer.write_u16(node.key.len() as u16)?;
                     writer.write_exact(&node.key)?;
                     writer.write_word(overflow)?;
-                    assert!(
-                        writer.pos()? == 0
-                            || writer.pos()? - start
-                                == 4 + 2 + 2 + writer.word_size() + node.key.len()
+                }
+                model::NodeData::SubData(ref db) => {
+                    tracing::debug!(
+                        "Writing sub-database node @{}: key:{}B, root:{:?}, flags:{:?}",
+                        start,
+                        node.key.len(),
+                        db.root,
+                        node.flags
                     );
+                    writer.write_u32(lowlevel::db_size(word_size) as u32)?;
+                    writer.write_u16(node.flags.bits())?;
+                    writer.write_u16(node.key.len() as u16)?;
+                    writer.write_exact(&node.key)?;
+                    Self::write_db_unsafe(writer, db.clone())?;
+                }
+                model::NodeData::SubPage(ref sub) => {
+                    tracing::debug!(
+                        "Writing sub-page node @{}: key:{}B, dups:{}, flags:{:?}",
+                        start,
+                        node.key.len(),
+                        sub.nodes.len(),
+                        node.flags
+                    );
+                    writer.write_u32(sub.sub_page_size(word_size) as u32)?;
+                    writer.write_u16(node.flags.bits())?;
+                    writer.write_u16(node.key.len() as u16)?;
+                    writer.write_exact(&node.key)?;
+                    Self::write_sub_page_unsafe(writer, leaf.pageno, sub)?;
+                }
+            }
+            assert!(writer.pos()? - start == node.size(word_size));
+        }
+
+        Ok(())
+    }
+
+    /// Write a `LEAF2` page, whose keys all have the same size and are packed
+    /// after the header without any node header nor pointer.
+    fn write_leaf2_unsafe<'b>(
+        writer: &'b mut (dyn DatabaseWriter + 'a),
+        leaf: model::Leaf,
+        page_size: usize,
+    ) -> Result<(), Error> {
+        let head = leaf.pageno * page_size;
+        writer.seek(std::io::SeekFrom::Start(head as u64))?;
+        let ksize = leaf.nodes.first().map_or(0, |node| node.key.len());
+        let nkeys = leaf.nodes.len();
+
+        let free_lower = lowlevel::page_header_size(writer.word_size()) + (nkeys << 1);
+        let free_upper = page_size as isize - (nkeys * ksize) as isize + (nkeys << 1) as isize;
+        if free_upper < free_lower as isize {
+            return Err(Report::new(Error::WriteError)
+                .attach_printable(format!("page {} overflows: {} keys", leaf.pageno, nkeys)));
+        }
+        Self::write_page_header_unsafe(
+            writer,
+            model::Header {
+                pageno: leaf.pageno as u64,
+                pad: ksize as u16,
+                flags: leaf.flags,
+                free_lower: free_lower as u16,
+                free_upper: free_upper as u16,
+            },
+        )?;
+        for node in leaf.nodes.iter() {
+            writer.write_exact(&node.key)?;
+        }
+
+        let tail = writer.pos()?;
+        writer.write_fill(page_size - (tail - head))?;
+        Ok(())
+    }
+
+    /// Write a leaf of duplicates as a sub-page at the current position,
+    /// without free space. `pageno` is the page holding the node.
+    fn write_sub_page_unsafe<'b>(
+        writer: &'b mut (dyn DatabaseWriter + 'a),
+        pageno: usize,
+        leaf: &model::Leaf,
+    ) -> Result<(), Error> {
+        let word_size = writer.word_size();
+        let head = writer.pos()?;
+        let nkeys = leaf.nodes.len();
+        let free_lower = lowlevel::page_header_size(word_size) + (nkeys << 1);
+        let leaf2 = leaf.flags.contains(model::header::Flags::LEAF2);
+        Self::write_page_header_unsafe(
+            writer,
+            model::Header {
+                pageno: pageno as u64,
+                pad: if leaf2 {
+                    leaf.nodes.first().map_or(0, |node| node.key.len() as u16)
+                } else {
+                    0
+                },
+                flags: leaf.flags,
+                free_lower: free_lower as u16,
+                free_upper: free_lower as u16,
+            },
+        )?;
+
+        if leaf2 {
+            for node in leaf.nodes.iter() {
+                writer.write_exact(&node.key)?;
+            }
+        } else {
+            // Nodes follow the pointers, in order
+            let mut offset = free_lower;
+            for node in leaf.nodes.iter() {
+                writer.write_u16(offset as u16)?;
+                offset += lowlevel::even(node.size(word_size));
+            }
+            for node in leaf.nodes.iter() {
+                let data = match node.data {
+                    model::NodeData::Data(ref data) => data,
+                    _ => {
+                        return Err(Report::new(Error::WriteError)
+                            .attach_printable("sub-page nodes only hold inline data"))
+                    }
+                };
+                writer.write_u32(data.len() as u32)?;
+                writer.write_u16(node.flags.bits())?;
+                writer.write_u16(node.key.len() as u16)?;
+                writer.write_exact(&node.key)?;
+                writer.write_exact(data)?;
+                if node.size(word_size) % 2 == 1 {
+                    writer.write_fill(1)?;
                 }
             }
         }
+        assert!(writer.pos()? - head == leaf.sub_page_size(word_size));
+        Ok(())
+    }
+
+    pub(super) fn write_branch_unsafe<'b>(
+        writer: &'b mut (dyn DatabaseWriter + 'a),
+        branch: model::Branch,
+        page_size: usize,
+    ) -> Result<(), Error> {
+        let sizes = branch
+            .nodes
+            .iter()
+            .map(|node| node.size())
+            .collect::<Vec<_>>();
+        let (head, ptrs) =
+            Self::write_node_page_unsafe(writer, branch.pageno, branch.flags, &sizes, page_size)?;
+
+        for (node, ptr) in branch.nodes.iter().zip(ptrs) {
+            let start = head + ptr;
+            writer.seek(std::io::SeekFrom::Start(start as u64))?;
+
+            tracing::debug!(
+                "Writing branch node @{}: key:{}B, child:{}",
+                start,
+                node.key.len(),
+                node.pageno
+            );
+            writer.write_u32(node.pageno as u32)?;
+            writer.write_u16((node.pageno >> 32) as u16)?;
+            writer.write_u16(node.key.len() as u16)?;
+            writer.write_exact(&node.key)?;
+            assert!(writer.pos()? - start == node.size());
+        }
 
         Ok(())
     }
@@ -204,12 +381,13 @@ impl<'a> Database<'a> {
         meta: model::Metadata,
         pageno: usize,
     ) -> Result<(), Error> {
-        let head = pageno * 4096;
+        let page_size = meta.free.pad as usize;
+        let head = pageno * page_size;
         writer.seek(std::io::SeekFrom::Start(head as u64))?;
         Self::write_page_header_unsafe(
             writer,
             model::Header {
-                pageno: 0,
+                pageno: pageno as u64,
                 pad: 0,
                 flags: model::header::Flags::META,
                 free_lower: 0,
@@ -229,10 +407,7 @@ impl<'a> Database<'a> {
         writer.write_word(meta.txnid)?;
 
         let tail = writer.pos()?;
-        if tail > head {
-            let fill = 4096 - (tail - head);
-            writer.write_fill(fill)?;
-        }
+        writer.write_fill(page_size - (tail - head))?;
         Ok(())
     }
 }
@@ -265,10 +440,10 @@ mod tests {
         let _guard = init_tracing();
         let file = tempfile::NamedTempFile::new().unwrap();
         let writer = std::io::BufWriter::new(file.reopen().unwrap());
-        let mut writer = Writer64::from(writer);
+        let mut writer: Writer64<_> = Writer64::from(writer);
         let dw = &mut writer;
 
-        let (meta1, meta2) = Database::init_meta_unsafe().unwrap();
+        let (meta1, meta2) = Database::init_meta_unsafe(4096).unwrap();
         Database::write_meta_unsafe(dw, meta1, 0).unwrap();
         Database::write_meta_unsafe(dw, meta2, 1).unwrap();
         writer.flush().unwrap();
@@ -276,7 +451,7 @@ mod tests {
         // Try to read back
         let file = file.reopen().unwrap();
         let reader = std::io::BufReader::new(file);
-        let mut reader = Reader64::from(reader);
+        let mut reader: Reader64<_> = Reader64::from(reader);
         let dr = &mut reader;
 
         let meta = Database::pick_meta_unsafe(dr).unwrap();
@@ -288,10 +463,10 @@ mod tests {
         let _guard = init_tracing();
         let file = tempfile::NamedTempFile::new().unwrap();
         let writer = std::io::BufWriter::new(file.reopen().unwrap());
-        let mut writer = Writer64::from(writer);
+        let mut writer: Writer64<_> = Writer64::from(writer);
         let dw = &mut writer;
 
-        let (meta1, meta2) = Database::init_meta_unsafe().unwrap();
+        let (meta1, meta2) = Database::init_meta_unsafe(4096).unwrap();
         Database::write_meta_unsafe(dw, meta1, 0).unwrap();
         Database::write_meta_unsafe(dw, meta2, 1).unwrap();
 
@@ -310,6 +485,7 @@ mod tests {
                 flags: model::header::Flags::LEAF,
                 nodes,
             },
+            4096,
         )
         .unwrap();
         writer.flush().unwrap();
@@ -317,23 +493,43 @@ mod tests {
         // Try to read back
         let file = file.reopen().unwrap();
         let reader = std::io::BufReader::new(file);
-        let mut reader = Reader64::from(reader);
+        let mut reader: Reader64<_> = Reader64::from(reader);
         let dr = &mut reader;
 
-        Database::seek_page_unsafe(dr, 2).unwrap();
+        Database::seek_page_unsafe(dr, 2, 4096).unwrap();
         let leaf = Database::read_leaf_unsafe(dr).unwrap();
         tracing::debug!("{:#?}", leaf);
     }
 
+    #[test]
+    fn test_write_leaf_overflows_64() {
+        let _guard = init_tracing();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let writer = std::io::BufWriter::new(file.reopen().unwrap());
+        let mut writer: Writer64<_> = Writer64::from(writer);
+
+        // A node bigger than the page itself
+        let leaf = model::Leaf {
+            pageno: 2,
+            flags: model::header::Flags::LEAF,
+            nodes: vec![model::Node {
+                flags: model::NodeFlags::empty(),
+                key: vec![1; 500],
+                data: model::NodeData::Data(vec![2; 100]),
+            }],
+        };
+        assert!(Database::write_leaf_unsafe(&mut writer, leaf, 512).is_err());
+    }
+
     #[test]
     fn test_write_meta_32() {
         let _guard = init_tracing();
         let file = tempfile::NamedTempFile::new().unwrap();
         let writer = std::io::BufWriter::new(file.reopen().unwrap());
-        let mut writer = Writer32::from(writer);
+        let mut writer: Writer32<_> = Writer32::from(writer);
         let dw = &mut writer;
 
-        let (meta1, meta2) = Database::init_meta_unsafe().unwrap();
+        let (meta1, meta2) = Database::init_meta_unsafe(4096).unwrap();
         Database::write_meta_unsafe(dw, meta1, 0).unwrap();
         Database::write_meta_unsafe(dw, meta2, 1).unwrap();
 
@@ -342,10 +538,48 @@ mod tests {
         // Try to read back
         let file = file.reopen().unwrap();
         let reader = std::io::BufReader::new(file);
-        let mut reader = Reader32::from(reader);
+        let mut reader: Reader32<_> = Reader32::from(reader);
         let dr = &mut reader;
 
         let meta = Database::pick_meta_unsafe(dr).unwrap();
         tracing::debug!("Metadata: {:?}", meta);
     }
+
+    #[test]
+    fn test_write_overflow_64() {
+        let _guard = init_tracing();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let writer = std::io::BufWriter::new(file.reopen().unwrap());
+        let mut writer: Writer64<_> = Writer64::from(writer);
+        let dw = &mut writer;
+
+        let data = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        Database::write_overflow_unsafe(
+            dw,
+            model::Overflow {
+                pageno: 2,
+                data: data.clone(),
+            },
+            4096,
+        )
+        .unwrap();
+        writer.flush().unwrap();
+        assert_eq!(file.as_file().metadata().unwrap().len(), 5 * 4096);
+
+        // Try to read back
+        let file = file.reopen().unwrap();
+        let reader = std::io::BufReader::new(file);
+        let mut reader: Reader64<_> = Reader64::from(reader);
+        let dr = &mut reader;
+
+        Database::seek_page_unsafe(dr, 2, 4096).unwrap();
+        assert_eq!(
+            Database::read_overflow_unsafe(dr, data.len(), 4096).unwrap(),
+            data
+        );
+
+        // A value larger than the run is rejected
+        Database::seek_page_unsafe(dr, 2, 4096).unwrap();
+        assert!(Database::read_overflow_unsafe(dr, 3 * 4096, 4096).is_err());
+    }
 }
@@ -0,0 +1,729 @@
+use std::collections::VecDeque;
+
+use error_stack::Report;
+use error_stack::Result;
+
+use super::compare;
+use super::cursor::WriteCursor;
+use super::cursor::CURSOR_STACK;
+use super::database::Database;
+use super::error::Error;
+use super::model;
+use super::model::lowlevel;
+
+/// Pages handed out and released by one update. Pages are never written
+/// in place: every page on the path to a change is copied to a free page,
+/// and the old ones are recorded in the free list once the new meta page is
+/// written, so the previous snapshot stays valid until then.
+struct Txn {
+    /// Free pages ready to be reused, in descending order like LMDB's IDLs
+    free: Vec<u64>,
+    /// Free list records old enough to be reused, oldest first
+    reusable: VecDeque<(u64, Vec<u64>)>,
+    /// Free list records not reused yet
+    kept: Vec<(u64, Vec<u64>)>,
+    /// Pages released by this update
+    freed: Vec<u64>,
+}
+
+impl<'a> Database<'a> {
+    /// Set `key` to `value` in the main tree, committing a new meta page.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.update(|db, txn| {
+            let mut tree = db.meta.main.clone();
+            db.update_tree(txn, &mut tree, key, Some(value))?;
+            db.meta.main = tree;
+            Ok(())
+        })
+    }
+
+    /// Remove `key` from the main tree, committing a new meta page. Returns
+    /// false, without any change, when the key is not found.
+    pub fn del(&mut self, key: &[u8]) -> Result<bool, Error> {
+        let tree = self.meta.main.clone();
+        if self.lookup(&tree, key)?.is_none() {
+            return Ok(false);
+        }
+        self.update(|db, txn| {
+            let mut tree = db.meta.main.clone();
+            db.update_tree(txn, &mut tree, key, None)?;
+            db.meta.main = tree;
+            Ok(())
+        })?;
+        Ok(true)
+    }
+
+    /// Same as `put`, in the named database `name`.
+    pub fn put_named(&mut self, name: &[u8], key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let tree = self.named_tree(name)?;
+        self.update(|db, txn| db.update_named(txn, name, tree, key, Some(value)))
+    }
+
+    /// Same as `del`, in the named database `name`.
+    pub fn del_named(&mut self, name: &[u8], key: &[u8]) -> Result<bool, Error> {
+        let tree = self.named_tree(name)?;
+        if self.lookup(&tree, key)?.is_none() {
+            return Ok(false);
+        }
+        self.update(|db, txn| db.update_named(txn, name, tree, key, None))?;
+        Ok(true)
+    }
+
+    fn named_tree(&mut self, name: &[u8]) -> Result<model::Database, Error> {
+        self.database(name)?.ok_or_else(|| {
+            Report::new(Error::DatabaseNotFound)
+                .attach_printable(String::from_utf8_lossy(name).to_string())
+        })
+    }
+
+    /// Update a named database, then its record in the main tree.
+    fn update_named(
+        &mut self,
+        txn: &mut Txn,
+        name: &[u8],
+        mut tree: model::Database,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        self.update_tree(txn, &mut tree, key, value)?;
+        let mut main = self.meta.main.clone();
+        let node = model::Node {
+            flags: model::NodeFlags::SUBDATA,
+            key: name.to_vec(),
+            data: model::NodeData::SubData(tree),
+        };
+        self.update_path(txn, &mut main, name, Some(node))?;
+        self.meta.main = main;
+        Ok(())
+    }
+
+    /// Run one update and commit it: write the free list and a new meta
+    /// page with the next `txnid`. Nothing is committed when `f` fails.
+    fn update<F>(&mut self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Self, &mut Txn) -> Result<(), Error>,
+    {
+        if self.writer.is_none() {
+            return Err(Report::new(Error::NoWriter));
+        }
+        let saved = self.meta.clone();
+
+        // Pages released by the last transaction may still be used by the
+        // previous meta page, as in `mdb_page_alloc`
+        let (reusable, kept): (Vec<_>, Vec<_>) = self
+            .freelist()?
+            .into_iter()
+            .partition(|(txnid, _)| *txnid < saved.txnid);
+        let mut txn = Txn {
+            free: Vec::new(),
+            reusable: reusable.into(),
+            kept,
+            freed: Vec::new(),
+        };
+
+        let result = f(self, &mut txn).and_then(|_| self.commit_update(txn));
+        if result.is_err() {
+            self.meta = saved;
+        }
+        result
+    }
+
+    fn commit_update(&mut self, mut txn: Txn) -> Result<(), Error> {
+        let txnid = self.meta.txnid + 1;
+        let free = self.meta.free.clone();
+
+        // The free list is written anew after the other pages, the pages of
+        // the old one being released with the others
+        txn.freed.extend(self.tree_pages(&free)?);
+        txn.freed.append(&mut txn.free);
+        let mut records = std::mem::take(&mut txn.kept);
+        records.extend(txn.reusable.drain(..));
+        if !txn.freed.is_empty() {
+            txn.freed.sort_unstable_by(|a, b| b.cmp(a));
+            txn.freed.dedup();
+            records.push((txnid, std::mem::take(&mut txn.freed)));
+        }
+        let tree = self.write_freelist(records)?;
+
+        let mut meta = self.meta.clone();
+        meta.txnid = txnid;
+        meta.free = tree;
+        meta.mapsize = std::cmp::max(meta.mapsize, (meta.last_pgno + 1) * self.page_size as u64);
+        tracing::debug!("Update: {:#?}", meta);
+
+        // The pages must reach the disk before the meta page pointing at
+        // them, which is only taken for committed once synced too
+        let meta_id = (self.meta_id + 1) % 2;
+        let mut writer = self.writer.as_ref().unwrap().lock().unwrap();
+        writer.sync()?;
+        Database::write_meta_unsafe(writer.as_mut(), meta.clone(), meta_id)?;
+        writer.sync()?;
+        drop(writer);
+
+        self.meta = meta;
+        self.meta_id = meta_id;
+        Ok(())
+    }
+
+    /// Write a new free list (`FREE_DBI`) tree after `last_pgno` from
+    /// `(txnid, pages)` records, pages in descending order. The flags and
+    /// page size kept in the current free database are carried over.
+    pub(crate) fn write_freelist(
+        &mut self,
+        mut records: Vec<(u64, Vec<u64>)>,
+    ) -> Result<model::Database, Error> {
+        let free = self.meta.free.clone();
+        let word_size = self.word_size();
+        let endianness = self.endianness();
+        records.sort_by_key(|(txnid, _)| *txnid);
+
+        let mut cur = WriteCursor::init_tree(self, None, free.flags)?;
+        for (txnid, pages) in records {
+            let mut words = vec![pages.len() as u64];
+            words.extend(pages);
+            cur.push(
+                endianness.write_words(&[txnid], word_size),
+                endianness.write_words(&words, word_size),
+            )?;
+        }
+        let mut tree = cur.finish()?;
+        tree.pad = free.pad;
+        Ok(tree)
+    }
+
+    /// Every page of a tree: branches, leaves and overflow runs.
+    fn tree_pages(&mut self, tree: &model::Database) -> Result<Vec<u64>, Error> {
+        let mut pages = Vec::new();
+        let mut stack = Vec::from_iter(tree.root);
+        while let Some(pageno) = stack.pop() {
+            pages.push(pageno);
+            match self.read_page(pageno as usize)? {
+                model::Page::Branch(branch) => {
+                    stack.extend(branch.nodes.iter().map(|node| node.pageno));
+                }
+                model::Page::Leaf(leaf) => {
+                    for node in leaf.nodes {
+                        if let model::NodeData::Overflow(overflow, size) = node.data {
+                            let count =
+                                lowlevel::overflow_pages(self.word_size(), self.page_size, size);
+                            pages.extend(overflow..overflow + count as u64);
+                        }
+                    }
+                }
+            }
+            if pages.len() as u64 > self.meta.last_pgno {
+                return Err(Report::new(Error::InvalidFileFormat)
+                    .attach_printable("tree holds more pages than the file"));
+            }
+        }
+        Ok(pages)
+    }
+
+    /// Take `count` contiguous pages from the free list, or after the last
+    /// page of the file.
+    fn allocate_pages(&mut self, txn: &mut Txn, count: usize) -> u64 {
+        loop {
+            // Runs are found from the lowest pages, at the end of the list
+            let free = &txn.free;
+            let run = (count - 1..free.len())
+                .rev()
+                .find(|&i| free[i - (count - 1)] == free[i] + (count - 1) as u64);
+            if let Some(i) = run {
+                let pageno = free[i];
+                txn.free.drain(i - (count - 1)..=i);
+                return pageno;
+            }
+            match txn.reusable.pop_front() {
+                Some((_, pages)) => {
+                    txn.free.extend(pages);
+                    txn.free.sort_unstable_by(|a, b| b.cmp(a));
+                }
+                None => break,
+            }
+        }
+        let pageno = self.meta.last_pgno + 1;
+        self.meta.last_pgno += count as u64;
+        pageno
+    }
+
+    /// Build the node holding `value`, moving big values to overflow pages.
+    fn value_node(
+        &mut self,
+        txn: &mut Txn,
+        tree: &mut model::Database,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<model::Node, Error> {
+        let word_size = self.word_size();
+        if lowlevel::NODE_SIZE + key.len() + value.len()
+            <= lowlevel::node_max(word_size, self.page_size)
+        {
+            return Ok(model::Node {
+                flags: model::NodeFlags::empty(),
+                key: key.to_vec(),
+                data: model::NodeData::Data(value.to_vec()),
+            });
+        }
+
+        let count = lowlevel::overflow_pages(word_size, self.page_size, value.len());
+        let pageno = self.allocate_pages(txn, count);
+        tree.overflow_pages += count as u64;
+        let mut writer = self.writer.as_ref().unwrap().lock().unwrap();
+        Database::write_overflow_unsafe(
+            writer.as_mut(),
+            model::Overflow {
+                pageno,
+                data: value.to_vec(),
+            },
+            self.page_size,
+        )?;
+        Ok(model::Node {
+            flags: model::NodeFlags::BIGDATA,
+            key: key.to_vec(),
+            data: model::NodeData::Overflow(pageno, value.len()),
+        })
+    }
+
+    /// Set (or remove, without value) `key` in `tree`.
+    fn update_tree(
+        &mut self,
+        txn: &mut Txn,
+        tree: &mut model::Database,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        if tree.flags.contains(model::metadata::Flags::DUPSORT) {
+            return Err(Report::new(Error::WriteError)
+                .attach_printable("updating databases with duplicates is not supported"));
+        }
+        let key_max = lowlevel::key_max(self.word_size(), self.page_size);
+        if key.is_empty() || key.len() > key_max {
+            return Err(Report::new(Error::WriteError).attach_printable(format!(
+                "keys must hold 1 to {} bytes, got {}",
+                key_max,
+                key.len()
+            )));
+        }
+        let node = match value {
+            Some(value) => Some(self.value_node(txn, tree, key, value)?),
+            None => None,
+        };
+        self.update_path(txn, tree, key, node)
+    }
+
+    /// Copy the pages from the root of `tree` to the leaf of `key`, with
+    /// `node` set in place of the key, or the key removed without node.
+    fn update_path(
+        &mut self,
+        txn: &mut Txn,
+        tree: &mut model::Database,
+        key: &[u8],
+        node: Option<model::Node>,
+    ) -> Result<(), Error> {
+        if tree.branch_pages == 0 && tree.leaf_pages > 1 {
+            return Err(Report::new(Error::WriteError)
+                .attach_printable("leaves without branch pages, convert the database first"));
+        }
+        let mut children = match tree.root {
+            Some(root) => self.update_page(txn, tree, root, key, node, 0)?,
+            None => match node {
+                Some(node) => {
+                    tree.entries += node.entries();
+                    tree.depth = 1;
+                    let leaf = model::Leaf {
+                        pageno: 0,
+                        flags: model::header::Flags::LEAF,
+                        nodes: vec![node],
+                    };
+                    self.write_leaves(txn, tree, leaf)?
+                }
+                None => Vec::new(),
+            },
+        };
+
+        // The root split, add a level of branches
+        while children.len() > 1 {
+            children[0].key.clear();
+            let branch = model::Branch {
+                pageno: 0,
+                flags: model::header::Flags::BRANCH,
+                nodes: children,
+            };
+            children = self.write_branches(txn, tree, branch)?;
+            tree.depth += 1;
+        }
+        tree.root = children.first().map(|child| child.pageno);
+        if tree.root.is_none() {
+            tree.depth = 0;
+        }
+        Ok(())
+    }
+
+    /// Copy the page `pageno` with the change applied below it. Returns the
+    /// pages replacing it: none when it is left empty, several when it had to
+    /// be split, the key of each being its first key.
+    fn update_page(
+        &mut self,
+        txn: &mut Txn,
+        tree: &mut model::Database,
+        pageno: u64,
+        key: &[u8],
+        node: Option<model::Node>,
+        level: usize,
+    ) -> Result<Vec<model::BranchNode>, Error> {
+        if level >= CURSOR_STACK {
+            return Err(Report::new(Error::InvalidFileFormat)
+                .attach_printable(format!("B-tree deeper than {}", CURSOR_STACK)));
+        }
+        let endianness = self.endianness();
+        let flags = tree.flags;
+        let cmp = |a: &[u8], b: &[u8]| compare::compare(flags, endianness, a, b);
+
+        txn.freed.push(pageno);
+        match self.read_page(pageno as usize)? {
+            model::Page::Leaf(mut leaf) => {
+                tree.leaf_pages -= 1;
+                match leaf.nodes.binary_search_by(|other| cmp(&other.key, key)) {
+                    Ok(idx) => {
+                        let old = &leaf.nodes[idx];
+                        let subdata = old.flags.contains(model::NodeFlags::SUBDATA);
+                        if subdata
+                            != node
+                                .as_ref()
+                                .is_some_and(|node| node.flags.contains(model::NodeFlags::SUBDATA))
+                        {
+                            return Err(Report::new(Error::WriteError).attach_printable(format!(
+                                "{:?} is the name of a database",
+                                String::from_utf8_lossy(key)
+                            )));
+                        }
+                        if let model::NodeData::Overflow(overflow, size) = old.data {
+                            let count =
+                                lowlevel::overflow_pages(self.word_size(), self.page_size, size);
+                            txn.freed.extend(overflow..overflow + count as u64);
+                            tree.overflow_pages -= count as u64;
+                        }
+                        tree.entries -= old.entries();
+                        match node {
+                            Some(node) => {
+                                tree.entries += node.entries();
+                                leaf.nodes[idx] = node;
+                            }
+                            None => {
+                                leaf.nodes.remove(idx);
+                            }
+                        }
+                    }
+                    Err(idx) => {
+                        if let Some(node) = node {
+                            tree.entries += node.entries();
+                            leaf.nodes.insert(idx, node);
+                        }
+                    }
+                }
+                if leaf.nodes.is_empty() {
+                    return Ok(Vec::new());
+                }
+                self.write_leaves(txn, tree, leaf)
+            }
+            model::Page::Branch(mut branch) => {
+                tree.branch_pages -= 1;
+                // Last child whose separator is not above the key, the first
+                // separator being implicit
+                let idx = branch
+                    .nodes
+                    .iter()
+                    .skip(1)
+                    .take_while(|other| cmp(&other.key, key).is_le())
+                    .count();
+                let child = match branch.nodes.get(idx) {
+                    Some(child) => child.clone(),
+                    None => {
+                        return Err(Report::new(Error::InvalidFileFormat)
+                            .attach_printable(format!("empty branch page {}", pageno)))
+                    }
+                };
+                let mut children =
+                    self.update_page(txn, tree, child.pageno, key, node, level + 1)?;
+                // The first page keeps the separator of the page it replaces
+                if let Some(first) = children.first_mut() {
+                    first.key = child.key;
+                }
+                branch.nodes.splice(idx..=idx, children);
+                if let Some(first) = branch.nodes.first_mut() {
+                    first.key.clear();
+                }
+
+                match branch.nodes.len() {
+                    0 => Ok(Vec::new()),
+                    // A root with a single child is replaced by the child
+                    1 if level == 0 => {
+                        tree.depth -= 1;
+                        Ok(branch.nodes)
+                    }
+                    _ => self.write_branches(txn, tree, branch),
+                }
+            }
+        }
+    }
+
+    /// Write the nodes of `leaf` to new pages, split when they do not fit.
+    fn write_leaves(
+        &mut self,
+        txn: &mut Txn,
+        tree: &mut model::Database,
+        leaf: model::Leaf,
+    ) -> Result<Vec<model::BranchNode>, Error> {
+        let word_size = self.word_size();
+        let room = self.page_size - lowlevel::page_header_size(word_size);
+        let mut pages = Vec::new();
+        for nodes in split(
+            leaf.nodes,
+            |node| 2 + lowlevel::even(node.size(word_size)),
+            room,
+        ) {
+            let pageno = self.allocate_pages(txn, 1);
+            pages.push(model::BranchNode {
+                key: nodes[0].key.clone(),
+                pageno,
+            });
+            let leaf = model::Leaf {
+                pageno: pageno as usize,
+                flags: leaf.flags,
+                nodes,
+            };
+            let mut writer = self.writer.as_ref().unwrap().lock().unwrap();
+            Database::write_leaf_unsafe(writer.as_mut(), leaf, self.page_size)?;
+            tree.leaf_pages += 1;
+        }
+        Ok(pages)
+    }
+
+    /// Write the nodes of `branch` to new pages, split when they do not fit.
+    fn write_branches(
+        &mut self,
+        txn: &mut Txn,
+        tree: &mut model::Database,
+        branch: model::Branch,
+    ) -> Result<Vec<model::BranchNode>, Error> {
+        let room = self.page_size - lowlevel::page_header_size(self.word_size());
+        let mut pages = Vec::new();
+        for mut nodes in split(branch.nodes, |node| 2 + lowlevel::even(node.size()), room) {
+            let pageno = self.allocate_pages(txn, 1);
+            // The first key of a branch is implicit, it moves to the parent
+            pages.push(model::BranchNode {
+                key: std::mem::take(&mut nodes[0].key),
+                pageno,
+            });
+            let branch = model::Branch {
+                pageno: pageno as usize,
+                flags: branch.flags,
+                nodes,
+            };
+            let mut writer = self.writer.as_ref().unwrap().lock().unwrap();
+            Database::write_branch_unsafe(writer.as_mut(), branch, self.page_size)?;
+            tree.branch_pages += 1;
+        }
+        Ok(pages)
+    }
+}
+
+/// Split items into pages of `room` bytes: two halves of about the same size
+/// when possible, pages filled one after the other otherwise.
+fn split<T>(items: Vec<T>, space: impl Fn(&T) -> usize, room: usize) -> Vec<Vec<T>> {
+    let sizes = items.iter().map(&space).collect::<Vec<_>>();
+    let total = sizes.iter().sum::<usize>();
+    if total <= room {
+        return vec![items];
+    }
+
+    let mut left = 0;
+    let mut count = 0;
+    for size in sizes.iter() {
+        if count > 0 && (left >= total / 2 || left + size > room) {
+            break;
+        }
+        left += size;
+        count += 1;
+    }
+    let mut ends = Vec::new();
+    if total - left <= room {
+        ends.push(count);
+    } else {
+        let mut used = 0;
+        for (i, size) in sizes.iter().enumerate() {
+            if used > 0 && used + size > room {
+                ends.push(i);
+                used = 0;
+            }
+            used += size;
+        }
+    }
+
+    let mut pages = Vec::new();
+    let mut items = items;
+    for end in ends.into_iter().rev() {
+        pages.push(items.split_off(end));
+    }
+    pages.push(items);
+    pages.reverse();
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::lmdb::Factory;
+
+    use super::*;
+
+    macro_rules! test_case {
+        ($fname:expr) => {
+            std::path::PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/", $fname))
+        };
+    }
+
+    fn copy(path: std::path::PathBuf) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::copy(path, file.path()).unwrap();
+        file
+    }
+
+    /// Every page past the meta pages is used by exactly one tree or is in
+    /// the free list.
+    fn check_pages(db: &mut Database) {
+        let mut pages = Vec::new();
+        for (_, tree) in db.databases().unwrap() {
+            pages.extend(db.tree_pages(&tree).unwrap());
+        }
+        for tree in [db.meta.main.clone(), db.meta.free.clone()] {
+            pages.extend(db.tree_pages(&tree).unwrap());
+        }
+        for (_, free) in db.freelist().unwrap() {
+            pages.extend(free);
+        }
+        pages.sort_unstable();
+        assert_eq!(pages, (2..=db.meta.last_pgno).collect::<Vec<_>>());
+    }
+
+    fn check_content(db: &mut Database, expected: &BTreeMap<Vec<u8>, Vec<u8>>) {
+        let elements = db
+            .read_cursor()
+            .unwrap()
+            .map(|element| element.map(|element| (element.key, element.value)))
+            .collect::<Result<BTreeMap<_, _>, _>>()
+            .unwrap();
+        assert_eq!(elements, *expected);
+        assert_eq!(db.meta.main.entries, expected.len() as u64);
+    }
+
+    #[test]
+    fn test_put_del_64() {
+        let file = copy(test_case!("btree.64bits"));
+        let mut db = Factory::open_rw(file.path().into()).unwrap();
+        let mut expected = db
+            .read_cursor()
+            .unwrap()
+            .map(|element| element.map(|element| (element.key, element.value)))
+            .collect::<Result<BTreeMap<_, _>, _>>()
+            .unwrap();
+        let txnid = db.meta.txnid;
+
+        // Deterministic mix of inserts, updates and deletes, with values
+        // moving to and from overflow pages
+        let mut seed = 42u32;
+        let mut random = move |n: u32| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) % n
+        };
+        for _ in 0..300 {
+            let key = format!("key-{:04}-x", random(400)).into_bytes();
+            if random(3) == 0 {
+                assert_eq!(db.del(&key).unwrap(), expected.remove(&key).is_some());
+            } else {
+                let size = [10, 300, 3000, 9000][random(4) as usize];
+                let value = vec![b'a' + random(26) as u8; size];
+                db.put(&key, &value).unwrap();
+                expected.insert(key, value);
+            }
+        }
+        check_content(&mut db, &expected);
+        check_pages(&mut db);
+        assert!(db.meta.txnid > txnid);
+
+        // Reopened from the file
+        let mut db = Factory::open(file.path().into()).unwrap();
+        check_content(&mut db, &expected);
+        check_pages(&mut db);
+
+        // Down to an empty tree
+        let mut db = Factory::open_rw(file.path().into()).unwrap();
+        for key in expected.keys() {
+            assert!(db.del(key).unwrap());
+        }
+        assert!(!db.del(b"key-0000-x").unwrap());
+        assert_eq!(db.meta.main.root, None);
+        assert_eq!(db.meta.main.depth, 0);
+        check_content(&mut db, &BTreeMap::new());
+        check_pages(&mut db);
+    }
+
+    #[test]
+    fn test_put_key_size_64() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create_with(
+            file.path().into(),
+            crate::lmdb::WordSize::Word64,
+            crate::lmdb::Endianness::Little,
+            512,
+        )
+        .unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        cur.push(b"a".to_vec(), b"1".to_vec()).unwrap();
+        cur.commit().unwrap();
+
+        let mut db = Factory::open_rw(file.path().into()).unwrap();
+        assert!(db.put(&[b'k'; 500], b"value").is_err());
+        assert!(db.put(&[b'k'; 191], b"value").is_err());
+        assert!(db.put(b"", b"value").is_err());
+        db.put(&[b'k'; 190], &[b'v'; 1000]).unwrap();
+        assert_eq!(db.get(&[b'k'; 190]).unwrap().unwrap(), vec![b'v'; 1000]);
+        check_pages(&mut db);
+    }
+
+    #[test]
+    fn test_put_named_32() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), crate::lmdb::WordSize::Word32).unwrap();
+        let mut cur = db
+            .write_cursor_named(b"alpha", model::metadata::Flags::empty())
+            .unwrap();
+        cur.push(b"a".to_vec(), b"1".to_vec()).unwrap();
+        cur.commit().unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        cur.push(b"main".to_vec(), b"value".to_vec()).unwrap();
+        cur.commit().unwrap();
+
+        let mut db = Factory::open_rw(file.path().into()).unwrap();
+        for i in 0..200 {
+            let key = format!("b{:03}", i);
+            db.put_named(b"alpha", key.as_bytes(), key.repeat(5).as_bytes())
+                .unwrap();
+        }
+        assert!(db.del_named(b"alpha", b"a").unwrap());
+        assert!(db.put(b"alpha", b"value").is_err());
+        assert!(db.put_named(b"beta", b"a", b"1").is_err());
+
+        let mut db = Factory::open(file.path().into()).unwrap();
+        let tree = db.database(b"alpha").unwrap().unwrap();
+        assert_eq!(tree.entries, 200);
+        assert!(tree.depth > 1);
+        assert_eq!(
+            db.get_from(&tree, b"b042").unwrap().unwrap(),
+            b"b042".repeat(5)
+        );
+        assert_eq!(db.get(b"main").unwrap().unwrap(), b"value");
+        check_pages(&mut db);
+    }
+}
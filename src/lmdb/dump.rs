@@ -1 +1,659 @@
+use std::io::BufRead;
+use std::io::Write;
 
+use error_stack::Report;
+use error_stack::Result;
+use error_stack::ResultExt;
+
+use super::compare;
+use super::database::Database;
+use super::error::Error;
+use super::lock;
+use super::model::lowlevel;
+use super::model::metadata::Flags;
+use super::model::Element;
+
+/// Version of the text format of `mdb_dump`
+pub const VERSION: u32 = 3;
+
+/// Database flags of a header, in the order `mdb_dump` writes them
+pub const FLAG_NAMES: [(Flags, &str); 6] = [
+    (Flags::REVERSEKEY, "reversekey"),
+    (Flags::DUPSORT, "dupsort"),
+    (Flags::INTEGERKEY, "integerkey"),
+    (Flags::DUPFIXED, "dupfixed"),
+    (Flags::INTEGERDUP, "integerdup"),
+    (Flags::REVERSEDUP, "reversedup"),
+];
+
+/// Header of a section of a `mdb_dump` output, one per database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Printable characters are written as-is (`format=print`) rather than
+    /// as hexadecimal (`format=bytevalue`)
+    pub print: bool,
+    /// Name of the database, none for the main one
+    pub database: Option<Vec<u8>>,
+    pub mapsize: u64,
+    pub maxreaders: u32,
+    pub flags: Flags,
+    pub page_size: usize,
+}
+
+impl Header {
+    pub fn write_to<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        let mut text = format!("VERSION={}\n", VERSION).into_bytes();
+        text.extend(format!("format={}\n", self.format()).bytes());
+        if let Some(name) = &self.database {
+            text.extend(b"database=");
+            text.extend(name);
+            text.push(b'\n');
+        }
+        text.extend(b"type=btree\n");
+        text.extend(format!("mapsize={}\n", self.mapsize).bytes());
+        text.extend(format!("maxreaders={}\n", self.maxreaders).bytes());
+        if self.flags.contains(Flags::DUPSORT) {
+            text.extend(b"duplicates=1\n");
+        }
+        for (flag, name) in FLAG_NAMES {
+            if self.flags.contains(flag) {
+                text.extend(format!("{}=1\n", name).bytes());
+            }
+        }
+        text.extend(format!("db_pagesize={}\n", self.page_size).bytes());
+        text.extend(b"HEADER=END\n");
+        out.write_all(&text).change_context(Error::WriteError)
+    }
+
+    fn format(&self) -> &'static str {
+        if self.print {
+            "print"
+        } else {
+            "bytevalue"
+        }
+    }
+
+    /// Encode a key or a value as a record line, without its leading space.
+    pub fn encode(&self, data: &[u8]) -> String {
+        let mut line = String::with_capacity(data.len() * 2);
+        for &byte in data {
+            match byte {
+                b'\\' if self.print => line.push_str("\\\\"),
+                0x20..=0x7e if self.print => line.push(byte as char),
+                _ if self.print => line.push_str(&format!("\\{:02x}", byte)),
+                _ => line.push_str(&format!("{:02x}", byte)),
+            }
+        }
+        line
+    }
+
+    /// Write the section: the header, one line for the key and one for the
+    /// value of each record, then `DATA=END`.
+    pub fn write_section<W, I>(&self, out: &mut W, elements: I) -> Result<(), Error>
+    where
+        W: Write,
+        I: IntoIterator<Item = Result<Element, Error>>,
+    {
+        self.write_to(out)?;
+        for element in elements {
+            let element = element?;
+            let lines = format!(
+                " {}\n {}\n",
+                self.encode(&element.key),
+                self.encode(&element.value)
+            );
+            out.write_all(lines.as_bytes())
+                .change_context(Error::WriteError)?;
+        }
+        out.write_all(b"DATA=END\n")
+            .change_context(Error::WriteError)
+    }
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header {
+            print: false,
+            database: None,
+            mapsize: 0,
+            maxreaders: lock::DEFAULT_READERS as u32,
+            flags: Flags::empty(),
+            page_size: lowlevel::DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+/// The records of a database read back from a `mdb_dump` output, in the
+/// order of the input.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub header: Header,
+    pub elements: Vec<Element>,
+}
+
+impl Section {
+    /// Check that the keys, duplicates and database name fit the nodes of
+    /// a database of `word_size` bytes words and `page_size` pages, so that
+    /// nothing is written when loading would fail.
+    pub fn check_sizes(&self, word_size: usize, page_size: usize) -> Result<(), Error> {
+        let key_max = lowlevel::key_max(word_size, page_size);
+        let dupsort = self.header.flags.contains(Flags::DUPSORT);
+        // Duplicates are the keys of their sub-page or sub-tree
+        let mut keys: Vec<&[u8]> = self.header.database.as_deref().into_iter().collect();
+        for element in &self.elements {
+            keys.push(&element.key);
+            if dupsort {
+                keys.push(&element.value);
+            }
+        }
+        for key in keys {
+            if key.is_empty() || key.len() > key_max {
+                return Err(Report::new(Error::WriteError).attach_printable(format!(
+                    "keys must hold 1 to {} bytes with {} bytes pages, got {}",
+                    key_max,
+                    page_size,
+                    key.len()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lines of an input, numbered for error messages.
+struct Lines<R> {
+    input: R,
+    number: usize,
+}
+
+impl<R: BufRead> Lines<R> {
+    /// The next line without its end of line, none at the end of input.
+    fn next(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut line = Vec::new();
+        let read = self
+            .input
+            .read_until(b'\n', &mut line)
+            .change_context(Error::ReadError)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.number += 1;
+        for end in [b'\n', b'\r'] {
+            if line.last() == Some(&end) {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    fn error(&self, message: String) -> Report<Error> {
+        Report::new(Error::InvalidFileFormat)
+            .attach_printable(format!("line {}: {}", self.number, message))
+    }
+
+    fn decode(&self, data: &[u8], print: bool) -> Result<Vec<u8>, Error> {
+        decode(data, print)
+            .ok_or_else(|| self.error(format!("invalid data {:?}", String::from_utf8_lossy(data))))
+    }
+}
+
+/// Decode a record line of `Header::encode`, without its leading space.
+pub fn decode(data: &[u8], print: bool) -> Option<Vec<u8>> {
+    let hex = |digits: &[u8]| {
+        std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+    };
+    if !print {
+        if !data.len().is_multiple_of(2) {
+            return None;
+        }
+        return data.chunks(2).map(hex).collect();
+    }
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        match (data[i], data.get(i + 1)) {
+            (b'\\', Some(b'\\')) => {
+                bytes.push(b'\\');
+                i += 2;
+            }
+            (b'\\', _) => {
+                bytes.push(hex(data.get(i + 1..i + 3)?)?);
+                i += 3;
+            }
+            (byte, _) => {
+                bytes.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(bytes)
+}
+
+/// Read every section of a `mdb_dump` output.
+pub fn read_dump<R: BufRead>(input: R) -> Result<Vec<Section>, Error> {
+    let mut lines = Lines { input, number: 0 };
+    let mut sections = Vec::new();
+    while let Some(header) = read_header(&mut lines)? {
+        let mut elements = Vec::new();
+        loop {
+            let key = match lines.next()? {
+                Some(line) if line == b"DATA=END" => break,
+                Some(line) if line.first() == Some(&b' ') => {
+                    lines.decode(&line[1..], header.print)?
+                }
+                Some(line) => {
+                    return Err(lines.error(format!(
+                        "unexpected line {:?}",
+                        String::from_utf8_lossy(&line)
+                    )))
+                }
+                None => return Err(lines.error("missing DATA=END".to_string())),
+            };
+            let value = match lines.next()? {
+                Some(line) if line.first() == Some(&b' ') => {
+                    lines.decode(&line[1..], header.print)?
+                }
+                _ => return Err(lines.error("missing value".to_string())),
+            };
+            elements.push(Element { key, value });
+        }
+        sections.push(Section { header, elements });
+    }
+    Ok(sections)
+}
+
+fn read_header<R: BufRead>(lines: &mut Lines<R>) -> Result<Option<Header>, Error> {
+    let mut header = Header::default();
+    let mut first = true;
+    loop {
+        let line = match lines.next()? {
+            Some(line) => line,
+            None if first => return Ok(None),
+            None => return Err(lines.error("missing HEADER=END".to_string())),
+        };
+        if line == b"HEADER=END" {
+            return Ok(Some(header));
+        }
+        let Some(equal) = line.iter().position(|&c| c == b'=') else {
+            return Err(lines.error(format!(
+                "unexpected line {:?}",
+                String::from_utf8_lossy(&line)
+            )));
+        };
+        let (keyword, value) = (&line[..equal], &line[equal + 1..]);
+        let text = String::from_utf8_lossy(value);
+        let number = |max: u64| {
+            text.parse::<u64>()
+                .ok()
+                .filter(|number| *number <= max)
+                .ok_or_else(|| lines.error(format!("invalid number {:?}", text)))
+        };
+        match keyword {
+            b"VERSION" if first => {
+                if number(u64::MAX)? != VERSION as u64 {
+                    return Err(Report::new(Error::VersionNotSupported)
+                        .attach_printable(format!("line {}: VERSION={}", lines.number, text)));
+                }
+            }
+            _ if first => return Err(lines.error("missing VERSION".to_string())),
+            b"format" => {
+                header.print = match value {
+                    b"print" => true,
+                    b"bytevalue" => false,
+                    _ => return Err(lines.error(format!("unsupported format {:?}", text))),
+                }
+            }
+            b"database" => header.database = Some(value.to_vec()),
+            b"type" if value != b"btree" => {
+                return Err(lines.error(format!("unsupported type {:?}", text)))
+            }
+            b"mapsize" => header.mapsize = number(u64::MAX)?,
+            b"maxreaders" => header.maxreaders = number(u32::MAX as u64)? as u32,
+            b"db_pagesize" => {
+                header.page_size = number(lowlevel::MAX_PAGE_SIZE as u64)? as usize;
+                if !lowlevel::is_valid_page_size(header.page_size) {
+                    return Err(lines.error(format!("invalid page size {:?}", text)));
+                }
+            }
+            // Implied by dupsort, and a fixed map address is meaningless here
+            b"type" | b"duplicates" | b"mapaddr" => (),
+            _ => match FLAG_NAMES
+                .iter()
+                .find(|(_, name)| name.as_bytes() == keyword)
+            {
+                Some((flag, _)) => header.flags.set(*flag, number(1)? == 1),
+                None => tracing::warn!(
+                    "line {}: unrecognized keyword {:?} ignored",
+                    lines.number,
+                    String::from_utf8_lossy(keyword)
+                ),
+            },
+        }
+        first = false;
+    }
+}
+
+/// Read alternating key and value lines, in the escaped form of
+/// `format=print` without a leading space, as `mdb_load -T` does.
+pub fn read_plain<R: BufRead>(input: R, header: Header) -> Result<Section, Error> {
+    let mut lines = Lines { input, number: 0 };
+    let mut elements = Vec::new();
+    while let Some(key) = lines.next()? {
+        let key = lines.decode(&key, true)?;
+        let value = match lines.next()? {
+            Some(line) => lines.decode(&line, true)?,
+            None => return Err(lines.error("missing value".to_string())),
+        };
+        elements.push(Element { key, value });
+    }
+    Ok(Section { header, elements })
+}
+
+impl Database<'_> {
+    /// Write `sections` into this database, just created, in a single
+    /// transaction. The records of each section are sorted in the order of
+    /// its flags first; for a key given twice the last value wins, unless
+    /// the database has sorted duplicates.
+    pub fn load(&mut self, sections: Vec<Section>) -> Result<(), Error> {
+        if self.meta.txnid > 0 {
+            return Err(Report::new(Error::WriteError)
+                .attach_printable("loading into a database already written"));
+        }
+        let endianness = self.endianness();
+        let (main, named): (Vec<_>, Vec<_>) = sections
+            .into_iter()
+            .partition(|section| section.header.database.is_none());
+        if main.len() > 1 {
+            return Err(Report::new(Error::InvalidFileFormat)
+                .attach_printable("main database given more than once"));
+        }
+
+        // Named databases first, they are recorded by the main commit
+        for mut section in named {
+            let name = section.header.database.clone().unwrap_or_default();
+            let flags = section.header.flags;
+            sort_elements(&mut section.elements, flags, endianness);
+            self.meta.mapsize = self.meta.mapsize.max(section.header.mapsize);
+            let mut cur = self.write_cursor_named(&name, flags)?;
+            for element in section.elements {
+                cur.push_element(element)?;
+            }
+            cur.commit()?;
+        }
+
+        let mut elements = Vec::new();
+        if let Some(mut section) = main.into_iter().next() {
+            self.meta.main.flags = section.header.flags;
+            self.meta.mapsize = self.meta.mapsize.max(section.header.mapsize);
+            sort_elements(&mut section.elements, section.header.flags, endianness);
+            elements = section.elements;
+        }
+        let mut cur = self.write_cursor()?;
+        for element in elements {
+            cur.push_element(element)?;
+        }
+        cur.commit()
+    }
+
+    /// Header of the main database, or of the named database `name`, as
+    /// written by `mdb_dump`.
+    pub fn dump_header(&mut self, name: Option<&[u8]>, print: bool) -> Result<Header, Error> {
+        let flags = match name {
+            Some(name) => {
+                self.database(name)?
+                    .ok_or(Error::DatabaseNotFound)
+                    .attach_printable_lazy(|| {
+                        format!("database {:?}", String::from_utf8_lossy(name))
+                    })?
+                    .flags
+            }
+            None => self.meta.main.flags,
+        };
+        Ok(Header {
+            print,
+            database: name.map(|name| name.to_vec()),
+            mapsize: self.meta.mapsize,
+            maxreaders: lock::DEFAULT_READERS as u32,
+            flags,
+            page_size: self.page_size,
+        })
+    }
+}
+
+/// Sort records in the order of a tree with `flags`, keeping the last value
+/// of a key given twice, or each distinct value with `DUPSORT`.
+fn sort_elements(elements: &mut Vec<Element>, flags: Flags, endianness: super::Endianness) {
+    let dupsort = flags.contains(Flags::DUPSORT);
+    elements.sort_by(|a, b| {
+        compare::compare(flags, endianness, &a.key, &b.key).then_with(|| {
+            if dupsort {
+                compare::compare_dups(flags, endianness, &a.value, &b.value)
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+    });
+    let mut sorted: Vec<Element> = Vec::with_capacity(elements.len());
+    for element in elements.drain(..) {
+        match sorted.last_mut() {
+            Some(last) if last.key == element.key && !dupsort => *last = element,
+            Some(last) if last.key == element.key && last.value == element.value => (),
+            _ => sorted.push(element),
+        }
+    }
+    *elements = sorted;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lmdb::Endianness;
+    use crate::lmdb::Factory;
+    use crate::lmdb::WordSize;
+
+    macro_rules! test_case {
+        ($fname:expr) => {
+            std::path::PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/", $fname))
+        };
+    }
+
+    #[test]
+    fn test_dump_header_64() {
+        let mut db = Factory::open(test_case!("dupsort.64bits")).unwrap();
+        let header = db.dump_header(Some(b"fixed"), false).unwrap();
+        let mut out = Vec::new();
+        header.write_to(&mut out).unwrap();
+        // As written by mdb_dump -s fixed
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "VERSION=3\nformat=bytevalue\ndatabase=fixed\ntype=btree\nmapsize=1048576\n\
+             maxreaders=126\nduplicates=1\ndupsort=1\ndupfixed=1\nintegerdup=1\n\
+             db_pagesize=4096\nHEADER=END\n"
+        );
+
+        assert!(db.dump_header(Some(b"missing"), false).is_err());
+    }
+
+    #[test]
+    fn test_dump_section_64() {
+        let mut db = Factory::open(test_case!("named.64bits")).unwrap();
+        let header = db.dump_header(None, false).unwrap();
+        let mut out = Vec::new();
+        header
+            .write_section(&mut out, db.read_cursor().unwrap())
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("VERSION=3\nformat=bytevalue\ntype=btree\n"));
+        assert!(text
+            .ends_with("HEADER=END\n 616161\n 6d61696e2d61\n 7a7a7a\n 6d61696e2d7a\nDATA=END\n"));
+    }
+
+    #[test]
+    fn test_dump_print() {
+        let header = Header {
+            print: true,
+            database: None,
+            mapsize: 1048576,
+            maxreaders: 126,
+            flags: Flags::empty(),
+            page_size: 4096,
+        };
+        assert_eq!(header.encode(b"key a\\b\x00\x7f~"), "key a\\\\b\\00\\7f~");
+        let header = Header {
+            print: false,
+            ..header
+        };
+        assert_eq!(header.encode(b"key\x00"), "6b657900");
+    }
+
+    fn dump_all(db: &mut Database<'_>) -> Vec<u8> {
+        let mut out = Vec::new();
+        let header = db.dump_header(None, false).unwrap();
+        header
+            .write_section(&mut out, db.read_cursor().unwrap())
+            .unwrap();
+        for (name, _) in db.databases().unwrap() {
+            let header = db.dump_header(Some(&name), false).unwrap();
+            header
+                .write_section(&mut out, db.read_cursor_named(&name).unwrap())
+                .unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn test_load_roundtrip_32() {
+        for path in [
+            test_case!("dupsort.64bits"),
+            test_case!("named.64bits"),
+            test_case!("overflow.64bits"),
+        ] {
+            let mut db = Factory::open(path.clone()).unwrap();
+            let dump = dump_all(&mut db);
+
+            let sections = read_dump(dump.as_slice()).unwrap();
+            let file = tempfile::NamedTempFile::new().unwrap();
+            let mut out = Factory::create(file.path().into(), WordSize::Word32).unwrap();
+            out.load(sections).unwrap();
+            out.close().unwrap();
+
+            let mut out = Factory::open(file.path().into()).unwrap();
+            assert_eq!(dump_all(&mut out), dump, "{:?}", path);
+            assert!(out.check().unwrap().is_ok(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn test_load_unsorted() {
+        let dump = "VERSION=3\nformat=print\ndatabase=dups\ntype=btree\nduplicates=1\n\
+                    dupsort=1\nHEADER=END\n b\n 2\n a\n 1\n b\n 1\n b\n 2\nDATA=END\n\
+                    VERSION=3\nformat=bytevalue\ntype=btree\nHEADER=END\n 62\n 31\n 61\n 31\n\
+                    \x2062\n 32\nDATA=END\n";
+        let sections = read_dump(dump.as_bytes()).unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].header.database, Some(b"dups".to_vec()));
+        assert_eq!(sections[0].header.flags, Flags::DUPSORT);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word64).unwrap();
+        db.load(sections).unwrap();
+        db.close().unwrap();
+        let mut db = Factory::open(file.path().into()).unwrap();
+        fn records(
+            elements: impl Iterator<Item = Result<Element, Error>>,
+        ) -> Vec<(Vec<u8>, Vec<u8>)> {
+            elements
+                .map(|element| {
+                    let element = element.unwrap();
+                    (element.key, element.value)
+                })
+                .collect()
+        }
+        // Sorted, duplicated records once, the last value of a key given twice
+        assert_eq!(
+            records(db.read_cursor_named(b"dups").unwrap()),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+            ]
+        );
+        assert_eq!(
+            records(db.read_cursor().unwrap()),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_key_size() {
+        let key = "6b".repeat(lowlevel::MAX_KEY_SIZE + 1);
+        let dump = format!("VERSION=3\nHEADER=END\n {}\n 31\nDATA=END\n", key);
+        let sections = read_dump(dump.as_bytes()).unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word64).unwrap();
+        assert!(sections[0].check_sizes(8, 4096).is_err());
+        let e = db.load(sections).unwrap_err();
+        assert!(matches!(e.current_context(), Error::WriteError));
+
+        // Smaller pages hold smaller keys
+        let key = "6b".repeat(500);
+        let dump = format!(
+            "VERSION=3\ndb_pagesize=512\nHEADER=END\n {}\n 31\nDATA=END\n",
+            key
+        );
+        let sections = read_dump(dump.as_bytes()).unwrap();
+        assert!(sections[0].check_sizes(8, 512).is_err());
+        assert!(sections[0].check_sizes(8, 4096).is_ok());
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create_with(
+            file.path().into(),
+            WordSize::Word64,
+            Endianness::Little,
+            512,
+        )
+        .unwrap();
+        let e = db.load(sections).unwrap_err();
+        assert!(matches!(e.current_context(), Error::WriteError));
+    }
+
+    #[test]
+    fn test_read_plain() {
+        let section = read_plain(
+            "key\\20a\nvalue\\\\\n".as_bytes(),
+            Header {
+                print: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(section.elements.len(), 1);
+        assert_eq!(section.elements[0].key, b"key a");
+        assert_eq!(section.elements[0].value, b"value\\");
+
+        assert!(read_plain("key\n".as_bytes(), Header::default()).is_err());
+        assert!(read_plain("key\\2\nvalue\n".as_bytes(), Header::default()).is_err());
+    }
+
+    #[test]
+    fn test_read_dump_invalid() {
+        for dump in [
+            "VERSION=4\nHEADER=END\nDATA=END\n",
+            "format=bytevalue\nHEADER=END\nDATA=END\n",
+            "VERSION=3\nformat=base64\nHEADER=END\nDATA=END\n",
+            "VERSION=3\ntype=hash\nHEADER=END\nDATA=END\n",
+            "VERSION=3\nHEADER=END\n 61\n",
+            "VERSION=3\nHEADER=END\n 6\n 61\nDATA=END\n",
+            "VERSION=3\nHEADER=END\n 61\n 61\n",
+            "VERSION=3\ndb_pagesize=1000\nHEADER=END\nDATA=END\n",
+            "VERSION=3\ndb_pagesize=65536\nHEADER=END\nDATA=END\n",
+        ] {
+            assert!(read_dump(dump.as_bytes()).is_err(), "{:?}", dump);
+        }
+        assert!(read_dump("".as_bytes()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Name of the database file of an environment directory
+pub const DATA_NAME: &str = "data.mdb";
+
+/// Name of the lock file of an environment directory
+pub const LOCK_NAME: &str = "lock.mdb";
+
+/// Suffix of the lock file of a database opened with MDB_NOSUBDIR
+pub const LOCK_SUFFIX: &str = "-lock";
+
+/// The files of an LMDB environment: by default a directory holding
+/// `data.mdb` and `lock.mdb`, or with MDB_NOSUBDIR a database file and its
+/// `-lock` file next to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Environment {
+    pub data: PathBuf,
+    pub lock: PathBuf,
+    /// Whether the environment is a directory
+    pub subdir: bool,
+}
+
+impl Environment {
+    /// Find the files of the environment at `path`: a directory, its
+    /// `data.mdb` file, or a MDB_NOSUBDIR database file. A `data.mdb` file
+    /// is taken for the file of a directory unless a `data.mdb-lock` file
+    /// lies next to it.
+    pub fn resolve(path: &Path) -> Environment {
+        if path.is_dir() {
+            return Self::directory(path);
+        }
+        let nosubdir = Self::file(path);
+        match (path.file_name(), path.parent()) {
+            (Some(name), Some(dir)) if name == DATA_NAME && !nosubdir.lock.exists() => {
+                Self::directory(dir)
+            }
+            _ => nosubdir,
+        }
+    }
+
+    /// The environment in the directory `dir`.
+    pub fn directory(dir: &Path) -> Environment {
+        Environment {
+            data: dir.join(DATA_NAME),
+            lock: dir.join(LOCK_NAME),
+            subdir: true,
+        }
+    }
+
+    /// The environment of the MDB_NOSUBDIR database `data`.
+    pub fn file(data: &Path) -> Environment {
+        let mut lock = data.as_os_str().to_owned();
+        lock.push(LOCK_SUFFIX);
+        Environment {
+            data: data.into(),
+            lock: lock.into(),
+            subdir: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_file() {
+        let path = PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/mender-store.64bits"
+        ));
+        let env = Environment::resolve(&path);
+        assert!(!env.subdir);
+        assert_eq!(env.data, path);
+        assert_eq!(
+            env.lock,
+            PathBuf::from(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/resources/mender-store.64bits-lock"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_resolve_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let expected = Environment {
+            data: dir.path().join("data.mdb"),
+            lock: dir.path().join("lock.mdb"),
+            subdir: true,
+        };
+        assert_eq!(Environment::resolve(dir.path()), expected);
+
+        std::fs::write(dir.path().join("data.mdb"), b"").unwrap();
+        assert_eq!(Environment::resolve(&expected.data), expected);
+
+        // A MDB_NOSUBDIR database which happens to be named data.mdb
+        std::fs::write(dir.path().join("data.mdb-lock"), b"").unwrap();
+        let env = Environment::resolve(&expected.data);
+        assert!(!env.subdir);
+        assert_eq!(env.lock, dir.path().join("data.mdb-lock"));
+    }
+}
@@ -8,6 +8,10 @@ pub enum Error {
     InvalidPageHeader,
     VersionNotSupported,
     NoReader,
+    NoWriter,
+    InvalidKeyOrder,
+    InvalidPageSize,
+    DatabaseNotFound,
 }
 
 impl Context for Error {}
@@ -21,6 +25,10 @@ impl std::fmt::Display for Error {
             Error::InvalidPageHeader => write!(f, "Invalid page header"),
             Error::VersionNotSupported => write!(f, "Version not supported"),
             Error::NoReader => write!(f, "No reader"),
+            Error::NoWriter => write!(f, "No writer"),
+            Error::InvalidKeyOrder => write!(f, "Invalid key order"),
+            Error::InvalidPageSize => write!(f, "Invalid page size"),
+            Error::DatabaseNotFound => write!(f, "Database not found"),
         }
     }
 }
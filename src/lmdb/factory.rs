@@ -1,6 +1,8 @@
-use byteorder::ReadBytesExt;
-use byteorder::LE;
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
 use clap;
+use std::io::Read;
 use std::io::Seek;
 
 use error_stack::Report;
@@ -8,13 +10,18 @@ use error_stack::Result;
 use error_stack::ResultExt;
 
 use super::database::Database;
+use super::environment::Environment;
 use super::error::Error;
+use super::model::lowlevel;
 use super::reader;
+use super::salvage::Salvage;
 use super::writer;
 
 #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WordSize {
+    #[value(alias = "32")]
     Word32,
+    #[value(alias = "64")]
     Word64,
 }
 
@@ -28,61 +35,272 @@ impl From<String> for WordSize {
     }
 }
 
-impl Into<u8> for WordSize {
-    fn into(self) -> u8 {
-        match self {
+impl From<WordSize> for u8 {
+    fn from(s: WordSize) -> Self {
+        match s {
             WordSize::Word32 => 32,
             WordSize::Word64 => 64,
         }
     }
 }
 
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    /// Byte order of a `byteorder` type parameter.
+    pub fn of<E: ByteOrder>() -> Self {
+        if E::read_u16(&[0, 1]) == 1 {
+            Endianness::Big
+        } else {
+            Endianness::Little
+        }
+    }
+
+    /// Decode the words packed in `buf`, such as the keys and values of the
+    /// free list.
+    pub(crate) fn read_words(&self, buf: &[u8], word_size: usize) -> Vec<u64> {
+        buf.chunks_exact(word_size)
+            .map(|word| match self {
+                Endianness::Little => LittleEndian::read_uint(word, word_size),
+                Endianness::Big => BigEndian::read_uint(word, word_size),
+            })
+            .collect()
+    }
+
+    pub(crate) fn write_words(&self, words: &[u64], word_size: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; words.len() * word_size];
+        for (word, chunk) in words.iter().zip(buf.chunks_exact_mut(word_size)) {
+            match self {
+                Endianness::Little => LittleEndian::write_uint(chunk, *word, word_size),
+                Endianness::Big => BigEndian::write_uint(chunk, *word, word_size),
+            }
+        }
+        buf
+    }
+
+    pub(crate) fn read_uint(&self, buf: &[u8]) -> u64 {
+        match self {
+            Endianness::Little => LittleEndian::read_uint(buf, buf.len()),
+            Endianness::Big => BigEndian::read_uint(buf, buf.len()),
+        }
+    }
+
+    fn read_u16(&self, buf: &[u8]) -> u16 {
+        match self {
+            Endianness::Little => LittleEndian::read_u16(buf),
+            Endianness::Big => BigEndian::read_u16(buf),
+        }
+    }
+
+    pub(crate) fn read_u32(&self, buf: &[u8]) -> u32 {
+        match self {
+            Endianness::Little => LittleEndian::read_u32(buf),
+            Endianness::Big => BigEndian::read_u32(buf),
+        }
+    }
+}
+
 pub struct Factory;
 
 impl Factory {
     pub fn detect(database: std::path::PathBuf) -> Result<WordSize, Error> {
+        Ok(Self::detect_format(database)?.0)
+    }
+
+    pub fn detect_format(database: std::path::PathBuf) -> Result<(WordSize, Endianness), Error> {
+        let database = Environment::resolve(&database).data;
         let file = std::fs::File::open(database.clone()).change_context(Error::ReadError)?;
         let mut rdr = std::io::BufReader::new(file);
 
-        let pageno = rdr.read_u32::<LE>().change_context(Error::ReadError)?;
-        let pad = rdr.read_u16::<LE>().change_context(Error::ReadError)?;
-        let flags = rdr.read_u16::<LE>().change_context(Error::ReadError)?;
+        // The first meta page, or the second one if the first was torn
+        let offsets = lowlevel::page_sizes().map(|size| (1, size));
+        for (pageno, offset) in std::iter::once((0, 0)).chain(offsets) {
+            /* MDB_page header followed by the MDB_meta magic */
+            let mut buf = [0u8; 20];
+            rdr.seek(std::io::SeekFrom::Start(offset as u64))
+                .change_context(Error::ReadError)?;
+            if rdr.read_exact(&mut buf).is_err() {
+                break;
+            }
+
+            for (s, word) in [(WordSize::Word32, 4), (WordSize::Word64, 8)] {
+                for e in [Endianness::Little, Endianness::Big] {
+                    let pageno_ok = e.read_uint(&buf[0..word]) == pageno;
+                    let pad = e.read_u16(&buf[word..]);
+                    let flags = e.read_u16(&buf[word + 2..]);
+                    let magic = e.read_u32(&buf[word + 8..]);
+                    if pageno_ok && pad == 0 && flags == 0x8 && magic == lowlevel::MAGIC {
+                        return Ok((s, e));
+                    }
+                }
+            }
+        }
+
+        Err(Report::new(Error::InvalidFileFormat)
+            .attach_printable("Neither 32bits nor 64bits meta page found"))
+    }
+
+    /// Open a database file, or the `data.mdb` file of an environment
+    /// directory, see `Environment::resolve`.
+    pub fn open<'a>(database: std::path::PathBuf) -> Result<Database<'a>, Error> {
+        let database = Environment::resolve(&database).data;
+        let file = std::fs::File::open(database.clone()).change_context(Error::ReadError)?;
+        let rdr = std::io::BufReader::new(file);
 
-        if pageno == 0 && pad == 0 && flags == 0x8 {
-            return Ok(WordSize::Word32);
+        match Self::detect_format(database.clone())? {
+            (WordSize::Word32, Endianness::Little) => {
+                Database::from_reader::<reader::Reader32<_, LittleEndian>, _>(rdr)
+            }
+            (WordSize::Word64, Endianness::Little) => {
+                Database::from_reader::<reader::Reader64<_, LittleEndian>, _>(rdr)
+            }
+            (WordSize::Word32, Endianness::Big) => {
+                Database::from_reader::<reader::Reader32<_, BigEndian>, _>(rdr)
+            }
+            (WordSize::Word64, Endianness::Big) => {
+                Database::from_reader::<reader::Reader64<_, BigEndian>, _>(rdr)
+            }
         }
+    }
 
-        rdr.seek(std::io::SeekFrom::Start(0))
-            .change_context(Error::ReadError)?;
-        let pageno = rdr.read_u64::<LE>().change_context(Error::ReadError)?;
-        let pad = rdr.read_u16::<LE>().change_context(Error::ReadError)?;
-        let flags = rdr.read_u16::<LE>().change_context(Error::ReadError)?;
+    /// Open the snapshot of meta page `meta_id` (0 or 1) of a database,
+    /// even if the other meta page is newer.
+    pub fn open_meta<'a>(
+        database: std::path::PathBuf,
+        meta_id: usize,
+    ) -> Result<Database<'a>, Error> {
+        let database = Environment::resolve(&database).data;
+        let file = std::fs::File::open(database.clone()).change_context(Error::ReadError)?;
+        let rdr = std::io::BufReader::new(file);
 
-        if pageno == 0 && pad == 0 && flags == 0x8 {
-            return Ok(WordSize::Word64);
+        match Self::detect_format(database.clone())? {
+            (WordSize::Word32, Endianness::Little) => {
+                Database::from_reader_meta::<reader::Reader32<_, LittleEndian>, _>(rdr, meta_id)
+            }
+            (WordSize::Word64, Endianness::Little) => {
+                Database::from_reader_meta::<reader::Reader64<_, LittleEndian>, _>(rdr, meta_id)
+            }
+            (WordSize::Word32, Endianness::Big) => {
+                Database::from_reader_meta::<reader::Reader32<_, BigEndian>, _>(rdr, meta_id)
+            }
+            (WordSize::Word64, Endianness::Big) => {
+                Database::from_reader_meta::<reader::Reader64<_, BigEndian>, _>(rdr, meta_id)
+            }
         }
+    }
 
-        Err(Report::new(Error::InvalidFileFormat)
-            .attach_printable("Neither 32bits nor 64bits page header found"))
+    /// Recover what can be read of a damaged database, see
+    /// `Database::salvage_from`. Pages are `page_size` bytes long, by default
+    /// the size recorded in the first meta page.
+    pub fn salvage(
+        database: std::path::PathBuf,
+        page_size: Option<usize>,
+    ) -> Result<Salvage, Error> {
+        let database = Environment::resolve(&database).data;
+        let file = std::fs::File::open(database.clone()).change_context(Error::ReadError)?;
+        let rdr = std::io::BufReader::new(file);
+
+        match Self::detect_format(database.clone())? {
+            (WordSize::Word32, Endianness::Little) => {
+                Database::salvage_from(reader::Reader32::<_, LittleEndian>::from(rdr), page_size)
+            }
+            (WordSize::Word64, Endianness::Little) => {
+                Database::salvage_from(reader::Reader64::<_, LittleEndian>::from(rdr), page_size)
+            }
+            (WordSize::Word32, Endianness::Big) => {
+                Database::salvage_from(reader::Reader32::<_, BigEndian>::from(rdr), page_size)
+            }
+            (WordSize::Word64, Endianness::Big) => {
+                Database::salvage_from(reader::Reader64::<_, BigEndian>::from(rdr), page_size)
+            }
+        }
     }
 
-    pub fn open<'a>(database: std::path::PathBuf) -> Result<Database<'a>, Error> {
+    /// Open an existing database for in-place updates (`Database::put`,
+    /// `Database::del`).
+    pub fn open_rw<'a>(database: std::path::PathBuf) -> Result<Database<'a>, Error> {
+        let database = Environment::resolve(&database).data;
+        // Separate handles, a cloned one would share the file offset
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(database.clone())
+            .change_context(Error::WriteError)?;
+        let wtr = std::io::BufWriter::new(file);
         let file = std::fs::File::open(database.clone()).change_context(Error::ReadError)?;
         let rdr = std::io::BufReader::new(file);
 
-        match Self::detect(database.clone())? {
-            WordSize::Word32 => Database::from_reader::<reader::Reader32<_>, _>(rdr),
-            WordSize::Word64 => Database::from_reader::<reader::Reader64<_>, _>(rdr),
+        match Self::detect_format(database.clone())? {
+            (WordSize::Word32, Endianness::Little) => Database::from_reader_writer::<
+                reader::Reader32<_, LittleEndian>,
+                writer::Writer32<_, LittleEndian>,
+                _,
+                _,
+            >(rdr, wtr),
+            (WordSize::Word64, Endianness::Little) => Database::from_reader_writer::<
+                reader::Reader64<_, LittleEndian>,
+                writer::Writer64<_, LittleEndian>,
+                _,
+                _,
+            >(rdr, wtr),
+            (WordSize::Word32, Endianness::Big) => Database::from_reader_writer::<
+                reader::Reader32<_, BigEndian>,
+                writer::Writer32<_, BigEndian>,
+                _,
+                _,
+            >(rdr, wtr),
+            (WordSize::Word64, Endianness::Big) => Database::from_reader_writer::<
+                reader::Reader64<_, BigEndian>,
+                writer::Writer64<_, BigEndian>,
+                _,
+                _,
+            >(rdr, wtr),
         }
     }
 
     pub fn create<'a>(database: std::path::PathBuf, s: WordSize) -> Result<Database<'a>, Error> {
+        Self::create_with(database, s, Endianness::Little, lowlevel::DEFAULT_PAGE_SIZE)
+    }
+
+    /// Create a database file, or the `data.mdb` file of `database` when it
+    /// is an existing directory.
+    pub fn create_with<'a>(
+        database: std::path::PathBuf,
+        s: WordSize,
+        e: Endianness,
+        page_size: usize,
+    ) -> Result<Database<'a>, Error> {
+        let database = Environment::resolve(&database).data;
+        // Checked before the file is created, not to leave it empty
+        if !lowlevel::is_valid_page_size(page_size) {
+            return Err(
+                Report::new(Error::InvalidPageSize).attach_printable(format!(
+                    "page size must be a power of two between {} and {}, got {}",
+                    lowlevel::MIN_PAGE_SIZE,
+                    lowlevel::MAX_PAGE_SIZE,
+                    page_size
+                )),
+            );
+        }
         let file = std::fs::File::create(database.clone()).change_context(Error::WriteError)?;
         let wtr = std::io::BufWriter::new(file);
 
-        match s {
-            WordSize::Word32 => Database::from_writer::<writer::Writer32<_>, _>(wtr),
-            WordSize::Word64 => Database::from_writer::<writer::Writer64<_>, _>(wtr),
+        match (s, e) {
+            (WordSize::Word32, Endianness::Little) => {
+                Database::from_writer::<writer::Writer32<_, LittleEndian>, _>(wtr, page_size)
+            }
+            (WordSize::Word64, Endianness::Little) => {
+                Database::from_writer::<writer::Writer64<_, LittleEndian>, _>(wtr, page_size)
+            }
+            (WordSize::Word32, Endianness::Big) => {
+                Database::from_writer::<writer::Writer32<_, BigEndian>, _>(wtr, page_size)
+            }
+            (WordSize::Word64, Endianness::Big) => {
+                Database::from_writer::<writer::Writer64<_, BigEndian>, _>(wtr, page_size)
+            }
         }
     }
 }
@@ -107,4 +325,18 @@ mod tests {
         let size = Factory::detect(database).unwrap();
         assert_eq!(size, WordSize::Word64);
     }
+
+    #[test]
+    fn test_detect_endianness() {
+        let database = test_case!("mender-store.32bits");
+        let format = Factory::detect_format(database).unwrap();
+        assert_eq!(format, (WordSize::Word32, Endianness::Little));
+
+        for s in [WordSize::Word32, WordSize::Word64] {
+            let file = tempfile::NamedTempFile::new().unwrap();
+            Factory::create_with(file.path().into(), s, Endianness::Big, 4096).unwrap();
+            let format = Factory::detect_format(file.path().into()).unwrap();
+            assert_eq!(format, (s, Endianness::Big));
+        }
+    }
 }
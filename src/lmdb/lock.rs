@@ -0,0 +1,538 @@
+use error_stack::Report;
+use error_stack::Result;
+use error_stack::ResultExt;
+
+use super::environment::Environment;
+use super::error::Error;
+use super::Endianness;
+
+/// Magic number at the start of a lock file
+pub const LOCK_MAGIC: u32 = 0xBEEFC0DE;
+
+/// Shared fields and reader slots are each aligned on a cache line
+const CACHELINE: usize = 64;
+
+/// Mask of the owner thread in a robust glibc mutex word
+const FUTEX_TID_MASK: u32 = 0x3fffffff;
+
+/// Kind of the mutexes liblmdb creates: robust and shared between processes
+const MUTEX_ROBUST_SHARED: u32 = 0x90;
+
+/// Mask of the version in the lock file format. The upper bits of a version 2
+/// format hash the layout of the liblmdb build which wrote it.
+const LOCK_VERSION_MASK: u32 = 0xfff;
+
+/// Reader slots of a lock file created with the default `mdb_env_set_maxreaders`
+pub const DEFAULT_READERS: usize = 126;
+
+fn align(offset: usize, to: usize) -> usize {
+    offset.div_ceil(to) * to
+}
+
+/// Where the fields of the lock file (the MDB_txninfo structure of liblmdb)
+/// lie. It depends on the word size, on the size of `pthread_mutex_t` and on
+/// the liblmdb release which created it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub word_size: usize,
+    pub mutex_size: usize,
+    /// Older releases store the reader mutex before the transaction id
+    pub mutex_first: bool,
+}
+
+impl Layout {
+    /// The layouts of glibc on 32 bits, x86_64 and aarch64, for both orders.
+    pub fn known() -> impl Iterator<Item = Layout> {
+        [(4, 24), (8, 40), (8, 48)]
+            .into_iter()
+            .flat_map(|(word_size, mutex_size)| {
+                [true, false].map(|mutex_first| Layout {
+                    word_size,
+                    mutex_size,
+                    mutex_first,
+                })
+            })
+    }
+
+    /// The layout of a lock file for `word_size` bytes words: the one of
+    /// this host if it has the same word size, otherwise the one of aarch64
+    /// or of 32 bits glibc.
+    pub fn for_word_size(word_size: usize, mutex_first: bool) -> Layout {
+        let mutex_size = match word_size {
+            4 => 24,
+            _ if cfg!(target_arch = "x86_64") => 40,
+            _ => 48,
+        };
+        Layout {
+            word_size,
+            mutex_size,
+            mutex_first,
+        }
+    }
+
+    pub fn txnid_offset(&self) -> usize {
+        if self.mutex_first {
+            align(8 + self.mutex_size, self.word_size)
+        } else {
+            8
+        }
+    }
+
+    pub fn numreaders_offset(&self) -> usize {
+        self.txnid_offset() + self.word_size
+    }
+
+    /// Offset of the mutex taken to register a reader
+    pub fn reader_mutex_offset(&self) -> usize {
+        if self.mutex_first {
+            8
+        } else {
+            align(self.numreaders_offset() + 4, self.word_size)
+        }
+    }
+
+    /// Offset of the mutex held by the write transaction
+    pub fn writer_mutex_offset(&self) -> usize {
+        let end = if self.mutex_first {
+            self.numreaders_offset() + 4
+        } else {
+            self.reader_mutex_offset() + self.mutex_size
+        };
+        align(end, CACHELINE)
+    }
+
+    /// Offset of the first reader slot
+    pub fn readers_offset(&self) -> usize {
+        self.writer_mutex_offset() + align(self.mutex_size, CACHELINE)
+    }
+
+    pub fn reader_size(&self) -> usize {
+        CACHELINE
+    }
+
+    /// Offset of the thread id within a reader slot
+    fn tid_offset(&self) -> usize {
+        align(self.word_size + 4, self.word_size)
+    }
+
+    /// Transaction id of a reader slot outside of a transaction, -1
+    fn no_txnid(&self) -> u64 {
+        u64::MAX >> (64 - 8 * self.word_size)
+    }
+
+    /// Offset of the `__kind` field of a glibc mutex, the same for both
+    /// mutexes of a lock file (robust and shared, or -1 once destroyed).
+    fn kind_offset(&self) -> usize {
+        if self.word_size == 8 {
+            16
+        } else {
+            12
+        }
+    }
+}
+
+/// A slot of the reader table in use by a thread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reader {
+    pub slot: usize,
+    pub pid: u32,
+    pub tid: u64,
+    /// Snapshot read, none when the thread keeps its slot between transactions
+    pub txnid: Option<u64>,
+}
+
+impl Reader {
+    /// Whether the process owning the slot still runs on this host.
+    pub fn is_alive(&self) -> bool {
+        is_alive(self.pid)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_alive(pid: u32) -> bool {
+    std::path::Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Without a way to tell, every process is assumed to be running.
+#[cfg(not(target_os = "linux"))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+/// Content of the lock file shared by the processes using an environment.
+#[derive(Debug, Clone)]
+pub struct LockFile {
+    pub layout: Layout,
+    pub endianness: Endianness,
+    pub format: u32,
+    /// Last committed transaction
+    pub txnid: u64,
+    /// Reader slots ever used, free or not
+    pub numreaders: u32,
+    /// Thread holding the writer mutex
+    pub writer: Option<u32>,
+    pub readers: Vec<Reader>,
+}
+
+/// Path of the lock file of a database, see `Environment::resolve`.
+pub fn lock_path(database: &std::path::Path) -> std::path::PathBuf {
+    Environment::resolve(database).lock
+}
+
+impl LockFile {
+    pub fn read(path: &std::path::Path) -> Result<Self, Error> {
+        let buf = std::fs::read(path)
+            .change_context(Error::ReadError)
+            .attach_printable_lazy(|| format!("Cannot read lock file {:?}", path))?;
+        Self::parse(&buf)
+    }
+
+    /// Decode a lock file, guessing its byte order and layout.
+    pub fn parse(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < 8 {
+            return Err(Report::new(Error::InvalidFileFormat)
+                .attach_printable(format!("Lock file too short: {} bytes", buf.len())));
+        }
+        let endianness = [Endianness::Little, Endianness::Big]
+            .into_iter()
+            .find(|e| e.read_u32(&buf[0..4]) == LOCK_MAGIC)
+            .ok_or_else(|| {
+                Report::new(Error::InvalidFileFormat).attach_printable(format!(
+                    "Invalid lock file magic {:#x}",
+                    Endianness::Little.read_u32(&buf[0..4])
+                ))
+            })?;
+        let layout = Layout::known()
+            .find(|layout| Self::fits(buf, endianness, layout))
+            .ok_or_else(|| {
+                Report::new(Error::VersionNotSupported).attach_printable("Unknown lock file layout")
+            })?;
+        Ok(Self::parse_with(buf, endianness, layout))
+    }
+
+    /// Whether both mutexes of `layout` look alike and the reader table fits
+    /// in the file.
+    fn fits(buf: &[u8], e: Endianness, layout: &Layout) -> bool {
+        let readers = layout.readers_offset();
+        if buf.len() < readers {
+            return false;
+        }
+        let kind = |mutex: usize| {
+            let offset = mutex + layout.kind_offset();
+            e.read_u32(&buf[offset..offset + 4])
+        };
+        let numreaders = layout.numreaders_offset();
+        let numreaders = e.read_u32(&buf[numreaders..numreaders + 4]) as usize;
+        let reader_kind = kind(layout.reader_mutex_offset());
+        reader_kind != 0
+            && reader_kind == kind(layout.writer_mutex_offset())
+            && numreaders <= (buf.len() - readers) / layout.reader_size()
+    }
+
+    fn parse_with(buf: &[u8], e: Endianness, layout: Layout) -> Self {
+        let word = |offset: usize| e.read_uint(&buf[offset..offset + layout.word_size]);
+        let u32_at = |offset: usize| e.read_u32(&buf[offset..offset + 4]);
+        let no_txnid = layout.no_txnid();
+
+        let numreaders = u32_at(layout.numreaders_offset());
+        let readers = (0..numreaders as usize)
+            .filter_map(|slot| {
+                let offset = layout.readers_offset() + slot * layout.reader_size();
+                let pid = u32_at(offset + layout.word_size);
+                // Released by a thread which exited
+                if pid == 0 {
+                    return None;
+                }
+                let txnid = word(offset);
+                Some(Reader {
+                    slot,
+                    pid,
+                    tid: word(offset + layout.tid_offset()),
+                    txnid: (txnid != no_txnid).then_some(txnid),
+                })
+            })
+            .collect();
+
+        let writer = u32_at(layout.writer_mutex_offset()) & FUTEX_TID_MASK;
+        LockFile {
+            layout,
+            endianness: e,
+            format: u32_at(4),
+            txnid: word(layout.txnid_offset()),
+            numreaders,
+            writer: (writer != 0).then_some(writer),
+            readers,
+        }
+    }
+
+    /// An empty lock file for a database of `word_size` bytes words, as
+    /// liblmdb would create it. The format is kept for the same word size;
+    /// otherwise its layout hash cannot be known and only the version is
+    /// kept. liblmdb writes the lock file anew, format included, when the
+    /// first process opens the environment.
+    pub fn reset(&self, word_size: usize, endianness: Endianness, txnid: u64) -> LockFile {
+        let (layout, format) = if word_size == self.layout.word_size {
+            (self.layout, self.format)
+        } else {
+            (
+                Layout::for_word_size(word_size, self.layout.mutex_first),
+                self.format & LOCK_VERSION_MASK,
+            )
+        };
+        LockFile {
+            layout,
+            endianness,
+            format,
+            txnid,
+            numreaders: 0,
+            writer: None,
+            readers: vec![],
+        }
+    }
+
+    /// Encode the lock file, with at least `DEFAULT_READERS` reader slots.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let layout = self.layout;
+        let slots = DEFAULT_READERS.max(self.numreaders as usize);
+        let mut buf = vec![0u8; layout.readers_offset() + slots * layout.reader_size()];
+        let mut put = |offset: usize, value: u64, size: usize| {
+            buf[offset..offset + size]
+                .copy_from_slice(&self.endianness.write_words(&[value], size));
+        };
+
+        put(0, LOCK_MAGIC as u64, 4);
+        put(4, self.format as u64, 4);
+        put(layout.txnid_offset(), self.txnid, layout.word_size);
+        put(layout.numreaders_offset(), self.numreaders as u64, 4);
+        for mutex in [layout.reader_mutex_offset(), layout.writer_mutex_offset()] {
+            put(mutex + layout.kind_offset(), MUTEX_ROBUST_SHARED as u64, 4);
+        }
+        if let Some(tid) = self.writer {
+            put(layout.writer_mutex_offset(), tid as u64, 4);
+        }
+        for reader in &self.readers {
+            let offset = layout.readers_offset() + reader.slot * layout.reader_size();
+            let txnid = reader.txnid.unwrap_or(layout.no_txnid());
+            put(offset, txnid, layout.word_size);
+            put(offset + layout.word_size, reader.pid as u64, 4);
+            put(offset + layout.tid_offset(), reader.tid, layout.word_size);
+        }
+        buf
+    }
+
+    pub fn write(&self, path: &std::path::Path) -> Result<(), Error> {
+        std::fs::write(path, self.to_bytes())
+            .change_context(Error::WriteError)
+            .attach_printable_lazy(|| format!("Cannot write lock file {:?}", path))
+    }
+
+    /// Readers whose process still runs on this host.
+    pub fn live_readers(&self) -> impl Iterator<Item = &Reader> {
+        self.readers.iter().filter(|reader| reader.is_alive())
+    }
+
+    /// Readers left behind by a process which exited without closing the
+    /// environment.
+    pub fn stale_readers(&self) -> impl Iterator<Item = &Reader> {
+        self.readers.iter().filter(|reader| !reader.is_alive())
+    }
+
+    /// Whether a live thread holds the writer mutex. The thread id of a
+    /// Linux thread is also a valid process id in `/proc`.
+    pub fn has_live_writer(&self) -> bool {
+        self.writer.is_some_and(is_alive)
+    }
+
+    /// Whether a process is using the environment.
+    pub fn is_busy(&self) -> bool {
+        self.has_live_writer() || self.live_readers().next().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! test_case {
+        ($fname:expr) => {
+            std::path::PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/", $fname))
+        };
+    }
+
+    #[test]
+    fn test_lock_resources_64() {
+        for (path, mutex_first, format, txnid) in [
+            (
+                test_case!("mender-store.64bits.json-error-lock"),
+                true,
+                0x00010001,
+                7,
+            ),
+            (
+                test_case!("mender-store.64bits.after-mender-launch-lock"),
+                true,
+                0x00010001,
+                2,
+            ),
+            (
+                test_case!("mender-store.64bits.before-mender-launch-lock"),
+                false,
+                0x2cd82002,
+                1,
+            ),
+        ] {
+            let name = path.display();
+            let lock = LockFile::read(&path).unwrap();
+            assert_eq!(
+                lock.layout,
+                Layout {
+                    word_size: 8,
+                    mutex_size: 48,
+                    mutex_first
+                },
+                "{}",
+                name
+            );
+            assert_eq!(lock.layout.readers_offset(), 0xc0, "{}", name);
+            assert_eq!(lock.endianness, Endianness::Little, "{}", name);
+            assert_eq!(lock.format, format, "{}", name);
+            assert_eq!(lock.txnid, txnid, "{}", name);
+            // The single reader slot was released when mender exited
+            assert_eq!(lock.numreaders, 1, "{}", name);
+            assert!(lock.readers.is_empty(), "{}", name);
+            assert_eq!(lock.writer, None, "{}", name);
+            assert!(!lock.is_busy(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_lock_readers_32() {
+        let layout = Layout {
+            word_size: 4,
+            mutex_size: 24,
+            mutex_first: false,
+        };
+        let mut buf = vec![0u8; layout.readers_offset() + 4 * layout.reader_size()];
+        let mut put = |offset: usize, value: u32| {
+            buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        put(0, LOCK_MAGIC);
+        put(4, 0x2cd82002);
+        put(layout.txnid_offset(), 5);
+        put(layout.numreaders_offset(), 3);
+        put(layout.reader_mutex_offset() + 12, 0x90);
+        put(layout.writer_mutex_offset() + 12, 0x90);
+
+        // A live reader in a transaction, a released slot and a stale reader
+        let slot = |n: usize| layout.readers_offset() + n * layout.reader_size();
+        put(slot(0), 5);
+        put(slot(0) + 4, std::process::id());
+        put(slot(0) + 8, 0x1234);
+        put(slot(1), u32::MAX);
+        put(slot(2), u32::MAX);
+        put(slot(2) + 4, 0x3fffffff);
+
+        let lock = LockFile::parse(&buf).unwrap();
+        assert_eq!(lock.layout, layout);
+        assert_eq!(lock.txnid, 5);
+        assert_eq!(lock.numreaders, 3);
+        assert_eq!(
+            lock.readers,
+            vec![
+                Reader {
+                    slot: 0,
+                    pid: std::process::id(),
+                    tid: 0x1234,
+                    txnid: Some(5),
+                },
+                Reader {
+                    slot: 2,
+                    pid: 0x3fffffff,
+                    tid: 0,
+                    txnid: None,
+                },
+            ]
+        );
+        assert_eq!(lock.live_readers().count(), 1);
+        assert_eq!(lock.stale_readers().count(), 1);
+        assert!(lock.is_busy());
+    }
+
+    #[test]
+    fn test_lock_reset_32() {
+        let lock = LockFile::read(&test_case!("mender-store.64bits.json-error-lock")).unwrap();
+
+        // Same word size, same layout and size
+        let same = LockFile::parse(&lock.reset(8, Endianness::Little, 7).to_bytes()).unwrap();
+        assert_eq!(same.layout, lock.layout);
+        assert_eq!(same.format, lock.format);
+        assert_eq!(same.to_bytes().len(), 8256);
+
+        let reset = lock.reset(4, Endianness::Little, 1);
+        let buf = reset.to_bytes();
+        assert_eq!(buf.len(), 128 + DEFAULT_READERS * 64);
+        let parsed = LockFile::parse(&buf).unwrap();
+        assert_eq!(
+            parsed.layout,
+            Layout {
+                word_size: 4,
+                mutex_size: 24,
+                mutex_first: true
+            }
+        );
+        assert_eq!(parsed.format, 0x001);
+        assert_eq!(parsed.txnid, 1);
+        assert_eq!(parsed.numreaders, 0);
+        assert!(parsed.readers.is_empty());
+
+        // The layout hash of a version 2 format is dropped
+        let lock =
+            LockFile::read(&test_case!("mender-store.64bits.before-mender-launch-lock")).unwrap();
+        assert_eq!(lock.reset(8, Endianness::Little, 1).format, 0x2cd82002);
+        assert_eq!(lock.reset(4, Endianness::Little, 1).format, 0x002);
+    }
+
+    #[test]
+    fn test_lock_roundtrip_64() {
+        let layout = Layout {
+            word_size: 8,
+            mutex_size: 40,
+            mutex_first: false,
+        };
+        let lock = LockFile {
+            layout,
+            endianness: Endianness::Big,
+            format: 0x2cd82002,
+            txnid: 12,
+            numreaders: 2,
+            writer: Some(4242),
+            readers: vec![Reader {
+                slot: 1,
+                pid: 4242,
+                tid: 0x7f00_0000_1000,
+                txnid: Some(11),
+            }],
+        };
+        let parsed = LockFile::parse(&lock.to_bytes()).unwrap();
+        assert_eq!(parsed.layout, layout);
+        assert_eq!(parsed.endianness, Endianness::Big);
+        assert_eq!(parsed.txnid, 12);
+        assert_eq!(parsed.writer, Some(4242));
+        assert_eq!(parsed.readers, lock.readers);
+    }
+
+    #[test]
+    fn test_lock_invalid() {
+        let buf = std::fs::read(test_case!("mender-store.64bits")).unwrap();
+        assert!(LockFile::parse(&buf).is_err());
+        assert!(LockFile::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn test_lock_path() {
+        assert_eq!(
+            lock_path(std::path::Path::new("/data/mender-store")),
+            std::path::PathBuf::from("/data/mender-store-lock")
+        );
+    }
+}
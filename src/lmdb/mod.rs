@@ -1,17 +1,24 @@
 pub mod dump;
 mod factory;
+pub use factory::Endianness;
 pub use factory::Factory;
 pub use factory::WordSize;
 
 pub mod error;
 
+pub mod check;
 pub mod database;
 mod database_lowlevel;
 mod database_lowlevel_read;
 mod database_lowlevel_write;
+mod database_update;
+pub mod environment;
+pub mod lock;
 pub mod reader;
+pub mod salvage;
 pub mod writer;
 
+pub mod compare;
 pub mod cursor;
 
 pub mod model;
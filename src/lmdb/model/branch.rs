@@ -0,0 +1,32 @@
+use core::fmt;
+
+use super::header::Flags;
+
+#[derive(Clone)]
+pub struct BranchNode {
+    pub key: Vec<u8>,
+    pub pageno: u64,
+}
+
+impl fmt::Debug for BranchNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let key_s: String = self.key.iter().map(|&c| c as char).collect();
+        f.debug_struct("BranchNode")
+            .field("key", &key_s)
+            .field("child-page", &self.pageno)
+            .finish()
+    }
+}
+
+impl BranchNode {
+    pub fn size(&self) -> usize {
+        4 /* pageno */ + 2 /* pageno (high) */ + 2 /* key */ + self.key.len()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Branch {
+    pub pageno: usize,
+    pub flags: Flags,
+    pub nodes: Vec<BranchNode>,
+}
@@ -2,6 +2,8 @@ use bitflags::bitflags;
 use core::fmt;
 
 use super::header::Flags;
+use super::lowlevel;
+use super::metadata::Database;
 
 bitflags! {
     #[repr(transparent)]
@@ -17,6 +19,8 @@ bitflags! {
 pub enum NodeData {
     Data(Vec<u8>),
     Overflow(u64, usize),
+    SubData(Database),
+    SubPage(Leaf),
 }
 
 #[derive(Clone)]
@@ -45,19 +49,42 @@ impl fmt::Debug for Node {
                 .field("overflow-page", &overflow)
                 .field("data-size", &size)
                 .finish(),
+            NodeData::SubData(ref db) => f
+                .debug_struct("SubDataNode")
+                .field("flags", &self.flags)
+                .field("key", &key_s)
+                .field("db", db)
+                .finish(),
+            NodeData::SubPage(ref leaf) => f
+                .debug_struct("SubPageNode")
+                .field("flags", &self.flags)
+                .field("key", &key_s)
+                .field("page", leaf)
+                .finish(),
         }
     }
 }
 
 impl Node {
-    pub fn size(&self) -> usize {
+    pub fn size(&self, word_size: usize) -> usize {
         let data_len = match self.data {
             NodeData::Data(ref data) => data.len(),
-            NodeData::Overflow(_, _) => 4, // FIXME: wordsize
+            NodeData::Overflow(_, _) => word_size,
+            NodeData::SubData(_) => lowlevel::db_size(word_size),
+            NodeData::SubPage(ref leaf) => leaf.sub_page_size(word_size),
         };
         4 /* data_len */ + 2 /* flags */ + 2 /* key */
             + self.key.len() + data_len
     }
+
+    /// Number of records held by the node, counting every duplicate.
+    pub fn entries(&self) -> u64 {
+        match self.data {
+            NodeData::SubData(ref db) if self.flags.contains(NodeFlags::DUPDATA) => db.entries,
+            NodeData::SubPage(ref leaf) => leaf.nodes.len() as u64,
+            _ => 1,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -67,6 +94,21 @@ pub struct Leaf {
     pub nodes: Vec<Node>,
 }
 
+impl Leaf {
+    /// Size of the leaf stored as a sub-page in a node, without free space.
+    pub fn sub_page_size(&self, word_size: usize) -> usize {
+        let nodes = if self.flags.contains(Flags::LEAF2) {
+            self.nodes.iter().map(|node| node.key.len()).sum::<usize>()
+        } else {
+            self.nodes
+                .iter()
+                .map(|node| 2 + lowlevel::even(node.size(word_size)))
+                .sum::<usize>()
+        };
+        lowlevel::page_header_size(word_size) + nodes
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Overflow {
     pub pageno: u64,
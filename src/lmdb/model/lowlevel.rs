@@ -1,3 +1,66 @@
 pub const MAGIC: u32 = 0xBEEFC0DE;
 
 pub const VERSION: u32 = 1;
+
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+
+pub const MIN_PAGE_SIZE: usize = 512;
+
+/// LMDB caps pages to 32KiB (`MAX_PAGESIZE`), page offsets being 16 bits.
+pub const MAX_PAGE_SIZE: usize = 0x8000;
+
+/// Largest key LMDB accepts with its default settings
+/// (`mdb_env_get_maxkeysize`).
+pub const MAX_KEY_SIZE: usize = 511;
+
+/// Size of a node header: `mn_lo`, `mn_hi`, `mn_flags` and `mn_ksize`.
+pub const NODE_SIZE: usize = 8;
+
+/// Size of an `MDB_db` record, as stored in the meta page or as the value of
+/// a named database node.
+pub fn db_size(word_size: usize) -> usize {
+    4 + 2 + 2 + 5 * word_size
+}
+
+/// Size of the `MDB_page` header, without the pointer array.
+pub fn page_header_size(word_size: usize) -> usize {
+    word_size + 2 + 2 + 2 + 2
+}
+
+/// Nodes are kept on 2-bytes boundaries within a page.
+pub fn even(n: usize) -> usize {
+    (n + 1) & !1
+}
+
+/// Largest node stored inline in a leaf, bigger values are moved to
+/// overflow pages (`me_nodemax`).
+pub fn node_max(word_size: usize, page_size: usize) -> usize {
+    (((page_size - page_header_size(word_size)) / 2) & !1) - 2
+}
+
+/// Largest key of a `page_size` database: its node must fit inline along
+/// with the largest fixed-size data, an `MDB_db` record of a sub-database,
+/// and LMDB caps it to `MAX_KEY_SIZE` anyway.
+pub fn key_max(word_size: usize, page_size: usize) -> usize {
+    std::cmp::min(
+        MAX_KEY_SIZE,
+        node_max(word_size, page_size) - NODE_SIZE - db_size(word_size),
+    )
+}
+
+/// Number of contiguous pages needed to store `size` bytes of overflow data
+/// after the page header (`OVPAGES`).
+pub fn overflow_pages(word_size: usize, page_size: usize, size: usize) -> usize {
+    (page_header_size(word_size) - 1 + size) / page_size + 1
+}
+
+pub fn is_valid_page_size(page_size: usize) -> bool {
+    page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+}
+
+/// Every valid page size, in ascending order.
+pub fn page_sizes() -> impl Iterator<Item = usize> {
+    (0..)
+        .map(|shift| MIN_PAGE_SIZE << shift)
+        .take_while(|&size| size <= MAX_PAGE_SIZE)
+}
@@ -13,6 +13,21 @@ bitflags! {
     }
 }
 
+impl Flags {
+    /// Flags of the sub-database holding the duplicates of a `DUPSORT` key,
+    /// whose keys are the values of the parent tree.
+    pub fn dup_flags(self) -> Flags {
+        let mut flags = self & Flags::DUPFIXED;
+        if self.contains(Flags::INTEGERDUP) {
+            flags |= Flags::INTEGERKEY;
+        }
+        if self.contains(Flags::REVERSEDUP) {
+            flags |= Flags::REVERSEKEY;
+        }
+        flags
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Metadata {
     pub magic: u32,
@@ -14,5 +14,12 @@ pub use leaf::NodeData;
 pub use leaf::NodeFlags;
 pub use leaf::Overflow;
 
+mod branch;
+pub use branch::Branch;
+pub use branch::BranchNode;
+
+mod page;
+pub use page::Page;
+
 mod element;
 pub use element::Element;
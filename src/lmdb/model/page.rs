@@ -0,0 +1,17 @@
+use super::Branch;
+use super::Leaf;
+
+#[derive(Debug, Clone)]
+pub enum Page {
+    Branch(Branch),
+    Leaf(Leaf),
+}
+
+impl Page {
+    pub fn pageno(&self) -> usize {
+        match self {
+            Page::Branch(branch) => branch.pageno,
+            Page::Leaf(leaf) => leaf.pageno,
+        }
+    }
+}
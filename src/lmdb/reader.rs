@@ -1,4 +1,6 @@
-use byteorder;
+use std::marker::PhantomData;
+
+use byteorder::ByteOrder;
 use byteorder::LittleEndian;
 
 use error_stack::Result;
@@ -6,28 +8,44 @@ use error_stack::ResultExt;
 
 use super::database::DatabaseReader;
 use super::error::Error;
+use super::Endianness;
 
 #[derive(Debug)]
-pub struct Reader32<R>
+pub struct Reader32<R, E = LittleEndian>
 where
     R: byteorder::ReadBytesExt + std::io::Seek,
+    E: ByteOrder,
 {
     reader: R,
+    endianness: PhantomData<E>,
 }
 
-impl<R> From<R> for Reader32<R>
+impl<R, E> From<R> for Reader32<R, E>
 where
     R: byteorder::ReadBytesExt + std::io::Seek,
+    E: ByteOrder,
 {
     fn from(reader: R) -> Self {
-        Self { reader }
+        Self {
+            reader,
+            endianness: PhantomData,
+        }
     }
 }
 
-impl<R> DatabaseReader for Reader32<R>
+impl<R, E> DatabaseReader for Reader32<R, E>
 where
     R: byteorder::ReadBytesExt + std::io::Seek,
+    E: ByteOrder,
 {
+    fn word_size(&self) -> usize {
+        4
+    }
+
+    fn endianness(&self) -> Endianness {
+        Endianness::of::<E>()
+    }
+
     fn seek(&mut self, pos: std::io::SeekFrom) -> Result<usize, Error> {
         Ok(self.reader.seek(pos).change_context(Error::ReadError)? as usize)
     }
@@ -35,14 +53,14 @@ where
     fn read_word(&mut self) -> Result<u64, Error> {
         Ok(self
             .reader
-            .read_u32::<LittleEndian>()
+            .read_u32::<E>()
             .change_context(Error::ReadError)? as u64)
     }
 
     fn read_opt_word(&mut self) -> Result<Option<u64>, Error> {
         let n = self
             .reader
-            .read_i32::<LittleEndian>()
+            .read_i32::<E>()
             .change_context(Error::ReadError)?;
         if n < 0 {
             Ok(None)
@@ -52,15 +70,11 @@ where
     }
 
     fn read_u16(&mut self) -> Result<u16, Error> {
-        self.reader
-            .read_u16::<LittleEndian>()
-            .change_context(Error::ReadError)
+        self.reader.read_u16::<E>().change_context(Error::ReadError)
     }
 
     fn read_u32(&mut self) -> Result<u32, Error> {
-        self.reader
-            .read_u32::<LittleEndian>()
-            .change_context(Error::ReadError)
+        self.reader.read_u32::<E>().change_context(Error::ReadError)
     }
 
     fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
@@ -68,40 +82,53 @@ where
     }
 }
 
-pub struct Reader64<R>
+pub struct Reader64<R, E = LittleEndian>
 where
     R: byteorder::ReadBytesExt + std::io::Seek,
+    E: ByteOrder,
 {
     reader: R,
+    endianness: PhantomData<E>,
 }
 
-impl<R> From<R> for Reader64<R>
+impl<R, E> From<R> for Reader64<R, E>
 where
     R: byteorder::ReadBytesExt + std::io::Seek,
+    E: ByteOrder,
 {
     fn from(reader: R) -> Self {
-        Self { reader }
+        Self {
+            reader,
+            endianness: PhantomData,
+        }
     }
 }
 
-impl<R> DatabaseReader for Reader64<R>
+impl<R, E> DatabaseReader for Reader64<R, E>
 where
     R: byteorder::ReadBytesExt + std::io::Seek,
+    E: ByteOrder,
 {
+    fn word_size(&self) -> usize {
+        8
+    }
+
+    fn endianness(&self) -> Endianness {
+        Endianness::of::<E>()
+    }
+
     fn seek(&mut self, pos: std::io::SeekFrom) -> Result<usize, Error> {
         Ok(self.reader.seek(pos).change_context(Error::ReadError)? as usize)
     }
 
     fn read_word(&mut self) -> Result<u64, Error> {
-        self.reader
-            .read_u64::<LittleEndian>()
-            .change_context(Error::ReadError)
+        self.reader.read_u64::<E>().change_context(Error::ReadError)
     }
 
     fn read_opt_word(&mut self) -> Result<Option<u64>, Error> {
         let n = self
             .reader
-            .read_i64::<LittleEndian>()
+            .read_i64::<E>()
             .change_context(Error::ReadError)?;
         if n < 0 {
             Ok(None)
@@ -111,15 +138,11 @@ where
     }
 
     fn read_u16(&mut self) -> Result<u16, Error> {
-        self.reader
-            .read_u16::<LittleEndian>()
-            .change_context(Error::ReadError)
+        self.reader.read_u16::<E>().change_context(Error::ReadError)
     }
 
     fn read_u32(&mut self) -> Result<u32, Error> {
-        self.reader
-            .read_u32::<LittleEndian>()
-            .change_context(Error::ReadError)
+        self.reader.read_u32::<E>().change_context(Error::ReadError)
     }
 
     fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use error_stack::Result;
+
+use super::compare;
+use super::database::Database;
+use super::database::DatabaseReader;
+use super::error::Error;
+use super::model;
+use super::model::lowlevel;
+use super::Endianness;
+
+/// Records of one tree recovered by `Database::salvage_from`.
+#[derive(Debug, Clone)]
+pub struct SalvagedDatabase {
+    /// Name of the database, `None` for the main one
+    pub name: Option<Vec<u8>>,
+    pub flags: model::metadata::Flags,
+    /// Records in key order
+    pub elements: Vec<model::Element>,
+}
+
+/// Outcome of `Database::salvage_from`.
+#[derive(Debug, Clone, Default)]
+pub struct Salvage {
+    pub page_size: usize,
+    /// Pages scanned, meta pages included
+    pub pages: u64,
+    /// Leaf pages records were taken from
+    pub leaves: u64,
+    /// Records left out: values whose overflow pages do not validate, and
+    /// keys whose tree of duplicates has no readable leaf
+    pub skipped: u64,
+    /// The main database first, then the named ones
+    pub databases: Vec<SalvagedDatabase>,
+}
+
+impl Salvage {
+    /// Write the recovered records into `out`, a database just created.
+    pub fn write_into(self, out: &mut Database<'_>) -> Result<(), Error> {
+        let mut main = None;
+        for db in self.databases {
+            match db.name {
+                Some(name) => {
+                    let mut cur = out.write_cursor_named(&name, db.flags)?;
+                    for element in db.elements {
+                        cur.push_element(element)?;
+                    }
+                    cur.commit()?;
+                }
+                None => main = Some(db),
+            }
+        }
+
+        if let Some(main) = &main {
+            out.meta.main.flags = main.flags;
+        }
+        let mut cur = out.write_cursor()?;
+        for element in main.into_iter().flat_map(|main| main.elements) {
+            cur.push_element(element)?;
+        }
+        cur.commit()
+    }
+}
+
+/// Tree a page was found in while walking from a meta page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Owner {
+    Main,
+    Named(Vec<u8>),
+    Free,
+    /// Duplicates of a key of the main (`None`) or a named database
+    Dups(Option<Vec<u8>>, Vec<u8>),
+}
+
+/// Values of a key found on a leaf page.
+#[derive(Debug, Clone)]
+enum Values {
+    Inline(Vec<Vec<u8>>),
+    /// In a tree of duplicates, salvaged from its own pages
+    Tree,
+}
+
+/// Pages reachable from the meta pages, with the `txnid` of the newest meta
+/// page reaching them.
+struct Reach {
+    owners: HashMap<u64, (Owner, u64)>,
+    /// Pages recorded in a free list, whose records are stale
+    freed: HashSet<u64>,
+    flags: HashMap<Vec<u8>, model::metadata::Flags>,
+    pages: u64,
+    page_size: usize,
+}
+
+impl Reach {
+    /// Walk a tree as far as it can be read, ignoring broken pages.
+    fn walk<'a>(
+        &mut self,
+        reader: &mut (dyn DatabaseReader + 'a),
+        root: Option<u64>,
+        owner: Owner,
+        txnid: u64,
+    ) {
+        let mut seen = HashSet::new();
+        let mut stack = Vec::from_iter(root);
+        while let Some(pageno) = stack.pop() {
+            if pageno < 2 || pageno >= self.pages || !seen.insert(pageno) {
+                continue;
+            }
+            let page = Database::seek_page_unsafe(reader, pageno as usize, self.page_size)
+                .and_then(|_| Database::read_page_unsafe(reader));
+            let page = match page {
+                Ok(page) if page.pageno() as u64 == pageno => page,
+                _ => continue,
+            };
+            // Reachable pages rank above all others, even from transaction 0
+            self.owners.insert(pageno, (owner.clone(), txnid + 1));
+            match page {
+                model::Page::Branch(branch) => {
+                    stack.extend(branch.nodes.iter().map(|node| node.pageno));
+                }
+                model::Page::Leaf(leaf) if owner == Owner::Free => {
+                    let (word_size, endianness) = (reader.word_size(), reader.endianness());
+                    for node in leaf.nodes {
+                        if let model::NodeData::Data(value) = node.data {
+                            let words = endianness.read_words(&value, word_size);
+                            self.freed.extend(words.iter().skip(1));
+                        }
+                    }
+                }
+                model::Page::Leaf(leaf) => {
+                    let name = match &owner {
+                        Owner::Main => None,
+                        Owner::Named(name) => Some(name.clone()),
+                        _ => continue,
+                    };
+                    for node in leaf.nodes {
+                        if let model::NodeData::SubData(db) = node.data {
+                            if node.flags.contains(model::NodeFlags::DUPDATA) {
+                                let dups = Owner::Dups(name.clone(), node.key);
+                                self.walk(reader, db.root, dups, txnid);
+                            } else if owner == Owner::Main {
+                                self.flags.insert(node.key.clone(), db.flags);
+                                self.walk(reader, db.root, Owner::Named(node.key), txnid);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Leaves of the free list hold word-sized keys and lists of page numbers.
+fn is_freelist_leaf(leaf: &model::Leaf, word_size: usize, endianness: Endianness) -> bool {
+    !leaf.nodes.is_empty()
+        && leaf.nodes.iter().all(|node| match &node.data {
+            model::NodeData::Data(value)
+                if node.key.len() == word_size
+                    && !value.is_empty()
+                    && value.len() % word_size == 0 =>
+            {
+                let words = endianness.read_words(value, word_size);
+                words[0] == words.len() as u64 - 1
+            }
+            _ => false,
+        })
+}
+
+impl<'a> Database<'a> {
+    /// Recover the records of a database too damaged to be opened or walked.
+    /// Every page is read on its own: the leaf pages whose header holds
+    /// their own page number give their records, overflow values being kept
+    /// when their pages validate. A key found on several pages keeps the
+    /// value of the page reachable from the newest meta page, then of the
+    /// highest page. Duplicates are taken from the sub-page of their key, or
+    /// from the leaves of its sub-tree. Leaves not reachable from any meta page are taken for
+    /// the main database, unless they are listed in a free list or look like
+    /// free list pages.
+    pub fn salvage_from<DR>(mut reader: DR, page_size: Option<usize>) -> Result<Salvage, Error>
+    where
+        DR: DatabaseReader + 'a,
+    {
+        let reader: &mut (dyn DatabaseReader + 'a) = &mut reader;
+        let word_size = reader.word_size();
+        let endianness = reader.endianness();
+
+        let mut metas = Vec::new();
+        Self::seek_page_unsafe(reader, 0, 0)?;
+        if let Ok(meta) = Self::read_meta_unsafe(reader) {
+            metas.push(meta);
+        }
+        let page_size = page_size
+            .or_else(|| metas.first().map(|meta| meta.free.pad as usize))
+            .filter(|&size| lowlevel::is_valid_page_size(size))
+            .unwrap_or(lowlevel::DEFAULT_PAGE_SIZE);
+        Self::seek_page_unsafe(reader, 1, page_size)?;
+        if let Ok(meta) = Self::read_meta_unsafe(reader) {
+            metas.push(meta);
+        }
+        metas.sort_by_key(|meta| meta.txnid);
+
+        let pages = (reader.seek(std::io::SeekFrom::End(0))? / page_size) as u64;
+        let mut reach = Reach {
+            owners: HashMap::new(),
+            freed: HashSet::new(),
+            flags: HashMap::new(),
+            pages,
+            page_size,
+        };
+        for meta in &metas {
+            reach.walk(reader, meta.free.root, Owner::Free, meta.txnid);
+            reach.walk(reader, meta.main.root, Owner::Main, meta.txnid);
+        }
+
+        let mut salvage = Salvage {
+            page_size,
+            pages,
+            ..Default::default()
+        };
+        // Best candidate for each key: rank, page and values
+        let mut found = HashMap::<Option<Vec<u8>>, HashMap<Vec<u8>, (u64, u64, Values)>>::new();
+        // Duplicates read from sub-tree leaves, with the rank of their page
+        let mut dups = HashMap::<(Option<Vec<u8>>, Vec<u8>), Vec<(u64, Vec<u8>)>>::new();
+        for pageno in 2..pages {
+            Self::seek_page_unsafe(reader, pageno as usize, page_size)?;
+            let header = match Self::read_page_header2_unsafe(reader) {
+                Ok(header) => header,
+                Err(_) => continue,
+            };
+            if header.pageno != pageno || !header.flags.contains(model::header::Flags::LEAF) {
+                continue;
+            }
+            let (owner, rank) = match reach.owners.get(&pageno) {
+                Some(owner) => owner.clone(),
+                None if reach.freed.contains(&pageno) => continue,
+                // Only trees of duplicates have LEAF2 pages
+                None if header.flags.contains(model::header::Flags::LEAF2) => continue,
+                None => (Owner::Main, 0),
+            };
+
+            Self::seek_page_unsafe(reader, pageno as usize, page_size)?;
+            let leaf = match Self::read_leaf_unsafe(reader) {
+                Ok(leaf) => leaf,
+                Err(e) => {
+                    tracing::warn!("Skipping page {}: {:?}", pageno, e);
+                    continue;
+                }
+            };
+            let name = match owner {
+                Owner::Main => None,
+                Owner::Named(name) => Some(name),
+                Owner::Free => continue,
+                Owner::Dups(name, key) => {
+                    salvage.leaves += 1;
+                    let values = dups.entry((name, key)).or_default();
+                    values.extend(leaf.nodes.into_iter().map(|node| (rank, node.key)));
+                    continue;
+                }
+            };
+            if rank == 0 && is_freelist_leaf(&leaf, word_size, endianness) {
+                continue;
+            }
+            salvage.leaves += 1;
+
+            let records = found.entry(name.clone()).or_default();
+            for node in leaf.nodes {
+                let values = match node.data {
+                    model::NodeData::Data(value) => Values::Inline(vec![value]),
+                    model::NodeData::Overflow(overflow, size) => {
+                        let count = lowlevel::overflow_pages(word_size, page_size, size) as u64;
+                        let value = if overflow >= 2 && overflow + count <= pages {
+                            Self::seek_page_unsafe(reader, overflow as usize, page_size)
+                                .and_then(|_| Self::read_overflow_unsafe(reader, size, page_size))
+                        } else {
+                            Err(error_stack::Report::new(Error::InvalidFileFormat))
+                        };
+                        match value {
+                            Ok(value) => Values::Inline(vec![value]),
+                            Err(_) => {
+                                tracing::warn!(
+                                    "Skipping {:?} on page {}: invalid overflow page {}",
+                                    String::from_utf8_lossy(&node.key),
+                                    pageno,
+                                    overflow
+                                );
+                                salvage.skipped += 1;
+                                continue;
+                            }
+                        }
+                    }
+                    // Named databases are salvaged from their own pages
+                    model::NodeData::SubData(_)
+                        if name.is_none() && !node.flags.contains(model::NodeFlags::DUPDATA) =>
+                    {
+                        continue
+                    }
+                    model::NodeData::SubPage(sub) => {
+                        Values::Inline(sub.nodes.into_iter().map(|dup| dup.key).collect())
+                    }
+                    model::NodeData::SubData(_)
+                        if node.flags.contains(model::NodeFlags::DUPDATA) =>
+                    {
+                        Values::Tree
+                    }
+                    model::NodeData::SubData(_) => {
+                        salvage.skipped += 1;
+                        continue;
+                    }
+                };
+                match records.get(&node.key) {
+                    Some((best, page, _)) if (*best, *page) > (rank, pageno) => (),
+                    _ => {
+                        records.insert(node.key, (rank, pageno, values));
+                    }
+                }
+            }
+        }
+
+        let main_flags = metas.last().map(|meta| meta.main.flags).unwrap_or_default();
+        let mut databases = found
+            .into_iter()
+            .map(|(name, records)| {
+                let flags = match &name {
+                    Some(name) => reach.flags.get(name).copied().unwrap_or_default(),
+                    None => main_flags,
+                };
+                let mut elements = Vec::new();
+                for (key, (rank, _, values)) in records {
+                    let values = match values {
+                        Values::Inline(values) => values,
+                        // Pages of the same snapshot as the key, or newer
+                        Values::Tree => dups
+                            .remove(&(name.clone(), key.clone()))
+                            .unwrap_or_default()
+                            .into_iter()
+                            .filter(|(dup_rank, _)| *dup_rank >= rank)
+                            .map(|(_, dup)| dup)
+                            .collect(),
+                    };
+                    if values.is_empty() {
+                        tracing::warn!(
+                            "Skipping {:?}: no duplicates found",
+                            String::from_utf8_lossy(&key)
+                        );
+                        salvage.skipped += 1;
+                    }
+                    elements.extend(values.into_iter().map(|value| model::Element {
+                        key: key.clone(),
+                        value,
+                    }));
+                }
+                elements.sort_by(|a, b| {
+                    compare::compare(flags, endianness, &a.key, &b.key)
+                        .then_with(|| compare::compare_dups(flags, endianness, &a.value, &b.value))
+                });
+                elements.dedup_by(|a, b| a.key == b.key && a.value == b.value);
+                SalvagedDatabase {
+                    name,
+                    flags,
+                    elements,
+                }
+            })
+            .collect::<Vec<_>>();
+        databases.sort_by(|a, b| a.name.cmp(&b.name));
+        salvage.databases = databases;
+        Ok(salvage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, Write};
+
+    use crate::lmdb::Factory;
+    use crate::lmdb::WordSize;
+
+    use super::*;
+
+    macro_rules! test_case {
+        ($fname:expr) => {
+            std::path::PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/", $fname))
+        };
+    }
+
+    pub fn init_tracing() -> tracing::subscriber::DefaultGuard {
+        let subscriber = tracing_subscriber::fmt::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_line_number(true)
+            .with_file(true)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .finish();
+        tracing::subscriber::set_default(subscriber)
+    }
+
+    fn elements(db: &mut Database) -> Vec<(Vec<u8>, Vec<u8>)> {
+        db.read_cursor()
+            .unwrap()
+            .map(|element| {
+                let element = element.unwrap();
+                (element.key, element.value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_salvage_named_64() {
+        let _guard = init_tracing();
+        let salvage = Factory::salvage(test_case!("named.64bits"), None).unwrap();
+        assert_eq!(salvage.skipped, 0);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut out = Factory::create(file.path().into(), WordSize::Word64).unwrap();
+        salvage.write_into(&mut out).unwrap();
+        out.close().unwrap();
+
+        let mut db = Factory::open(test_case!("named.64bits")).unwrap();
+        let mut out = Factory::open(file.path().into()).unwrap();
+        assert_eq!(elements(&mut out), elements(&mut db));
+        for (name, _) in db.databases().unwrap() {
+            let count = out.read_cursor_named(&name).unwrap().count();
+            assert_eq!(count, db.read_cursor_named(&name).unwrap().count());
+        }
+    }
+
+    #[test]
+    fn test_salvage_dupsort_64() {
+        let _guard = init_tracing();
+        let salvage = Factory::salvage(test_case!("dupsort.64bits"), None).unwrap();
+        assert_eq!(salvage.skipped, 0);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut out = Factory::create(file.path().into(), WordSize::Word64).unwrap();
+        salvage.write_into(&mut out).unwrap();
+        out.close().unwrap();
+
+        let mut db = Factory::open(test_case!("dupsort.64bits")).unwrap();
+        let mut out = Factory::open(file.path().into()).unwrap();
+        assert_eq!(elements(&mut out), elements(&mut db));
+        let databases = db.databases().unwrap();
+        assert_eq!(out.databases().unwrap().len(), databases.len());
+        for (name, tree) in databases {
+            let expected = db
+                .read_cursor_named(&name)
+                .unwrap()
+                .map(|element| {
+                    let element = element.unwrap();
+                    (element.key, element.value)
+                })
+                .collect::<Vec<_>>();
+            let salvaged = out
+                .read_cursor_named(&name)
+                .unwrap()
+                .map(|element| {
+                    let element = element.unwrap();
+                    (element.key, element.value)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(salvaged.len() as u64, tree.entries);
+            assert_eq!(salvaged, expected, "{:?}", name);
+            assert_eq!(out.database(&name).unwrap().unwrap().flags, tree.flags);
+        }
+    }
+
+    #[test]
+    fn test_salvage_broken_root_64() {
+        let _guard = init_tracing();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut db = Factory::create(file.path().into(), WordSize::Word64).unwrap();
+        let mut cur = db.write_cursor().unwrap();
+        for i in 0..500 {
+            cur.push(format!("key-{:04}", i).into_bytes(), vec![b'v'; 40])
+                .unwrap();
+        }
+        cur.commit().unwrap();
+        let root = db.meta.main.root.unwrap();
+        drop(db);
+
+        // The tree cannot be walked anymore, and LMDB falls back to the
+        // empty snapshot of meta page 0
+        file.seek(std::io::SeekFrom::Start(root * 4096)).unwrap();
+        file.write_all(&[0; 4096]).unwrap();
+        file.flush().unwrap();
+        let mut db = Factory::open(file.path().into()).unwrap();
+        assert!(elements(&mut db).is_empty());
+
+        let salvage = Factory::salvage(file.path().into(), None).unwrap();
+        assert_eq!(salvage.databases.len(), 1);
+        let keys = salvage.databases[0]
+            .elements
+            .iter()
+            .map(|element| element.key.clone())
+            .collect::<Vec<_>>();
+        let expected = (0..500)
+            .map(|i| format!("key-{:04}", i).into_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_salvage_broken_overflow_64() {
+        let _guard = init_tracing();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&std::fs::read(test_case!("overflow.64bits")).unwrap())
+            .unwrap();
+        let mut db = Factory::open(file.path().into()).unwrap();
+        let leaf = db.read(db.meta.main.root.unwrap() as usize).unwrap();
+        let overflow = leaf
+            .nodes
+            .iter()
+            .find_map(|node| match node.data {
+                model::NodeData::Overflow(overflow, _) => Some(overflow),
+                _ => None,
+            })
+            .unwrap();
+
+        // Not an overflow page anymore
+        file.seek(std::io::SeekFrom::Start(overflow * 4096))
+            .unwrap();
+        file.write_all(&[0; 16]).unwrap();
+        file.flush().unwrap();
+
+        let salvage = Factory::salvage(file.path().into(), None).unwrap();
+        assert_eq!(salvage.skipped, 1);
+        assert_eq!(
+            salvage.databases[0].elements.len() as u64,
+            db.meta.main.entries - 1
+        );
+    }
+}
@@ -1,4 +1,6 @@
-use byteorder;
+use std::marker::PhantomData;
+
+use byteorder::ByteOrder;
 use byteorder::LittleEndian;
 
 use error_stack::Result;
@@ -6,39 +8,76 @@ use error_stack::ResultExt;
 
 use super::database::DatabaseWriter;
 use super::error::Error;
+use super::Endianness;
+
+/// Destinations which can be synced to the disk.
+pub trait SyncData {
+    fn sync_data(&mut self) -> std::io::Result<()>;
+}
+
+impl SyncData for std::fs::File {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        std::fs::File::sync_data(self)
+    }
+}
+
+impl<W: std::io::Write + SyncData> SyncData for std::io::BufWriter<W> {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        std::io::Write::flush(self)?;
+        self.get_mut().sync_data()
+    }
+}
+
+/// Nothing to sync in memory.
+impl<T> SyncData for std::io::Cursor<T> {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
-pub struct Writer32<W>
+pub struct Writer32<W, E = LittleEndian>
 where
     W: byteorder::WriteBytesExt + std::io::Seek,
+    E: ByteOrder,
 {
     writer: W,
+    endianness: PhantomData<E>,
 }
 
-impl<W> From<W> for Writer32<W>
+impl<W, E> From<W> for Writer32<W, E>
 where
     W: byteorder::WriteBytesExt + std::io::Seek,
+    E: ByteOrder,
 {
     fn from(writer: W) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            endianness: PhantomData,
+        }
     }
 }
 
-impl<W> DatabaseWriter for Writer32<W>
+impl<W, E> DatabaseWriter for Writer32<W, E>
 where
-    W: byteorder::WriteBytesExt + std::io::Seek,
+    W: byteorder::WriteBytesExt + std::io::Seek + SyncData,
+    E: ByteOrder,
 {
     fn word_size(&self) -> usize {
         4
     }
 
+    fn endianness(&self) -> Endianness {
+        Endianness::of::<E>()
+    }
+
     fn seek(&mut self, pos: std::io::SeekFrom) -> Result<usize, Error> {
         Ok(self.writer.seek(pos).change_context(Error::WriteError)? as usize)
     }
 
     fn write_word(&mut self, n: u64) -> Result<(), Error> {
         self.writer
-            .write_u32::<LittleEndian>(n as u32)
+            .write_u32::<E>(n as u32)
             .change_context(Error::WriteError)
     }
 
@@ -46,24 +85,24 @@ where
         match n {
             Some(n) => self
                 .writer
-                .write_u32::<LittleEndian>(n as u32)
+                .write_u32::<E>(n as u32)
                 .change_context(Error::WriteError),
             None => self
                 .writer
-                .write_i32::<LittleEndian>(-1)
+                .write_i32::<E>(-1)
                 .change_context(Error::WriteError),
         }
     }
 
     fn write_u16(&mut self, n: u16) -> Result<(), Error> {
         self.writer
-            .write_u16::<LittleEndian>(n)
+            .write_u16::<E>(n)
             .change_context(Error::WriteError)
     }
 
     fn write_u32(&mut self, n: u32) -> Result<(), Error> {
         self.writer
-            .write_u32::<LittleEndian>(n)
+            .write_u32::<E>(n)
             .change_context(Error::WriteError)
     }
 
@@ -74,39 +113,54 @@ where
     fn flush(&mut self) -> Result<(), Error> {
         self.writer.flush().change_context(Error::WriteError)
     }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.writer.sync_data().change_context(Error::WriteError)
+    }
 }
 
-pub struct Writer64<W>
+pub struct Writer64<W, E = LittleEndian>
 where
     W: byteorder::WriteBytesExt + std::io::Seek,
+    E: ByteOrder,
 {
     writer: W,
+    endianness: PhantomData<E>,
 }
 
-impl<W> From<W> for Writer64<W>
+impl<W, E> From<W> for Writer64<W, E>
 where
     W: byteorder::WriteBytesExt + std::io::Seek,
+    E: ByteOrder,
 {
     fn from(writer: W) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            endianness: PhantomData,
+        }
     }
 }
 
-impl<W> DatabaseWriter for Writer64<W>
+impl<W, E> DatabaseWriter for Writer64<W, E>
 where
-    W: byteorder::WriteBytesExt + std::io::Seek,
+    W: byteorder::WriteBytesExt + std::io::Seek + SyncData,
+    E: ByteOrder,
 {
     fn word_size(&self) -> usize {
         8
     }
 
+    fn endianness(&self) -> Endianness {
+        Endianness::of::<E>()
+    }
+
     fn seek(&mut self, pos: std::io::SeekFrom) -> Result<usize, Error> {
         Ok(self.writer.seek(pos).change_context(Error::WriteError)? as usize)
     }
 
     fn write_word(&mut self, n: u64) -> Result<(), Error> {
         self.writer
-            .write_u64::<LittleEndian>(n)
+            .write_u64::<E>(n)
             .change_context(Error::WriteError)
     }
 
@@ -114,24 +168,24 @@ where
         match n {
             Some(n) => self
                 .writer
-                .write_u64::<LittleEndian>(n)
+                .write_u64::<E>(n)
                 .change_context(Error::WriteError),
             None => self
                 .writer
-                .write_i64::<LittleEndian>(-1)
+                .write_i64::<E>(-1)
                 .change_context(Error::WriteError),
         }
     }
 
     fn write_u16(&mut self, n: u16) -> Result<(), Error> {
         self.writer
-            .write_u16::<LittleEndian>(n)
+            .write_u16::<E>(n)
             .change_context(Error::WriteError)
     }
 
     fn write_u32(&mut self, n: u32) -> Result<(), Error> {
         self.writer
-            .write_u32::<LittleEndian>(n)
+            .write_u32::<E>(n)
             .change_context(Error::WriteError)
     }
 
@@ -142,4 +196,8 @@ where
     fn flush(&mut self) -> Result<(), Error> {
         self.writer.flush().change_context(Error::WriteError)
     }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.writer.sync_data().change_context(Error::WriteError)
+    }
 }
@@ -1,11 +1,15 @@
 use base64::engine::general_purpose::STANDARD as base64;
 use base64::Engine;
-use json;
-use std::{collections::HashMap, path::PathBuf, vec};
+use std::io::Write;
+use std::ops::Bound;
 
-use clap::{builder::OsStr, Parser};
+use clap::Parser;
 
-mod lmdb;
+use lmdb_tool::lmdb;
+
+mod encoding;
+use encoding::Encoding;
+mod import;
 
 #[derive(Parser, Debug, Clone)]
 #[clap(name = "lmbd", version, author, about)]
@@ -20,6 +24,39 @@ pub struct Cli {
     command: Commands,
 }
 
+/// What `convert` does with the lock file of the database.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LockMode {
+    /// Write an empty lock file in the layout of the output word size
+    Regenerate,
+    /// Delete the lock file, liblmdb creates a new one on the next open
+    Remove,
+    /// Leave the lock files untouched
+    Keep,
+}
+
+/// How `dump` writes values in JSON outputs.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ValueFormat {
+    /// Values are encoded strings
+    Bytes,
+    /// Values holding JSON are nested in the output
+    Json,
+}
+
+/// Output of the `dump` command.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum DumpFormat {
+    /// One `key: value` line per record
+    Text,
+    /// A JSON object
+    Json,
+    /// The text format of `mdb_dump`, read by `mdb_load`
+    Mdb,
+    /// One JSON record per line, written as the records are read
+    Jsonl,
+}
+
 #[derive(Parser, Debug, Clone)]
 enum Commands {
     #[clap(
@@ -39,17 +76,306 @@ enum Commands {
             help = "The word size to convert to"
         )]
         format: lmdb::WordSize,
+
+        #[clap(
+            long,
+            help = "The page size to convert to, defaults to the page size of the source"
+        )]
+        page_size: Option<usize>,
+
+        #[clap(
+            short,
+            long,
+            help = "The byte order to convert to, defaults to the byte order of the source"
+        )]
+        endianness: Option<lmdb::Endianness>,
+
+        #[clap(
+            long,
+            default_value = "regenerate",
+            help = "What to do with the lock file of the source next to the destination"
+        )]
+        lock: LockMode,
+
+        #[clap(long, help = "Write even while live processes use the database")]
+        force: bool,
+    },
+    #[clap(about = "Rewrite a database without its free and dead pages, like mdb_copy -c")]
+    Compact {
+        #[clap(value_name = "source", help = "The database to compact")]
+        input: std::path::PathBuf,
+
+        #[clap(value_name = "destination", help = "The compacted copy to write")]
+        output: std::path::PathBuf,
+
+        #[clap(
+            short,
+            long,
+            help = "The word size of the copy, defaults to the word size of the source"
+        )]
+        format: Option<lmdb::WordSize>,
+    },
+    #[clap(about = "Rewrite a consistent copy of a database from its last usable meta page")]
+    Recover {
+        #[clap(value_name = "source", help = "The damaged database")]
+        input: std::path::PathBuf,
+
+        #[clap(value_name = "destination", help = "The recovered copy to write")]
+        output: std::path::PathBuf,
+
+        #[clap(
+            long,
+            value_parser = clap::value_parser!(u8).range(0..=1),
+            help = "Recover from meta page 0 or 1 rather than the newest usable one"
+        )]
+        meta: Option<u8>,
+    },
+    #[clap(about = "Extract the records of a damaged database page by page into a new one")]
+    Salvage {
+        #[clap(value_name = "source", help = "The damaged database")]
+        input: std::path::PathBuf,
+
+        #[clap(value_name = "destination", help = "The database to write")]
+        output: std::path::PathBuf,
+
+        #[clap(
+            long,
+            help = "The size of the pages to scan, defaults to the size recorded in the first meta page"
+        )]
+        page_size: Option<usize>,
+    },
+    #[clap(about = "Create a database from the text format of mdb_dump, like mdb_load")]
+    Load {
+        #[clap(value_name = "destination", help = "The database to create")]
+        output: std::path::PathBuf,
+
+        #[clap(
+            short = 'f',
+            long,
+            value_name = "file",
+            help = "Read the dump from FILE rather than the standard input"
+        )]
+        input: Option<std::path::PathBuf>,
+
+        #[clap(
+            short,
+            long,
+            default_value = "word64",
+            help = "The word size of the database"
+        )]
+        word_size: lmdb::WordSize,
+
+        #[clap(
+            short = 'T',
+            long,
+            help = "Read alternating key and value lines without headers, like mdb_load -T"
+        )]
+        plain: bool,
+
+        #[clap(long, help = "With --plain, load the records into the named database")]
+        db: Option<String>,
+
+        #[clap(
+            long,
+            help = "The page size of the database, defaults to the page size recorded in the dump"
+        )]
+        page_size: Option<usize>,
+
+        #[clap(
+            short,
+            long,
+            default_value = "little",
+            help = "The byte order of the database"
+        )]
+        endianness: lmdb::Endianness,
+    },
+    #[clap(about = "Create a database from JSON records, the inverse of dump --json")]
+    Import {
+        #[clap(value_name = "destination", help = "The database to create")]
+        output: std::path::PathBuf,
+
+        #[clap(
+            long,
+            value_name = "file",
+            required_unless_present = "jsonl",
+            conflicts_with = "jsonl",
+            help = "Read an array of records, or an object of values by key"
+        )]
+        json: Option<std::path::PathBuf>,
+
+        #[clap(long, value_name = "file", help = "Read a record per line")]
+        jsonl: Option<std::path::PathBuf>,
+
+        #[clap(
+            short,
+            long,
+            default_value = "word64",
+            help = "The word size of the database"
+        )]
+        word_size: lmdb::WordSize,
+
+        #[clap(long, help = "Import the records into the named database")]
+        db: Option<String>,
+
+        #[clap(
+            long,
+            help = "Create the database with sorted duplicates, an array holding the values of a key"
+        )]
+        dupsort: bool,
+
+        #[clap(
+            long,
+            default_value = "utf8",
+            help = "Encoding of the keys of records without key_encoding"
+        )]
+        key_encoding: Encoding,
+
+        #[clap(
+            long,
+            default_value = "utf8",
+            help = "Encoding of the values of records without value_encoding"
+        )]
+        value_encoding: Encoding,
+
+        #[clap(long, default_value = "4096", help = "The page size of the database")]
+        page_size: usize,
+
+        #[clap(
+            short,
+            long,
+            default_value = "little",
+            help = "The byte order of the database"
+        )]
+        endianness: lmdb::Endianness,
     },
     Dump {
         #[clap(value_name = "file")]
         input: std::path::PathBuf,
 
+        #[clap(long, help = "Dump the named database instead of the main one")]
+        db: Option<String>,
+
         #[clap(long, help = "Convert keys to strings")]
         string_key: bool,
 
         #[clap(long, help = "Convert values to strings")]
         string_value: bool,
 
+        #[arg(long, help = "Output as JSON, same as --format json")]
+        json: bool,
+
+        #[clap(long, help = "The output format, defaults to text")]
+        format: Option<DumpFormat>,
+
+        #[clap(
+            long,
+            help = "With --format mdb, write printable characters as-is like mdb_dump -p"
+        )]
+        print: bool,
+
+        #[clap(
+            long,
+            default_value = "bytes",
+            help = "With --format json or jsonl, nest the values holding JSON in the output"
+        )]
+        value_format: ValueFormat,
+
+        #[clap(long, help = "Start at the first key greater than or equal to KEY")]
+        from: Option<String>,
+
+        #[clap(long, help = "Stop before the first key greater than or equal to KEY")]
+        to: Option<String>,
+
+        #[clap(long, help = "Only dump keys starting with PREFIX")]
+        prefix: Option<String>,
+
+        #[clap(long, help = "Dump the records in reverse key order")]
+        reverse: bool,
+
+        #[clap(long, help = "Dump at most N records")]
+        limit: Option<usize>,
+    },
+    #[clap(about = "Print the value of a single key")]
+    Get {
+        #[clap(value_name = "file")]
+        input: std::path::PathBuf,
+
+        #[clap(value_name = "key")]
+        key: String,
+
+        #[clap(long, help = "Look up the key in the named database")]
+        db: Option<String>,
+
+        #[clap(long, default_value = "string", help = "Encoding of the key argument")]
+        key_encoding: Encoding,
+
+        #[clap(long, default_value = "string", help = "Encoding of the printed value")]
+        value_encoding: Encoding,
+    },
+    #[clap(about = "Set the value of a single key, updating the file in place")]
+    Put {
+        #[clap(value_name = "file")]
+        input: std::path::PathBuf,
+
+        #[clap(value_name = "key")]
+        key: String,
+
+        #[clap(value_name = "value")]
+        value: String,
+
+        #[clap(long, help = "Set the key in the named database")]
+        db: Option<String>,
+
+        #[clap(long, default_value = "string", help = "Encoding of the key argument")]
+        key_encoding: Encoding,
+
+        #[clap(
+            long,
+            default_value = "string",
+            help = "Encoding of the value argument"
+        )]
+        value_encoding: Encoding,
+
+        #[clap(long, help = "Write even while live processes use the database")]
+        force: bool,
+    },
+    #[clap(about = "Delete a single key, updating the file in place")]
+    Del {
+        #[clap(value_name = "file")]
+        input: std::path::PathBuf,
+
+        #[clap(value_name = "key")]
+        key: String,
+
+        #[clap(long, help = "Delete the key from the named database")]
+        db: Option<String>,
+
+        #[clap(long, default_value = "string", help = "Encoding of the key argument")]
+        key_encoding: Encoding,
+
+        #[clap(long, help = "Write even while live processes use the database")]
+        force: bool,
+    },
+    #[clap(
+        about = "Check the structure of a database. Exits with 0 when it is sound, 1 when problems are found, 2 when it cannot be opened"
+    )]
+    Verify {
+        #[clap(value_name = "file")]
+        input: std::path::PathBuf,
+
+        #[arg(long, help = "Output as JSON")]
+        json: bool,
+    },
+    #[clap(
+        about = "List the reader table of the lock file of a database, flagging readers whose process is gone"
+    )]
+    Readers {
+        #[clap(
+            value_name = "file",
+            help = "The database or environment directory, or its lock file (ending with -lock or named lock.mdb)"
+        )]
+        input: std::path::PathBuf,
+
         #[arg(long, help = "Output as JSON")]
         json: bool,
     },
@@ -59,9 +385,153 @@ enum Commands {
 
         #[arg(long, help = "Output as JSON")]
         json: bool,
+
+        #[arg(long, help = "Report the free pages of each transaction")]
+        freelist: bool,
     },
 }
 
+/// Integer keys and duplicates are stored in the byte order of the database.
+fn swap_integers(element: &mut lmdb::model::Element, flags: lmdb::model::metadata::Flags) {
+    if flags.contains(lmdb::model::metadata::Flags::INTEGERKEY) {
+        element.key.reverse();
+    }
+    if flags.contains(lmdb::model::metadata::Flags::INTEGERDUP) {
+        element.value.reverse();
+    }
+}
+
+/// The database file of an environment, which may be a directory.
+fn data_path(path: &std::path::Path) -> std::path::PathBuf {
+    lmdb::environment::Environment::resolve(path).data
+}
+
+/// Exit when a live process is reading or writing the database, whose lock
+/// file is left behind by liblmdb, unless `force` is set.
+fn refuse_if_busy(database: &std::path::Path, force: bool) {
+    let path = lmdb::lock::lock_path(database);
+    if !path.exists() {
+        return;
+    }
+    let lock = match lmdb::lock::LockFile::read(&path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            tracing::warn!("Ignoring unreadable lock file {:?}: {:?}", path, e);
+            return;
+        }
+    };
+    let busy = if let Some(tid) = lock.writer.filter(|_| lock.has_live_writer()) {
+        format!("Database {:?} is being written by thread {}", database, tid)
+    } else {
+        let live: Vec<u32> = lock.live_readers().map(|reader| reader.pid).collect();
+        if live.is_empty() {
+            return;
+        }
+        format!(
+            "Database {:?} is being read by processes {:?}",
+            database, live
+        )
+    };
+    if force {
+        tracing::warn!("{}, going on as forced", busy);
+        return;
+    }
+    tracing::error!("{}, stop them first or use --force", busy);
+    std::process::exit(1);
+}
+
+/// Replace the lock file next to `output` according to `mode`, when the
+/// database `input` had one.
+fn convert_lock(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    mode: LockMode,
+    db: &lmdb::database::Database<'_>,
+    endianness: lmdb::Endianness,
+) {
+    let source = lmdb::lock::lock_path(input);
+    let target = lmdb::lock::lock_path(output);
+    if mode == LockMode::Keep || !source.exists() {
+        return;
+    }
+    let permissions = std::fs::metadata(&source).unwrap().permissions();
+    let lock = match mode {
+        LockMode::Regenerate => match lmdb::lock::LockFile::read(&source) {
+            Ok(lock) => Some(lock.reset(db.word_size(), endianness, db.meta.txnid)),
+            Err(e) => {
+                tracing::warn!("Removing unreadable lock file {:?}: {:?}", source, e);
+                None
+            }
+        },
+        _ => None,
+    };
+    if target.exists() {
+        tracing::info!("Removing lock file {:?}", target);
+        std::fs::remove_file(&target).unwrap();
+    }
+    if let Some(lock) = lock {
+        tracing::info!(
+            "Writing a {} bits lock file at {:?}",
+            lock.layout.word_size * 8,
+            target
+        );
+        lock.write(&target).unwrap();
+        std::fs::set_permissions(&target, permissions).unwrap();
+    }
+}
+
+/// A record of `dump --format jsonl`. A nested JSON value is written along
+/// with the text stored, in `value_text`, when serializing it gives other
+/// bytes, so that `import` stores it back unchanged.
+fn jsonl_record(element: &lmdb::model::Element, value_format: ValueFormat) -> json::JsonValue {
+    let (key, key_encoding) = encoding::encode_readable(&element.key);
+    let nested = match value_format {
+        ValueFormat::Json => encoding::json_value(&element.value),
+        ValueFormat::Bytes => None,
+    };
+    let mut record = json::object! {
+        "key": key,
+        "key_encoding": key_encoding,
+    };
+    match nested {
+        Some(value) => {
+            let exact = json::stringify(value.clone()).as_bytes() == element.value;
+            record["value"] = value;
+            record["value_encoding"] = "json".into();
+            if !exact {
+                record["value_text"] = String::from_utf8_lossy(&element.value).as_ref().into();
+            }
+        }
+        None => {
+            let (value, value_encoding) = encoding::encode_readable(&element.value);
+            record["value"] = value.into();
+            record["value_encoding"] = value_encoding.into();
+        }
+    }
+    record
+}
+
+/// The object of values by key written by `dump --format json`. The
+/// duplicates of a key are gathered in an array, even when its first value
+/// is itself a nested array.
+fn json_object(items: Vec<(String, json::JsonValue)>) -> json::JsonValue {
+    let mut object = json::JsonValue::new_object();
+    let mut gathered = std::collections::HashSet::new();
+    for (key, value) in items {
+        if !object.has_key(&key) {
+            object[key.as_str()] = value;
+            continue;
+        }
+        let entry = &mut object[key.as_str()];
+        if gathered.insert(key.clone()) {
+            let first = entry.take();
+            *entry = json::array![first];
+        }
+        entry.push(value).unwrap();
+    }
+    object
+}
+
 fn main() {
     let opts = Cli::parse();
     // Setup tracing & logging
@@ -81,7 +551,18 @@ fn main() {
             input,
             output,
             format,
+            page_size,
+            endianness,
+            lock,
+            force,
         } => {
+            let input = data_path(&input);
+            let output = output.map(|output| data_path(&output));
+            refuse_if_busy(&input, force);
+            let source = input.clone();
+            if let Some(output) = output.as_ref().filter(|output| output.exists()) {
+                refuse_if_busy(output, force);
+            }
             let (input, output) = match output {
                 Some(output) => {
                     if input == output {
@@ -103,25 +584,64 @@ fn main() {
                 }
             };
 
-            let wordize = lmdb::Factory::detect(input.clone()).unwrap();
-            if wordize != format {
-                tracing::info!("Converting database from {:?} to {:?}", wordize, format);
-                let mut db_in = lmdb::Factory::open(input.clone()).unwrap();
-                let mut cur_in = db_in.read_cursor().unwrap();
+            let (wordize, endian) = lmdb::Factory::detect_format(input.clone()).unwrap();
+            let mut db_in = lmdb::Factory::open(input.clone()).unwrap();
+            let page_size = page_size.unwrap_or(db_in.page_size);
+            let endianness = endianness.unwrap_or(endian);
+            if wordize != format || endian != endianness || page_size != db_in.page_size {
+                tracing::info!(
+                    "Converting database from {:?} {:?} ({}B pages) to {:?} {:?} ({}B pages)",
+                    wordize,
+                    endian,
+                    db_in.page_size,
+                    format,
+                    endianness,
+                    page_size
+                );
+                let mut db_out =
+                    lmdb::Factory::create_with(output.clone(), format, endianness, page_size)
+                        .unwrap_or_else(|e| {
+                            tracing::error!("Failed to create {:?}: {:?}", output, e);
+                            std::process::exit(1);
+                        });
+                db_out.set_env_flags(db_in.env_flags()).unwrap();
+                let swap = endian != endianness;
 
-                let mut db_out = lmdb::Factory::create(output.clone(), format).unwrap();
+                // Named databases first, they are recorded by the main commit
+                for (name, tree) in db_in.databases().unwrap() {
+                    tracing::info!("Converting database {:?}", String::from_utf8_lossy(&name));
+                    let cur_in = db_in.read_cursor_named(&name).unwrap();
+                    let mut cur_out = db_out.write_cursor_named(&name, tree.flags).unwrap();
+                    for element in cur_in {
+                        let mut element = element.unwrap();
+                        if swap {
+                            swap_integers(&mut element, tree.flags);
+                        }
+                        cur_out.push_element(element).unwrap();
+                    }
+                    cur_out.commit().unwrap();
+                }
+
+                let flags = db_in.meta.main.flags;
+                db_out.meta.main.flags = flags;
+                let cur_in = db_in.read_cursor().unwrap();
                 let mut cur_out = db_out.write_cursor().unwrap();
 
-                while let Some(mut element) = cur_in.next().unwrap() {
+                for element in cur_in {
+                    let mut element = element.unwrap();
                     if element.value == "null".as_bytes() {
                         element.value = vec![];
                     }
+                    if swap {
+                        swap_integers(&mut element, flags);
+                    }
                     cur_out.push_element(element).unwrap();
                 }
                 cur_out.commit().unwrap();
 
                 db_in.close().unwrap();
                 db_out.close().unwrap();
+                convert_lock(&source, &output, lock, &db_out, endianness);
 
                 if input == output {
                     std::fs::rename(output.clone(), input.clone()).unwrap();
@@ -133,62 +653,634 @@ fn main() {
                 tracing::info!("No conversion needed");
             }
         }
+        Commands::Compact {
+            input,
+            output,
+            format,
+        } => {
+            if data_path(&input) == data_path(&output) {
+                tracing::error!("Output file is the same as input file");
+                std::process::exit(1);
+            }
+            let (wordsize, endianness) = lmdb::Factory::detect_format(input.clone()).unwrap();
+            let mut db_in = lmdb::Factory::open(input.clone()).unwrap();
+            let mut db_out = lmdb::Factory::create_with(
+                output.clone(),
+                format.unwrap_or(wordsize),
+                endianness,
+                db_in.page_size,
+            )
+            .unwrap();
+            if let Err(e) = db_in.compact_into(&mut db_out) {
+                tracing::error!("Failed to compact the database: {:?}", e);
+                std::process::exit(1);
+            }
+            db_out.close().unwrap();
+
+            let before = std::fs::metadata(&input).unwrap().len();
+            let after = std::fs::metadata(&output).unwrap().len();
+            println!(
+                "Compacted {:?}: {} bytes ({} pages) -> {} bytes ({} pages)",
+                input,
+                before,
+                db_in.meta.last_pgno + 1,
+                after,
+                db_out.meta.last_pgno + 1
+            );
+        }
+        Commands::Recover {
+            input,
+            output,
+            meta,
+        } => {
+            if data_path(&input) == data_path(&output) {
+                tracing::error!("Output file is the same as input file");
+                std::process::exit(1);
+            }
+            let (wordsize, endianness) = lmdb::Factory::detect_format(input.clone()).unwrap();
+            let db_in = match meta {
+                Some(meta_id) => lmdb::Factory::open_meta(input.clone(), meta_id as usize),
+                None => lmdb::Factory::open(input.clone()),
+            };
+            let mut db_in = db_in.unwrap_or_else(|e| {
+                tracing::error!("No usable meta page: {:?}", e);
+                std::process::exit(1);
+            });
+            tracing::info!(
+                "Recovering from meta page {} (transaction {})",
+                db_in.meta_id(),
+                db_in.meta.txnid
+            );
+            let mut db_out =
+                lmdb::Factory::create_with(output.clone(), wordsize, endianness, db_in.page_size)
+                    .unwrap();
+            if let Err(e) = db_in.compact_into(&mut db_out) {
+                tracing::error!("Failed to recover the database: {:?}", e);
+                std::process::exit(1);
+            }
+            db_out.close().unwrap();
+            println!(
+                "Recovered {:?} from meta page {} (transaction {}): {} entries",
+                input,
+                db_in.meta_id(),
+                db_in.meta.txnid,
+                db_out.meta.main.entries
+            );
+        }
+        Commands::Salvage {
+            input,
+            output,
+            page_size,
+        } => {
+            if data_path(&input) == data_path(&output) {
+                tracing::error!("Output file is the same as input file");
+                std::process::exit(1);
+            }
+            let (wordsize, endianness) = lmdb::Factory::detect_format(input.clone()).unwrap();
+            let salvage = lmdb::Factory::salvage(input.clone(), page_size).unwrap();
+            let (pages, leaves, skipped) = (salvage.pages, salvage.leaves, salvage.skipped);
+            let records = salvage
+                .databases
+                .iter()
+                .map(|db| db.elements.len())
+                .sum::<usize>();
+            let mut db_out =
+                lmdb::Factory::create_with(output.clone(), wordsize, endianness, salvage.page_size)
+                    .unwrap();
+            if let Err(e) = salvage.write_into(&mut db_out) {
+                tracing::error!("Failed to write the salvaged records: {:?}", e);
+                std::process::exit(1);
+            }
+            db_out.close().unwrap();
+            println!(
+                "Salvaged {} records from {} leaf pages out of {} pages, {} records skipped",
+                records, leaves, pages, skipped
+            );
+        }
+        Commands::Load {
+            output,
+            input,
+            word_size,
+            plain,
+            db: name,
+            page_size,
+            endianness,
+        } => {
+            if data_path(&output).exists() {
+                tracing::error!("Output file {:?} already exists", output);
+                std::process::exit(1);
+            }
+            let reader: Box<dyn std::io::BufRead> = match input {
+                Some(input) => {
+                    Box::new(std::io::BufReader::new(std::fs::File::open(input).unwrap()))
+                }
+                None => Box::new(std::io::stdin().lock()),
+            };
+            let sections = if plain {
+                let header = lmdb::dump::Header {
+                    print: true,
+                    database: name.map(String::into_bytes),
+                    ..Default::default()
+                };
+                lmdb::dump::read_plain(reader, header).map(|section| vec![section])
+            } else {
+                lmdb::dump::read_dump(reader)
+            };
+            let sections = sections.unwrap_or_else(|e| {
+                tracing::error!("Failed to read the dump: {:?}", e);
+                std::process::exit(1);
+            });
+            let page_size = page_size
+                .or(sections.first().map(|section| section.header.page_size))
+                .unwrap_or(lmdb::dump::Header::default().page_size);
+            let records = sections
+                .iter()
+                .map(|section| section.elements.len())
+                .sum::<usize>();
+            let count = sections.len();
+            let word_bytes = u8::from(word_size) as usize / 8;
+            for section in &sections {
+                if let Err(e) = section.check_sizes(word_bytes, page_size) {
+                    tracing::error!("Failed to load the database: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+            let mut db =
+                lmdb::Factory::create_with(output.clone(), word_size, endianness, page_size)
+                    .unwrap_or_else(|e| {
+                        tracing::error!("Failed to create {:?}: {:?}", output, e);
+                        std::process::exit(1);
+                    });
+            if let Err(e) = db.load(sections) {
+                tracing::error!("Failed to load the database: {:?}", e);
+                drop(db);
+                std::fs::remove_file(data_path(&output)).unwrap();
+                std::process::exit(1);
+            }
+            db.close().unwrap();
+            println!(
+                "Loaded {} records in {} databases into {:?}",
+                records, count, output
+            );
+        }
+        Commands::Import {
+            output,
+            json,
+            jsonl,
+            word_size,
+            db: name,
+            dupsort,
+            key_encoding,
+            value_encoding,
+            page_size,
+            endianness,
+        } => {
+            if data_path(&output).exists() {
+                tracing::error!("Output file {:?} already exists", output);
+                std::process::exit(1);
+            }
+            let options = import::Options {
+                key_encoding,
+                value_encoding,
+                dupsort,
+            };
+            let elements = match (json, jsonl) {
+                (Some(json), _) => std::fs::read_to_string(&json)
+                    .map_err(|e| e.to_string())
+                    .and_then(|text| import::read_json(&text, options)),
+                (None, Some(jsonl)) => std::fs::File::open(&jsonl)
+                    .map_err(|e| e.to_string())
+                    .and_then(|file| import::read_jsonl(std::io::BufReader::new(file), options)),
+                (None, None) => unreachable!("--json or --jsonl is required"),
+            };
+            let elements = elements.unwrap_or_else(|e| {
+                tracing::error!("Failed to read the records: {}", e);
+                std::process::exit(1);
+            });
+            let records = elements.len();
+            let mut flags = lmdb::model::metadata::Flags::empty();
+            flags.set(lmdb::model::metadata::Flags::DUPSORT, dupsort);
+            let section = lmdb::dump::Section {
+                header: lmdb::dump::Header {
+                    database: name.map(String::into_bytes),
+                    flags,
+                    ..Default::default()
+                },
+                elements,
+            };
+            if let Err(e) = section.check_sizes(u8::from(word_size) as usize / 8, page_size) {
+                tracing::error!("Failed to import the records: {:?}", e);
+                std::process::exit(1);
+            }
+            let mut db =
+                lmdb::Factory::create_with(output.clone(), word_size, endianness, page_size)
+                    .unwrap_or_else(|e| {
+                        tracing::error!("Failed to create {:?}: {:?}", output, e);
+                        std::process::exit(1);
+                    });
+            if let Err(e) = db.load(vec![section]) {
+                tracing::error!("Failed to import the records: {:?}", e);
+                drop(db);
+                std::fs::remove_file(data_path(&output)).unwrap();
+                std::process::exit(1);
+            }
+            db.close().unwrap();
+            println!("Imported {} records into {:?}", records, output);
+        }
         Commands::Dump {
             input,
+            db: name,
             string_key,
             string_value,
             json,
+            format,
+            print,
+            value_format,
+            from,
+            to,
+            prefix,
+            reverse,
+            limit,
         } => {
+            let format = match (format, json) {
+                (Some(format), _) => format,
+                (None, true) => DumpFormat::Json,
+                (None, false) => DumpFormat::Text,
+            };
+            // Keys given on the command line use the same encoding as the output
+            let key_encoding = if string_key {
+                Encoding::String
+            } else {
+                Encoding::Base64
+            };
+            let decode = |key: Option<String>| {
+                key.map(|key| {
+                    key_encoding.decode(&key).unwrap_or_else(|e| {
+                        tracing::error!("Invalid key: {}", e);
+                        std::process::exit(1);
+                    })
+                })
+            };
+            let start = decode(from).map_or(Bound::Unbounded, Bound::Included);
+            let end = decode(to).map_or(Bound::Unbounded, Bound::Excluded);
+            let prefix = decode(prefix);
+
             let mut db = lmdb::Factory::open(input.clone()).unwrap();
-            let mut cur = db.read_cursor().unwrap();
-            let items: HashMap<String, String> = cur
-                .next()
-                .unwrap()
-                .iter()
+
+            if format == DumpFormat::Mdb {
+                // The main database, unless it only holds named databases,
+                // then a section per named database
+                let sections = match name {
+                    Some(name) => vec![Some(name.into_bytes())],
+                    None => {
+                        let names = db.databases().unwrap();
+                        let main = names.is_empty() || db.meta.main.entries > names.len() as u64;
+                        let main = main.then_some(None).into_iter();
+                        main.chain(names.into_iter().map(|(name, _)| Some(name)))
+                            .collect()
+                    }
+                };
+                let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+                for section in sections {
+                    let header = db
+                        .dump_header(section.as_deref(), print)
+                        .unwrap_or_else(|e| {
+                            tracing::error!("Failed to dump the database: {:?}", e);
+                            std::process::exit(1);
+                        });
+                    let mut cur = match &section {
+                        Some(name) => db.read_cursor_named(name).unwrap(),
+                        None => db.read_cursor().unwrap(),
+                    };
+                    cur.set_range(start.clone(), end.clone());
+                    if let Some(prefix) = &prefix {
+                        cur.set_prefix(prefix.clone());
+                    }
+                    let elements: Box<dyn Iterator<Item = _>> = if reverse {
+                        Box::new(std::iter::from_fn(|| cur.prev().transpose()))
+                    } else {
+                        Box::new(&mut cur)
+                    };
+                    header
+                        .write_section(&mut out, elements.take(limit.unwrap_or(usize::MAX)))
+                        .unwrap();
+                }
+                return;
+            }
+
+            let mut cur = match name {
+                Some(name) => db.read_cursor_named(name.as_bytes()).unwrap_or_else(|e| {
+                    tracing::error!("Failed to dump the database: {:?}", e);
+                    std::process::exit(1);
+                }),
+                None => db.read_cursor().unwrap(),
+            };
+            cur.set_range(start, end);
+            if let Some(prefix) = prefix {
+                cur.set_prefix(prefix);
+            }
+
+            let elements: Box<dyn Iterator<Item = _>> = if reverse {
+                Box::new(std::iter::from_fn(|| cur.prev().transpose()))
+            } else {
+                Box::new(&mut cur)
+            };
+            let elements = elements.take(limit.unwrap_or(usize::MAX));
+
+            if format == DumpFormat::Jsonl {
+                let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+                for element in elements {
+                    let record = jsonl_record(&element.unwrap(), value_format);
+                    writeln!(out, "{}", json::stringify(record)).unwrap();
+                }
+                return;
+            }
+
+            let items: Vec<(String, json::JsonValue)> = elements
                 .map(|element| {
+                    let element = element.unwrap();
                     let key = if string_key {
                         String::from_utf8_lossy(&element.key).to_string()
                     } else {
                         base64.encode(&element.key)
                     };
-                    let value = if string_value {
-                        String::from_utf8_lossy(&element.value).to_string()
+                    let nested = match value_format {
+                        ValueFormat::Json => encoding::json_value(&element.value),
+                        ValueFormat::Bytes => None,
+                    };
+                    let value = if let Some(value) = nested {
+                        value
+                    } else if string_value {
+                        String::from_utf8_lossy(&element.value).to_string().into()
                     } else {
-                        base64.encode(&element.value)
+                        base64.encode(&element.value).into()
                     };
                     (key, value)
                 })
                 .collect();
 
-            if json {
-                let json_string = json::stringify_pretty(items, 2);
-                println!("{}", json_string);
+            if format == DumpFormat::Json {
+                println!("{}", json::stringify_pretty(json_object(items), 2));
                 return;
             }
 
             for (key, value) in items {
-                println!("{}: {}", key, value);
+                match value.as_str() {
+                    Some(value) => println!("{}: {}", key, value),
+                    None => println!("{}: {}", key, json::stringify(value)),
+                }
             }
         }
-        Commands::Info { input, json } => {
-            let wordize = lmdb::Factory::detect(input.clone()).unwrap();
-            let db = lmdb::Factory::open(input.clone()).unwrap();
-            let out = json::object! {
+        Commands::Get {
+            input,
+            key,
+            db: name,
+            key_encoding,
+            value_encoding,
+        } => {
+            let key = key_encoding.decode(&key).unwrap_or_else(|e| {
+                tracing::error!("Invalid key: {}", e);
+                std::process::exit(1);
+            });
+            let mut db = lmdb::Factory::open(input.clone()).unwrap();
+            let tree = match name {
+                Some(name) => db.database(name.as_bytes()).unwrap().unwrap_or_else(|| {
+                    tracing::error!("Database {:?} not found", name);
+                    std::process::exit(1);
+                }),
+                None => db.meta.main.clone(),
+            };
+            match db.get_from(&tree, &key).unwrap() {
+                Some(value) => println!("{}", value_encoding.encode(&value)),
+                None => {
+                    tracing::error!("Key not found");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Put {
+            input,
+            key,
+            value,
+            db: name,
+            key_encoding,
+            value_encoding,
+            force,
+        } => {
+            let key = key_encoding.decode(&key).unwrap_or_else(|e| {
+                tracing::error!("Invalid key: {}", e);
+                std::process::exit(1);
+            });
+            let value = value_encoding.decode(&value).unwrap_or_else(|e| {
+                tracing::error!("Invalid value: {}", e);
+                std::process::exit(1);
+            });
+            refuse_if_busy(&data_path(&input), force);
+            let mut db = lmdb::Factory::open_rw(input.clone()).unwrap();
+            let result = match name {
+                Some(name) => db.put_named(name.as_bytes(), &key, &value),
+                None => db.put(&key, &value),
+            };
+            if let Err(e) = result {
+                tracing::error!("Failed to set the key: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Del {
+            input,
+            key,
+            db: name,
+            key_encoding,
+            force,
+        } => {
+            let key = key_encoding.decode(&key).unwrap_or_else(|e| {
+                tracing::error!("Invalid key: {}", e);
+                std::process::exit(1);
+            });
+            refuse_if_busy(&data_path(&input), force);
+            let mut db = lmdb::Factory::open_rw(input.clone()).unwrap();
+            let result = match name {
+                Some(name) => db.del_named(name.as_bytes(), &key),
+                None => db.del(&key),
+            };
+            match result {
+                Ok(true) => (),
+                Ok(false) => {
+                    tracing::error!("Key not found");
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    tracing::error!("Failed to delete the key: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Verify { input, json } => {
+            let check = match lmdb::Factory::open(input.clone()).and_then(|mut db| db.check()) {
+                Ok(check) => check,
+                Err(e) => {
+                    tracing::error!("Failed to open the database: {:?}", e);
+                    std::process::exit(2);
+                }
+            };
+            if json {
+                let out = json::object! {
+                    "ok": check.is_ok(),
+                    "pages": check.pages,
+                    "used": check.used,
+                    "free": check.free,
+                    "leaked": check.leaked.clone(),
+                    "problems": check.problems.iter().map(|problem| json::object! {
+                        "page": problem.page,
+                        "message": problem.message.clone(),
+                    }).collect::<Vec<_>>(),
+                };
+                println!("{}", json::stringify_pretty(out, 2));
+            } else {
+                for problem in &check.problems {
+                    println!("{}", problem);
+                }
+                println!(
+                    "{}: {} pages, {} used, {} free, {} leaked, {} problems",
+                    if check.is_ok() { "OK" } else { "CORRUPT" },
+                    check.pages,
+                    check.used,
+                    check.free,
+                    check.leaked.len(),
+                    check.problems.len()
+                );
+            }
+            if !check.is_ok() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Readers { input, json } => {
+            let name = input.file_name().unwrap_or_default().to_string_lossy();
+            let path = if name.ends_with(lmdb::environment::LOCK_SUFFIX)
+                || name == lmdb::environment::LOCK_NAME
+            {
+                input.clone()
+            } else {
+                lmdb::lock::lock_path(&input)
+            };
+            let lock = lmdb::lock::LockFile::read(&path).unwrap_or_else(|e| {
+                tracing::error!("Failed to read the lock file: {:?}", e);
+                std::process::exit(1);
+            });
+            let state = |reader: &lmdb::lock::Reader| {
+                if reader.is_alive() {
+                    "live"
+                } else {
+                    "stale"
+                }
+            };
+            if json {
+                let out = json::object! {
+                    "word-size": lock.layout.word_size * 8,
+                    "endianness": format!("{:?}", lock.endianness).to_lowercase(),
+                    "format": lock.format,
+                    "txnid": lock.txnid,
+                    "slots": lock.numreaders,
+                    "writer": lock.writer,
+                    "readers": lock.readers.iter().map(|reader| json::object! {
+                        "slot": reader.slot,
+                        "pid": reader.pid,
+                        "tid": reader.tid,
+                        "txnid": reader.txnid,
+                        "state": state(reader),
+                    }).collect::<Vec<_>>(),
+                };
+                println!("{}", json::stringify_pretty(out, 2));
+                return;
+            }
+            println!("Lock file: {:?}", path);
+            println!(
+                "Format: {:#x} ({} bits, {:?}, {} bytes mutexes)",
+                lock.format,
+                lock.layout.word_size * 8,
+                lock.endianness,
+                lock.layout.mutex_size
+            );
+            println!("Transaction: {}", lock.txnid);
+            match lock.writer {
+                Some(tid) => println!("Writer: thread {}", tid),
+                None => println!("Writer: none"),
+            }
+            if lock.readers.is_empty() {
+                println!("Readers: none of {} slots in use", lock.numreaders);
+            } else {
+                println!("Readers:");
+            }
+            for reader in &lock.readers {
+                let txnid = reader
+                    .txnid
+                    .map_or("-".to_string(), |txnid| txnid.to_string());
+                println!(
+                    "  slot {}: pid {} thread {:#x} txnid {} ({})",
+                    reader.slot,
+                    reader.pid,
+                    reader.tid,
+                    txnid,
+                    state(reader)
+                );
+            }
+        }
+        Commands::Info {
+            input,
+            json,
+            freelist,
+        } => {
+            let (wordize, endian) = lmdb::Factory::detect_format(input.clone()).unwrap();
+            let mut db = lmdb::Factory::open(input.clone()).unwrap();
+            let databases = db.databases().unwrap();
+            let records = if freelist {
+                db.freelist().unwrap()
+            } else {
+                vec![]
+            };
+            let free_pages = records.iter().map(|(_, pages)| pages.len()).sum::<usize>();
+            let mut out = json::object! {
                     "word-size": Into::<u8>::into(wordize),
+                    "endianness": format!("{:?}", endian).to_lowercase(),
                     "pages": json::object! {
                         "leaf": db.meta.main.leaf_pages,
                         "branch": db.meta.main.branch_pages,
                         "overflow": db.meta.main.overflow_pages,
                     },
+                    "page-size": db.page_size,
                     "root": db.meta.main.root,
                     "last": db.meta.last_pgno,
                     "entries": db.meta.main.entries,
+                    "databases": databases.iter().map(|(name, tree)| json::object! {
+                        "name": String::from_utf8_lossy(name).to_string(),
+                        "flags": tree.flags.bits(),
+                        "depth": tree.depth,
+                        "pages": json::object! {
+                            "leaf": tree.leaf_pages,
+                            "branch": tree.branch_pages,
+                            "overflow": tree.overflow_pages,
+                        },
+                        "root": tree.root,
+                        "entries": tree.entries,
+                    }).collect::<Vec<_>>(),
             };
+            if freelist {
+                out["freelist"] = json::object! {
+                    "pages": free_pages,
+                    "bytes": free_pages * db.page_size,
+                    "transactions": records.iter().map(|(txnid, pages)| json::object! {
+                        "txnid": *txnid,
+                        "pages": pages.len(),
+                    }).collect::<Vec<_>>(),
+                };
+            }
             if json {
                 println!("{}", json::stringify_pretty(out, 2));
                 return;
             }
             println!("Word size: {:?}", wordize);
+            println!("Endianness: {:?}", endian);
+            println!("Page size: {:?}", db.page_size);
             println!(
                 "Pages: leaf:{:?}, branch:{:?}, overflow:{:?}",
                 db.meta.main.leaf_pages, db.meta.main.branch_pages, db.meta.main.overflow_pages
@@ -196,6 +1288,82 @@ fn main() {
             println!("Root: {:?}", db.meta.main.root);
             println!("Last: {:?}", db.meta.last_pgno);
             println!("Entries: {:?}", db.meta.main.entries);
+            for (name, tree) in databases {
+                println!("Database {:?}:", String::from_utf8_lossy(&name));
+                println!("  Flags: {:?}", tree.flags);
+                println!("  Depth: {:?}", tree.depth);
+                println!(
+                    "  Pages: leaf:{:?}, branch:{:?}, overflow:{:?}",
+                    tree.leaf_pages, tree.branch_pages, tree.overflow_pages
+                );
+                println!("  Root: {:?}", tree.root);
+                println!("  Entries: {:?}", tree.entries);
+            }
+            if freelist {
+                println!(
+                    "Free list: {} pages, {} bytes reclaimable",
+                    free_pages,
+                    free_pages * db.page_size
+                );
+                for (txnid, pages) in records {
+                    println!("  Transaction {}: {} pages", txnid, pages.len());
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jsonl_record_nested() {
+        let path = std::path::PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/mender-store.32bits.2"
+        ));
+        let mut db = lmdb::Factory::open(path).unwrap();
+        let state = db.get(b"state").unwrap().unwrap();
+        let element = lmdb::model::Element {
+            key: b"state".to_vec(),
+            value: state.clone(),
+        };
+
+        // Go escapes '&' as \u0026, which is kept apart to be stored back
+        let record = jsonl_record(&element, ValueFormat::Json);
+        assert_eq!(record["value_encoding"], "json");
+        assert_eq!(record["value"]["Name"], "reboot");
+        assert_eq!(record["value_text"].as_str().unwrap().as_bytes(), state);
+
+        let options = import::Options {
+            key_encoding: Encoding::String,
+            value_encoding: Encoding::String,
+            dupsort: false,
+        };
+        let line = json::stringify(record);
+        let elements = import::read_jsonl(line.as_bytes(), options).unwrap();
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0].value, state);
+
+        let record = jsonl_record(&element, ValueFormat::Bytes);
+        assert_eq!(record["value_encoding"], "utf8");
+        assert!(!record.has_key("value_text"));
+    }
+
+    #[test]
+    fn test_json_object() {
+        let items = vec![
+            ("a".to_string(), json::array![1]),
+            ("b".to_string(), json::array![1]),
+            ("b".to_string(), json::object! {}),
+            ("c".to_string(), "1".into()),
+            ("c".to_string(), "2".into()),
+            ("c".to_string(), "3".into()),
+        ];
+        assert_eq!(
+            json::stringify(json_object(items)),
+            r#"{"a":[1],"b":[[1],{}],"c":["1","2","3"]}"#
+        );
+    }
+}